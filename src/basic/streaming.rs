@@ -5,14 +5,25 @@
 //! It models CBOR as a series of [`Event`]s, which are not always full data items.
 //! In this way, it is comparable to SAX in the XML world.
 
-use crate::errors::{DecodeError, EncodeError};
+use super::tree;
+use crate::errors::{
+	DecodeError, DecodeErrorKind, EncodeError, EventContext, MalformedKind, NotPreferredKind,
+	PathSegment,
+};
+use half::f16;
 use std::{
 	borrow::Cow,
-	cell::RefCell,
-	io::{Read, Write},
+	io::{BufRead, Read, Write},
+	iter::FusedIterator,
+	marker::PhantomData,
 	num::NonZeroUsize,
 };
 
+#[cfg(feature = "async")]
+use futures_io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "async")]
+use std::{future::poll_fn, pin::Pin, task::Poll};
+
 fn read_be_u16(input: &[u8]) -> u16 {
 	let mut bytes = [0u8; 2];
 	bytes.copy_from_slice(&input[..2]);
@@ -31,961 +42,4087 @@ fn read_be_u64(input: &[u8]) -> u64 {
 	u64::from_be_bytes(bytes)
 }
 
-/// A streaming decoder for the CBOR basic data model.
-#[derive(Debug, Clone)]
-pub struct Decoder<T: Read> {
-	source: RefCell<T>,
-	input_buffer: Vec<u8>,
-	pending: Vec<Pending>,
+// half sets the quiet bit of any NaN it converts, so these do NaNs by hand, to keep their payloads exactly.
+// Anything else is converted the usual way, which is exact when widening.
+
+fn f16_to_f64(f: f16) -> f64 {
+	if f.is_nan() {
+		let bits = f.to_bits() as u64;
+		f64::from_bits((bits & 0x8000) << 48 | 0x7FF0_0000_0000_0000 | (bits & 0x03FF) << 42)
+	} else {
+		f.to_f64()
+	}
 }
 
-#[derive(Debug, Clone)]
-enum Pending {
-	Break,
-	Array(u64),
-	Map(u64, bool),
-	UnknownLengthMap(bool),
-	Tag,
+fn f32_to_f64(f: f32) -> f64 {
+	if f.is_nan() {
+		let bits = f.to_bits() as u64;
+		f64::from_bits(
+			(bits & 0x8000_0000) << 32 | 0x7FF0_0000_0000_0000 | (bits & 0x007F_FFFF) << 29,
+		)
+	} else {
+		f as f64
+	}
 }
 
-#[derive(Debug)]
-enum TryNextEventOutcome {
-	GotEvent(Event<'static>),
-	Error(DecodeError),
-	Needs(NonZeroUsize),
+/// Narrow an f64 to an f16, if that can be done without changing it at all.
+fn f64_to_f16(f: f64) -> Option<f16> {
+	let bits = f.to_bits();
+	if f.is_nan() {
+		(bits & 0x03FF_FFFF_FFFF == 0).then(|| {
+			f16::from_bits(((bits >> 48) & 0x8000 | 0x7C00 | (bits >> 42) & 0x03FF) as u16)
+		})
+	} else {
+		let narrow = f16::from_f64(f);
+		(narrow.to_f64().to_bits() == bits).then_some(narrow)
+	}
 }
 
-impl<T: Read> Decoder<T> {
-	pub fn new(source: T) -> Self {
-		Decoder {
-			source: RefCell::new(source),
-			input_buffer: Vec::with_capacity(128),
-			pending: Vec::new(),
-		}
+/// Narrow an f64 to an f32, if that can be done without changing it at all.
+fn f64_to_f32(f: f64) -> Option<f32> {
+	let bits = f.to_bits();
+	if f.is_nan() {
+		(bits & 0x1FFF_FFFF == 0).then(|| {
+			f32::from_bits(
+				((bits >> 32) & 0x8000_0000 | 0x7F80_0000 | (bits >> 29) & 0x007F_FFFF) as u32,
+			)
+		})
+	} else {
+		let narrow = f as f32;
+		((narrow as f64).to_bits() == bits).then_some(narrow)
 	}
+}
 
-	/// Pull an event from the decoder.
+/// A source of input for a [`Decoder`].
+///
+/// This is implemented by [`ReadSource`], which pulls input from a [`Read`]er,
+/// by [`BufReadSource`], which pulls input from a [`BufRead`]er,
+/// by [`SliceSource`], which lends out parts of a byte slice,
+/// and by [`PushSource`], which is given input piece by piece as it becomes available.
+/// `'de` is the lifetime of any data the source can lend out.
+pub trait Source<'de> {
+	/// Make at least `len` bytes of input available, and return all of the input currently available.
 	///
-	/// Note that the resulting event does not, at present, actually borrow the decoder.
-	/// At the moment, the decoder isn't zero-copy.
-	/// Even though [`Event`] supports borrowing the contents of byte- and text-strings,
-	/// they are never borrowed in decoding, only in encoding.
-	/// However, `next_event` is typed as if it were zero-copy for forward compatibility.
-	pub fn next_event(&mut self) -> Result<Event, DecodeError> {
-		use TryNextEventOutcome::*;
-		loop {
-			match self.try_next_event() {
-				GotEvent(e) => return Ok(e),
-				Error(e) => return Err(e),
-				Needs(n) => self.extend_input_buffer(n)?,
-			}
-		}
+	/// If the input ends before `len` bytes are available, this returns [`DecodeErrorKind::Insufficient`].
+	fn fill(&mut self, len: usize) -> Result<&[u8], DecodeErrorKind>;
+
+	/// Discard `len` bytes from the start of the available input.
+	///
+	/// `len` must not be greater than the amount of input made available by [`Self::fill`].
+	/// This can only fail if the input that was made available can't be got at again,
+	/// which is up to whatever the source is reading from.
+	fn consume(&mut self, len: usize) -> Result<(), DecodeErrorKind>;
+
+	/// Remove `len` bytes from the start of the available input and return them.
+	///
+	/// `len` must not be greater than the amount of input made available by [`Self::fill`].
+	/// This can fail in the same way as [`Self::consume`].
+	fn take(&mut self, len: usize) -> Result<Cow<'de, [u8]>, DecodeErrorKind>;
+
+	/// Return `len` bytes of the available input, starting `start` bytes in, without removing them.
+	///
+	/// `start + len` must not be greater than the amount of input made available by [`Self::fill`].
+	/// This can fail in the same way as [`Self::consume`].
+	fn peek(&mut self, start: usize, len: usize) -> Result<Cow<'de, [u8]>, DecodeErrorKind>;
+
+	/// Check whether the source is holding on to any input it has read, but not yet consumed.
+	fn is_buffer_empty(&self) -> bool;
+
+	/// Discard the next `len` bytes of input, whether or not they have been made available yet.
+	///
+	/// If the input ends before `len` bytes have been discarded, this returns [`DecodeErrorKind::Insufficient`].
+	/// The default implementation [fills](`Self::fill`) and then [consumes](`Self::consume`) them,
+	/// but a source that can throw input away without holding all of it at once should do that instead.
+	fn skip(&mut self, len: u64) -> Result<(), DecodeErrorKind> {
+		// Input that doesn't fit in the address space can't be held all at once, so it can't be there.
+		let len = usize::try_from(len).map_err(|_| DecodeErrorKind::Insufficient)?;
+		self.fill(len)?;
+		self.consume(len)
 	}
 
-	fn extend_input_buffer(&mut self, by: NonZeroUsize) -> Result<(), DecodeError> {
-		let by = by.into();
-		let orig_len = self.input_buffer.len();
-		self.input_buffer.reserve(by);
-		for _ in 0..by {
-			// 0xFF is used because encountering a string of them at the wrong time will usually cause an InvalidBreak,
-			// whereas encountering a string of (for example) zeros will be interpreted as valid.
-			self.input_buffer.push(0xFF);
-		}
-		let buf = &mut self.input_buffer[orig_len..];
-		match self.source.borrow_mut().read_exact(buf) {
-			Ok(()) => (),
-			Err(e) => {
-				if e.kind() == std::io::ErrorKind::UnexpectedEof {
-					return Err(DecodeError::Insufficient);
-				} else {
-					return Err(DecodeError::IoError(e));
-				}
-			}
+	/// Move up to `buf.len()` bytes from the start of the input into `buf`, and return how many were moved.
+	///
+	/// Unless `buf` is empty, at least one byte is moved; if the input has ended, this returns [`DecodeErrorKind::Insufficient`].
+	/// The default implementation [fills](`Self::fill`) and then [consumes](`Self::consume`) whatever is available,
+	/// but a source that can read input without holding it first should do that instead.
+	fn read_into(&mut self, buf: &mut [u8]) -> Result<usize, DecodeErrorKind> {
+		if buf.is_empty() {
+			return Ok(0);
 		}
-		Ok(())
+		let available = self.fill(1)?;
+		let len = available.len().min(buf.len());
+		buf[..len].copy_from_slice(&available[..len]);
+		self.consume(len)?;
+		Ok(len)
 	}
 
-	fn try_next_event(&mut self) -> TryNextEventOutcome {
-		use TryNextEventOutcome::*;
-		let input = &mut self.input_buffer;
-		if input.is_empty() {
-			Needs(1.try_into().unwrap())
-		} else {
-			let (event, size) = {
-				let initial = input[0];
-				let excess = &input[1..];
-				let major = initial >> 5;
-				let additional = initial & 0b11111;
-
-				macro_rules! bounds_check {
-					($bound:expr) => {
-						match ($bound as usize)
-							.checked_sub(excess.len())
-							.unwrap_or(0)
-							.try_into()
-						{
-							Ok(n) => return Needs(n),
-							Err(_) => (),
-						}
-					};
-				}
+	/// Start keeping track of all the input that gets consumed, taken, or skipped.
+	///
+	/// Recordings don't nest: starting a new one discards any that's in progress.
+	fn start_recording(&mut self);
 
-				macro_rules! read_argument {
-					() => {
-						match additional {
-							n if n < 24 => (Some(n as u64), 1),
-							24 => {
-								bounds_check!(1);
-								(Some(excess[0] as _), 2)
-							}
-							25 => {
-								bounds_check!(2);
-								(Some(read_be_u16(excess) as _), 3)
-							}
-							26 => {
-								bounds_check!(4);
-								(Some(read_be_u32(excess) as _), 5)
-							}
-							27 => {
-								bounds_check!(8);
-								(Some(read_be_u64(excess) as _), 9)
-							}
-							28 | 29 | 30 => return Error(DecodeError::Malformed),
-							31 => (None, 0),
-							_ => unreachable!(),
-						}
-					};
-				}
+	/// Stop recording, and return all the input consumed, taken, or skipped since [`Self::start_recording`].
+	fn end_recording(&mut self) -> Cow<'de, [u8]>;
+}
 
-				let mut pop_pending = false;
-				match self.pending.last_mut() {
-					Some(Pending::Array(ref mut n)) => {
-						*n -= 1;
-						if *n == 0 {
-							pop_pending = true;
-						}
-					}
-					Some(Pending::Map(ref mut n, ref mut can_stop)) => {
-						*can_stop = !*can_stop;
-						if *can_stop {
-							*n -= 1;
-							if *n == 0 {
-								pop_pending = true;
-							}
-						}
-					}
-					Some(Pending::UnknownLengthMap(ref mut can_stop)) => {
-						*can_stop = !*can_stop;
-					}
-					Some(Pending::Tag) => {
-						pop_pending = true;
-					}
-					Some(Pending::Break) | None => (),
-				}
-				if pop_pending {
-					self.pending.pop();
-				}
+/// Input that has been read, but not yet consumed, for the sources that keep their own buffer.
+///
+/// Consuming input only moves a cursor forward.
+/// What has been consumed is dropped just before more input is added, and only once there's at least as much of it as there is input left,
+/// so on average each byte is moved a constant number of times, instead of the rest of the buffer being shifted after every event.
+#[derive(Debug, Clone, Default)]
+struct InputBuffer {
+	data: Vec<u8>,
+	/// Where the input that hasn't been consumed starts.
+	start: usize,
+}
 
-				match major {
-					0 => {
-						let (val, offset) = read_argument!();
-						(
-							Event::Unsigned(match val {
-								Some(x) => x,
-								None => return Error(DecodeError::Malformed),
-							}),
-							offset,
-						)
-					}
-					1 => {
-						let (val, offset) = read_argument!();
-						(
-							Event::Signed(match val {
-								Some(x) => x,
-								None => return Error(DecodeError::Malformed),
-							}),
-							offset,
-						)
-					}
-					2 => {
-						let (val, offset) = read_argument!();
-						match val {
-							Some(len) => {
-								let len = len as usize;
-								// remember that offset includes the initial
-								bounds_check!(len + offset - 1);
-								let contents = excess[offset - 1..len + offset - 1].to_owned();
-								(Event::ByteString(Cow::Owned(contents)), len + offset)
-							}
-							None => {
-								self.pending.push(Pending::Break);
-								(Event::UnknownLengthByteString, 1)
-							}
-						}
-					}
-					3 => {
-						let (val, offset) = read_argument!();
-						match val {
-							Some(len) => {
-								let len = len as usize;
-								// remember that offset includes the initial
-								bounds_check!(len + offset - 1);
-								let contents = excess[offset - 1..len + offset - 1].to_owned();
-								match String::from_utf8(contents) {
-									Ok(s) => (Event::TextString(Cow::Owned(s)), len + offset),
-									Err(e) => return Error(e.into()),
-								}
-							}
-							None => {
-								self.pending.push(Pending::Break);
-								(Event::UnknownLengthTextString, 1)
-							}
-						}
-					}
-					4 => {
-						let (val, offset) = read_argument!();
-						match val {
-							Some(len) => {
-								if len > 0 {
-									self.pending.push(Pending::Array(len));
-								}
-								(Event::Array(len), offset)
-							}
-							None => {
-								self.pending.push(Pending::Break);
-								(Event::UnknownLengthArray, 1)
-							}
-						}
-					}
-					5 => {
-						let (val, offset) = read_argument!();
-						match val {
-							Some(len) => {
-								if len > 0 {
-									self.pending.push(Pending::Map(len, true));
-								}
-								(Event::Map(len), offset)
-							}
-							None => {
-								self.pending.push(Pending::UnknownLengthMap(true));
-								(Event::UnknownLengthMap, 1)
-							}
-						}
-					}
-					6 => {
-						let (val, offset) = read_argument!();
-						match val {
-							Some(tag) => {
-								self.pending.push(Pending::Tag);
-								(Event::Tag(tag), offset)
-							}
-							None => {
-								return Error(DecodeError::Malformed);
-							}
-						}
-					}
-					7 => match additional {
-						n @ 0..=23 => (Event::Simple(n), 1),
-						24 => {
-							bounds_check!(1);
-							match excess[0] {
-								0..=23 => return Error(DecodeError::Malformed),
-								n => (Event::Simple(n), 2),
-							}
-						}
-						25 => {
-							bounds_check!(2);
-							let mut bytes = [0u8; 2];
-							bytes.copy_from_slice(&excess[..2]);
-							(Event::Float(half::f16::from_be_bytes(bytes).into()), 3)
-						}
-						26 => {
-							bounds_check!(4);
-							let mut bytes = [0u8; 4];
-							bytes.copy_from_slice(&excess[..4]);
-							(Event::Float(f32::from_be_bytes(bytes).into()), 5)
-						}
-						27 => {
-							bounds_check!(8);
-							let mut bytes = [0u8; 8];
-							bytes.copy_from_slice(&excess[..8]);
-							(Event::Float(f64::from_be_bytes(bytes)), 9)
-						}
-						28..=30 => return Error(DecodeError::Malformed),
-						31 => {
-							match self.pending.pop() {
-								// This is false because it's already been flipped for this item.
-								Some(Pending::Break) | Some(Pending::UnknownLengthMap(false)) => (),
-								_ => return Error(DecodeError::Malformed),
-							}
-							(Event::Break, 1)
-						}
-						32..=u8::MAX => unreachable!(),
-					},
-					8..=u8::MAX => unreachable!(),
-				}
-			};
-			input.drain(0..size);
-			GotEvent(event)
+impl InputBuffer {
+	fn with_capacity(capacity: usize) -> Self {
+		InputBuffer {
+			data: Vec::with_capacity(capacity),
+			start: 0,
 		}
 	}
 
-	/// Check whether it is possible to end the decoding now.
-	///
-	/// If this returns true, it means cutting off the CBOR now results in a complete object, _and_ there is no extra data in the internal buffer.
-	/// There can be extra data in the internal buffer if a partial CBOR event has just been read.
-	pub fn ready_to_finish(&self) -> bool {
-		self.pending.is_empty() && self.input_buffer.is_empty()
+	/// Get the input that hasn't been consumed.
+	fn available(&self) -> &[u8] {
+		&self.data[self.start..]
 	}
 
-	/// End the decoding.
-	///
-	/// This is [checked](`Self::ready_to_finish`) and will return [`DecodeError::Insufficient`] if the CBOR is incomplete.
-	/// If you've performed the check already, try [`Self::force_finish`].
-	pub fn finish(self) -> Result<T, DecodeError> {
-		if self.ready_to_finish() {
-			Ok(self.source.into_inner())
-		} else {
-			Err(DecodeError::Insufficient)
+	fn len(&self) -> usize {
+		self.data.len() - self.start
+	}
+
+	fn is_empty(&self) -> bool {
+		self.start == self.data.len()
+	}
+
+	/// Consume `len` bytes, and return them.
+	fn consume(&mut self, len: usize) -> &[u8] {
+		let start = self.start;
+		self.start += len;
+		&self.data[start..self.start]
+	}
+
+	/// Get the underlying [`Vec`], to add more input to the end of it.
+	fn for_appending(&mut self) -> &mut Vec<u8> {
+		if self.start >= self.data.len() - self.start {
+			self.data.drain(..self.start);
+			self.start = 0;
 		}
+		&mut self.data
 	}
 
-	/// End the decoding, without checking whether the decoder is finished or not.
-	///
-	/// This does not return the original reader,
-	/// but the reader returned behaves as if it were the original reader.
-	/// (The discrepancy is because [`Decoder`] contains an internal buffer.
-	/// Rest assured it behaves as if this buffer were not used.)
-	pub fn force_finish(self) -> impl Read {
-		std::io::Cursor::new(self.input_buffer).chain(self.source.into_inner())
+	fn clear(&mut self) {
+		self.data.clear();
+		self.start = 0;
+	}
+
+	fn into_vec(mut self) -> Vec<u8> {
+		self.data.drain(..self.start);
+		self.data
 	}
 }
 
-/// An event encountered while decoding or encoding CBOR using a streaming basic implementation.
+/// A [`Source`] that pulls input from a [`Read`]er.
+///
+/// Since the input is gone once it has been read, strings are copied out of it.
 #[derive(Debug, Clone)]
-pub enum Event<'a> {
-	/// An unsigned integer.
-	Unsigned(u64),
-	/// A signed integer in a slightly odd representation.
-	///
-	/// The actual value of the integer is -1 minus the provided value.
-	/// Some integers that can be CBOR encoded underflow [`i64`].
-	/// Use one of the `interpret_signed` associated functions to resolve this.
-	Signed(u64),
-	/// A byte string.
-	ByteString(Cow<'a, [u8]>),
-	/// The start of a byte string whose length is unknown.
-	///
-	/// After this event come a series of `ByteString` events, followed by a `Break`.
-	/// To get the true value of the byte string, concatenate the `ByteString` events together.
-	UnknownLengthByteString,
-	/// A text string.
-	TextString(Cow<'a, str>),
-	/// The start of a text string whose length is unknown.
-	///
-	/// After this event come a series of `TextString` events, followed by a `Break`.
-	/// To get the true value of the text string, concatenate the `TextString` events together.
-	UnknownLengthTextString,
-	/// The start of an array with a known length.
-	Array(u64),
-	/// The start of an array whose length is unknown.
-	///
-	/// After this event come a series of events representing the items in the array.
-	/// The array ends at the matching `Break` event.
-	UnknownLengthArray,
-	/// The start of a map with a known length.
-	///
-	/// Note that the actual number of sub-items is _twice_ the length given.
-	/// The first in each pair is a key, and the second is the value.
-	Map(u64),
-	/// The start of a map with an unknown length.
-	UnknownLengthMap,
-	/// Additional type information for the next CBOR item.
-	Tag(u64),
-	/// A CBOR simple value.
-	///
-	/// Most notably, simple values 20, 21, 22, and 23 represent false, true, null, and undefined, respectively.
-	Simple(u8),
-	/// A floating-point number.
-	Float(f64),
-	/// The end of an unknown-length item.
-	Break,
+pub struct ReadSource<T: Read> {
+	reader: T,
+	buffer: InputBuffer,
+	recording: Option<Vec<u8>>,
 }
 
-impl Event<'_> {
-	/// Convert this [`Event`] to an owned value.
-	pub fn into_owned(self) -> Event<'static> {
-		match self {
-			Self::Unsigned(n) => Event::Unsigned(n),
-			Self::Signed(n) => Event::Signed(n),
-			Self::ByteString(b) => Event::ByteString(Cow::Owned(b.into_owned())),
-			Self::UnknownLengthByteString => Event::UnknownLengthByteString,
-			Self::TextString(t) => Event::TextString(Cow::Owned(t.into_owned())),
-			Self::UnknownLengthTextString => Event::UnknownLengthTextString,
-			Self::Array(l) => Event::Array(l),
-			Self::UnknownLengthArray => Event::UnknownLengthArray,
-			Self::Map(l) => Event::Map(l),
-			Self::UnknownLengthMap => Event::UnknownLengthMap,
-			Self::Tag(t) => Event::Tag(t),
-			Self::Simple(s) => Event::Simple(s),
-			Self::Float(f) => Event::Float(f),
-			Self::Break => Event::Break,
+impl<T: Read> ReadSource<T> {
+	pub fn new(reader: T) -> Self {
+		ReadSource {
+			reader,
+			buffer: InputBuffer::with_capacity(128),
+			recording: None,
 		}
 	}
+}
 
-	/// Interpret a [`Event::Signed`] value.
-	///
-	/// # Overflow behavior
-	///
-	/// On overflow, this function will panic if overflow checks are enabled (default in debug mode)
-	/// and wrap if overflow checks are disabled (default in release mode).
-	pub fn interpret_signed(val: u64) -> i64 {
-		-1 - (val as i64)
+impl<'de, T: Read> Source<'de> for ReadSource<T> {
+	fn fill(&mut self, len: usize) -> Result<&[u8], DecodeErrorKind> {
+		if let Some(needed) = len.checked_sub(self.buffer.len()) {
+			// This only grows the buffer as data actually arrives,
+			// so a bogus length can't make us allocate a huge buffer up front.
+			let got = (&mut self.reader)
+				.take(needed as u64)
+				.read_to_end(self.buffer.for_appending())?;
+			if got < needed {
+				return Err(DecodeErrorKind::Insufficient);
+			}
+		}
+		Ok(self.buffer.available())
 	}
 
-	/// Interpret a [`Event::Signed`] value.
-	///
-	/// # Overflow behavior
-	///
-	/// On overflow, this function will return [`None`].
-	pub fn interpret_signed_checked(val: u64) -> Option<i64> {
-		match val {
-			n if n < i64::MAX as u64 => Some(-1 - (n as i64)),
-			_ => None,
+	fn consume(&mut self, len: usize) -> Result<(), DecodeErrorKind> {
+		let consumed = self.buffer.consume(len);
+		if let Some(recording) = &mut self.recording {
+			recording.extend_from_slice(consumed);
 		}
+		Ok(())
 	}
 
-	/// Interpret a [`Event::Signed`] value.
-	///
-	/// # Overflow behavior
-	///
-	/// This function does not overflow, because it returns an [`i128`].
-	pub fn interpret_signed_wide(val: u64) -> i128 {
-		-1 - (val as i128)
+	fn take(&mut self, len: usize) -> Result<Cow<'de, [u8]>, DecodeErrorKind> {
+		let taken = self.buffer.consume(len).to_vec();
+		if let Some(recording) = &mut self.recording {
+			recording.extend_from_slice(&taken);
+		}
+		Ok(Cow::Owned(taken))
 	}
 
-	/// Create a [`Event::Signed`] or [`Event::Unsigned`] value.
-	pub fn create_signed(val: i64) -> Event<'static> {
-		if val.is_negative() {
-			Event::Signed(val.abs_diff(-1))
+	fn peek(&mut self, start: usize, len: usize) -> Result<Cow<'de, [u8]>, DecodeErrorKind> {
+		Ok(Cow::Owned(
+			self.buffer.available()[start..start + len].to_vec(),
+		))
+	}
+
+	fn is_buffer_empty(&self) -> bool {
+		self.buffer.is_empty()
+	}
+
+	fn skip(&mut self, len: u64) -> Result<(), DecodeErrorKind> {
+		let buffered =
+			usize::try_from(len).map_or(self.buffer.len(), |len| len.min(self.buffer.len()));
+		self.consume(buffered)?;
+		let rest = len - buffered as u64;
+		let mut rest_reader = (&mut self.reader).take(rest);
+		let skipped = match &mut self.recording {
+			Some(recording) => rest_reader.read_to_end(recording)? as u64,
+			None => std::io::copy(&mut rest_reader, &mut std::io::sink())?,
+		};
+		if skipped < rest {
+			Err(DecodeErrorKind::Insufficient)
 		} else {
-			Event::Unsigned(val as _)
+			Ok(())
 		}
 	}
 
-	/// Create a [`Event::Signed`] or [`Event::Unsigned`] value.
-	///
-	/// Because this takes an [`i128`], it can express all the numbers CBOR can encode.
-	/// However, some [`i128`]s cannot be encoded in basic CBOR integers.
-	/// In this case, it will return [`None`].
-	pub fn create_signed_wide(val: i128) -> Option<Event<'static>> {
-		if val.is_negative() {
-			match val.abs_diff(-1).try_into() {
-				Ok(val) => Some(Event::Signed(val)),
-				Err(_) => None,
+	fn read_into(&mut self, buf: &mut [u8]) -> Result<usize, DecodeErrorKind> {
+		if buf.is_empty() {
+			return Ok(0);
+		}
+		// Anything already buffered has to come first.
+		if !self.buffer.is_empty() {
+			let len = self.buffer.len().min(buf.len());
+			buf[..len].copy_from_slice(&self.buffer.available()[..len]);
+			self.consume(len)?;
+			return Ok(len);
+		}
+		let len = loop {
+			match self.reader.read(buf) {
+				Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+				result => break result?,
 			}
-		} else {
-			Some(Event::Unsigned(val as _))
+		};
+		if len == 0 {
+			return Err(DecodeErrorKind::Insufficient);
 		}
+		if let Some(recording) = &mut self.recording {
+			recording.extend_from_slice(&buf[..len]);
+		}
+		Ok(len)
+	}
+
+	fn start_recording(&mut self) {
+		self.recording = Some(Vec::new());
+	}
+
+	fn end_recording(&mut self) -> Cow<'de, [u8]> {
+		Cow::Owned(self.recording.take().unwrap_or_default())
 	}
 }
 
-/// A streaming encoder for the CBOR basic data model.
+/// A [`Source`] that pulls input from a [`BufRead`]er.
+///
+/// This works straight out of the reader's own buffer,
+/// so input is only copied when an event is split between two refills of it, or to hand out the contents of a string.
+/// That makes it much faster than a [`ReadSource`], which has to read exactly as much as each event needs.
+/// It still doesn't consume anything past the end of the CBOR, so the reader can be used for something else afterwards.
 #[derive(Debug, Clone)]
-pub struct Encoder<T: Write> {
-	dest: T,
-	pending: Vec<Pending>,
+pub struct BufReadSource<T: BufRead> {
+	reader: T,
+	/// Input that has been moved out of the reader, because an event didn't fit in what it had buffered.
+	buffer: Vec<u8>,
+	recording: Option<Vec<u8>>,
 }
 
-impl<T: Write> Encoder<T> {
-	pub fn new(dest: T) -> Self {
-		Encoder {
-			dest,
-			pending: Vec::new(),
+impl<T: BufRead> BufReadSource<T> {
+	pub fn new(reader: T) -> Self {
+		BufReadSource {
+			reader,
+			buffer: Vec::new(),
+			recording: None,
 		}
 	}
+}
 
-	pub fn feed_event(&mut self, event: Event) -> Result<(), EncodeError> {
-		macro_rules! write_initial_and_argument {
-			($major:expr, $argument:expr) => {
-				let major: u8 = $major << 5;
-				match $argument {
-					n if n <= 0x17 => {
-						self.dest.write_all(&[major | n as u8])?;
-					}
-					n if n <= u8::MAX as _ => {
-						self.dest.write_all(&[major | 0x18, n as u8])?;
-					}
-					n if n <= u16::MAX as _ => {
-						self.dest.write_all(&[major | 0x19])?;
-						self.dest.write_all(&u16::to_be_bytes(n as _))?;
-					}
-					n if n <= u32::MAX as _ => {
-						self.dest.write_all(&[major | 0x1A])?;
-						self.dest.write_all(&u32::to_be_bytes(n as _))?;
-					}
-					n => {
-						self.dest.write_all(&[major | 0x1B])?;
-						self.dest.write_all(&u64::to_be_bytes(n))?;
-					}
-				}
-			};
+/// Fill a [`BufRead`]er's buffer, trying again if it's interrupted.
+fn fill_buf(reader: &mut impl BufRead) -> Result<&[u8], DecodeErrorKind> {
+	loop {
+		match reader.fill_buf() {
+			Ok(_) => break,
+			Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+			Err(e) => return Err(e.into()),
 		}
+	}
+	// Now that the buffer has been filled, this just returns it.
+	Ok(reader.fill_buf()?)
+}
 
-		let mut pop_pending = false;
-		match self.pending.last_mut() {
-			Some(Pending::Array(ref mut n)) => {
-				*n -= 1;
-				pop_pending = *n == 0;
-			}
-			Some(Pending::Map(ref mut n, ref mut can_stop)) => {
-				*can_stop = match *can_stop {
-					true => false,
-					false => {
-						*n -= 1;
-						pop_pending = *n == 0;
-						true
-					}
-				};
-			}
-			Some(Pending::UnknownLengthMap(ref mut can_stop)) => {
-				*can_stop = !*can_stop;
+/// Get the input a [`BufRead`]er has already been made to buffer by [`fill_buf`], of which there must be at least `len` bytes.
+///
+/// This must only be used when the reader is known to have input buffered,
+/// since if its buffer is empty, [`BufRead::fill_buf`] reads more, which can block.
+/// [`BufReadSource`] makes sure of this by never touching the reader for zero-length input.
+/// Even then, nothing stops the reader from failing, or from losing what it had buffered,
+/// so those are errors rather than panics.
+fn buffered(reader: &mut impl BufRead, len: usize) -> Result<&[u8], DecodeErrorKind> {
+	let available = fill_buf(reader)?;
+	if available.len() < len {
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			"BufRead::fill_buf lost input it had buffered",
+		)
+		.into());
+	}
+	Ok(available)
+}
+
+impl<'de, T: BufRead> Source<'de> for BufReadSource<T> {
+	fn fill(&mut self, len: usize) -> Result<&[u8], DecodeErrorKind> {
+		if len == 0 {
+			return Ok(&self.buffer);
+		}
+		if self.buffer.is_empty() && fill_buf(&mut self.reader)?.len() >= len {
+			return buffered(&mut self.reader, len);
+		}
+		// Only move as much as is needed, so the rest is still in the reader if decoding ends here.
+		while self.buffer.len() < len {
+			let available = fill_buf(&mut self.reader)?;
+			if available.is_empty() {
+				return Err(DecodeErrorKind::Insufficient);
+			}
+			let moved = available.len().min(len - self.buffer.len());
+			self.buffer.extend_from_slice(&available[..moved]);
+			self.reader.consume(moved);
+		}
+		Ok(&self.buffer)
+	}
+
+	fn consume(&mut self, len: usize) -> Result<(), DecodeErrorKind> {
+		if len == 0 {
+			return Ok(());
+		}
+		if self.buffer.is_empty() {
+			if let Some(recording) = &mut self.recording {
+				recording.extend_from_slice(&buffered(&mut self.reader, len)?[..len]);
+			}
+			self.reader.consume(len);
+		} else {
+			let consumed = self.buffer.drain(..len);
+			if let Some(recording) = &mut self.recording {
+				recording.extend(consumed);
+			}
+		}
+		Ok(())
+	}
+
+	fn take(&mut self, len: usize) -> Result<Cow<'de, [u8]>, DecodeErrorKind> {
+		let taken: Vec<u8> = if len == 0 {
+			Vec::new()
+		} else if self.buffer.is_empty() {
+			let taken = buffered(&mut self.reader, len)?[..len].to_vec();
+			self.reader.consume(len);
+			taken
+		} else {
+			self.buffer.drain(..len).collect()
+		};
+		if let Some(recording) = &mut self.recording {
+			recording.extend_from_slice(&taken);
+		}
+		Ok(Cow::Owned(taken))
+	}
+
+	fn peek(&mut self, start: usize, len: usize) -> Result<Cow<'de, [u8]>, DecodeErrorKind> {
+		let available = if len == 0 {
+			return Ok(Cow::Owned(Vec::new()));
+		} else if self.buffer.is_empty() {
+			buffered(&mut self.reader, start + len)?
+		} else {
+			&self.buffer
+		};
+		Ok(Cow::Owned(available[start..start + len].to_vec()))
+	}
+
+	fn is_buffer_empty(&self) -> bool {
+		// Whatever's left in the reader's buffer is still the reader's.
+		self.buffer.is_empty()
+	}
+
+	fn skip(&mut self, len: u64) -> Result<(), DecodeErrorKind> {
+		let buffered =
+			usize::try_from(len).map_or(self.buffer.len(), |len| len.min(self.buffer.len()));
+		self.consume(buffered)?;
+		let mut rest = len - buffered as u64;
+		while rest > 0 {
+			let available = fill_buf(&mut self.reader)?;
+			if available.is_empty() {
+				return Err(DecodeErrorKind::Insufficient);
+			}
+			let skipped =
+				usize::try_from(rest).map_or(available.len(), |rest| rest.min(available.len()));
+			if let Some(recording) = &mut self.recording {
+				recording.extend_from_slice(&available[..skipped]);
+			}
+			self.reader.consume(skipped);
+			rest -= skipped as u64;
+		}
+		Ok(())
+	}
+
+	fn read_into(&mut self, buf: &mut [u8]) -> Result<usize, DecodeErrorKind> {
+		if buf.is_empty() {
+			return Ok(0);
+		}
+		let available = if self.buffer.is_empty() {
+			fill_buf(&mut self.reader)?
+		} else {
+			&self.buffer
+		};
+		if available.is_empty() {
+			return Err(DecodeErrorKind::Insufficient);
+		}
+		let len = available.len().min(buf.len());
+		buf[..len].copy_from_slice(&available[..len]);
+		self.consume(len)?;
+		Ok(len)
+	}
+
+	fn start_recording(&mut self) {
+		self.recording = Some(Vec::new());
+	}
+
+	fn end_recording(&mut self) -> Cow<'de, [u8]> {
+		Cow::Owned(self.recording.take().unwrap_or_default())
+	}
+}
+
+/// A [`Source`] that lends out parts of a byte slice.
+///
+/// Decoding from this is zero-copy: strings are borrowed straight from the slice.
+#[derive(Debug, Clone)]
+pub struct SliceSource<'a> {
+	input: &'a [u8],
+	/// The input as it was when recording started.
+	recording: Option<&'a [u8]>,
+}
+
+impl<'a> SliceSource<'a> {
+	pub fn new(input: &'a [u8]) -> Self {
+		SliceSource {
+			input,
+			recording: None,
+		}
+	}
+}
+
+impl<'a> Source<'a> for SliceSource<'a> {
+	fn fill(&mut self, len: usize) -> Result<&[u8], DecodeErrorKind> {
+		if self.input.len() < len {
+			Err(DecodeErrorKind::Insufficient)
+		} else {
+			Ok(self.input)
+		}
+	}
+
+	fn consume(&mut self, len: usize) -> Result<(), DecodeErrorKind> {
+		self.input = &self.input[len..];
+		Ok(())
+	}
+
+	fn take(&mut self, len: usize) -> Result<Cow<'a, [u8]>, DecodeErrorKind> {
+		let (taken, rest) = self.input.split_at(len);
+		self.input = rest;
+		Ok(Cow::Borrowed(taken))
+	}
+
+	fn peek(&mut self, start: usize, len: usize) -> Result<Cow<'a, [u8]>, DecodeErrorKind> {
+		Ok(Cow::Borrowed(&self.input[start..start + len]))
+	}
+
+	fn is_buffer_empty(&self) -> bool {
+		// Nothing is ever buffered, because the whole input is available all along.
+		true
+	}
+
+	fn start_recording(&mut self) {
+		self.recording = Some(self.input);
+	}
+
+	fn end_recording(&mut self) -> Cow<'a, [u8]> {
+		// Everything consumed since then is still in the original slice, so it can be lent out too.
+		let start = self.recording.take().unwrap_or(self.input);
+		Cow::Borrowed(&start[..start.len() - self.input.len()])
+	}
+}
+
+/// A [`Source`] that is given input piece by piece as it becomes available.
+///
+/// This is meant for input that cannot be waited on, such as a non-blocking socket.
+/// Instead of blocking until there is enough input for the next event,
+/// a decoder using this source [reports how much more it needs](`Decoder::try_next_event`).
+#[derive(Debug, Clone, Default)]
+pub struct PushSource {
+	buffer: InputBuffer,
+	shortfall: Option<NonZeroUsize>,
+	recording: Option<Vec<u8>>,
+}
+
+impl PushSource {
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	/// Add some input to the end of the buffer.
+	pub fn feed(&mut self, input: &[u8]) {
+		self.buffer.for_appending().extend_from_slice(input);
+	}
+}
+
+impl<'de> Source<'de> for PushSource {
+	fn fill(&mut self, len: usize) -> Result<&[u8], DecodeErrorKind> {
+		match len
+			.checked_sub(self.buffer.len())
+			.and_then(NonZeroUsize::new)
+		{
+			Some(shortfall) => {
+				self.shortfall = Some(shortfall);
+				Err(DecodeErrorKind::Insufficient)
+			}
+			None => Ok(self.buffer.available()),
+		}
+	}
+
+	fn consume(&mut self, len: usize) -> Result<(), DecodeErrorKind> {
+		let consumed = self.buffer.consume(len);
+		if let Some(recording) = &mut self.recording {
+			recording.extend_from_slice(consumed);
+		}
+		Ok(())
+	}
+
+	fn take(&mut self, len: usize) -> Result<Cow<'de, [u8]>, DecodeErrorKind> {
+		let taken = self.buffer.consume(len).to_vec();
+		if let Some(recording) = &mut self.recording {
+			recording.extend_from_slice(&taken);
+		}
+		Ok(Cow::Owned(taken))
+	}
+
+	fn peek(&mut self, start: usize, len: usize) -> Result<Cow<'de, [u8]>, DecodeErrorKind> {
+		Ok(Cow::Owned(
+			self.buffer.available()[start..start + len].to_vec(),
+		))
+	}
+
+	fn is_buffer_empty(&self) -> bool {
+		self.buffer.is_empty()
+	}
+
+	fn start_recording(&mut self) {
+		self.recording = Some(Vec::new());
+	}
+
+	fn end_recording(&mut self) -> Cow<'de, [u8]> {
+		Cow::Owned(self.recording.take().unwrap_or_default())
+	}
+}
+
+/// An event pulled by [`Decoder::next_event_streaming`].
+#[derive(Debug, Clone)]
+pub enum StreamingEvent<'a> {
+	/// The start of a known-length byte string, with its length in bytes.
+	///
+	/// Its contents can be read from [`Decoder::string_reader`].
+	ByteString(u64),
+	/// The start of a known-length text string, with its length in bytes.
+	///
+	/// Its contents can be read from [`Decoder::string_reader`].
+	TextString(u64),
+	/// Any other event.
+	Event(Event<'a>),
+}
+
+/// A [`Read`]er for the contents of a string, from [`Decoder::string_reader`].
+///
+/// Errors are reported as [`std::io::Error`]s wrapping a [`DecodeError`]:
+/// running out of input is [`UnexpectedEof`](`std::io::ErrorKind::UnexpectedEof`),
+/// and invalid UTF-8 in a text string is [`InvalidData`](`std::io::ErrorKind::InvalidData`).
+/// Errors from the underlying reader of a [`ReadSource`] are passed through as they are.
+#[derive(Debug)]
+pub struct StringReader<'a, S> {
+	decoder: &'a mut Decoder<S>,
+}
+
+impl<S> StringReader<'_, S> {
+	/// Get how many bytes of the string are left to read.
+	pub fn remaining(&self) -> u64 {
+		self.decoder
+			.string
+			.as_ref()
+			.map_or(0, |string| string.remaining)
+	}
+}
+
+impl<'de, S: Source<'de>> Read for StringReader<'_, S> {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		self.decoder.read_string(buf)
+	}
+}
+
+/// An iterator over the events of one data item, from [`Decoder::events`].
+#[derive(Debug)]
+pub struct Events<'a, 'de, S> {
+	decoder: &'a mut Decoder<S>,
+	done: bool,
+	_input: PhantomData<&'de [u8]>,
+}
+
+impl<'de, S: Source<'de>> Iterator for Events<'_, 'de, S> {
+	type Item = Result<Event<'de>, DecodeError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.done {
+			return None;
+		}
+		// Running out of input between items just means there are no more of them.
+		if self.decoder.pending.is_empty() {
+			if let Err(e) = self.decoder.peek_major_type() {
+				if matches!(e.kind(), DecodeErrorKind::Insufficient) {
+					self.done = true;
+					return None;
+				}
+			}
+		}
+		let event = self.decoder.next_event();
+		self.done = event.is_err() || self.decoder.pending.is_empty();
+		Some(event)
+	}
+}
+
+impl<'de, S: Source<'de>> FusedIterator for Events<'_, 'de, S> {}
+
+/// The outcome of [trying to pull an event](`Decoder::try_next_event`) from a push-mode decoder.
+#[derive(Debug)]
+pub enum TryNextEventOutcome<'a> {
+	/// An event was decoded.
+	GotEvent(Event<'a>),
+	/// The next event is incomplete.
+	///
+	/// This is the number of bytes that are missing, as far as the decoder can tell.
+	/// Once they have been [fed](`Decoder::feed`) in, the next event might turn out to need even more,
+	/// for instance if it's a string whose length was the only part missing.
+	Needs(NonZeroUsize),
+}
+
+/// Limits on how much a [`Decoder`] will accept, for use with untrusted input.
+///
+/// - `max_depth` is how deeply arrays, maps, tags, and unknown-length strings may be nested.
+/// - `max_string_len` is how long a byte- or text-string may be, in bytes.
+///   For an unknown-length string, this applies to all of its pieces together.
+/// - `max_collection_len` is how many items an array, or how many pairs a map, may have.
+///   Known-length arrays and maps are checked against the length they declare,
+///   and unknown-length ones are counted as their items come in.
+/// - `max_input_len` is how many bytes of input may be consumed in total.
+///
+/// When a limit is exceeded, the decoder returns [`DecodeErrorKind::TooDeep`], [`DecodeErrorKind::StringTooLong`],
+/// [`DecodeErrorKind::CollectionTooLong`], or [`DecodeErrorKind::InputTooLong`] respectively.
+/// [`None`] means no limit, which is the default for all of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DecodeLimits {
+	max_depth: Option<usize>,
+	max_string_len: Option<u64>,
+	max_collection_len: Option<u64>,
+	max_input_len: Option<u64>,
+}
+
+impl DecodeLimits {
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	config_accessors!(
+		max_depth,
+		Option<usize>,
+		max_depth,
+		max_depth_mut,
+		set_max_depth
+	);
+
+	config_accessors!(
+		max_string_len,
+		Option<u64>,
+		max_string_len,
+		max_string_len_mut,
+		set_max_string_len
+	);
+
+	config_accessors!(
+		max_collection_len,
+		Option<u64>,
+		max_collection_len,
+		max_collection_len_mut,
+		set_max_collection_len
+	);
+
+	config_accessors!(
+		max_input_len,
+		Option<u64>,
+		max_input_len,
+		max_input_len_mut,
+		set_max_input_len
+	);
+}
+
+/// A streaming decoder for the CBOR basic data model.
+///
+/// By default, this accepts any well-formed CBOR.
+/// With [`Self::set_require_preferred`], it also rejects anything that isn't in preferred serialization,
+/// returning [`DecodeErrorKind::NotPreferred`].
+/// With [`Self::set_require_definite`], it rejects indefinite-length strings, arrays and maps
+/// as soon as their headers appear, returning [`DecodeErrorKind::NotDefinite`],
+/// as protocols like CTAP2 and COSE require.
+///
+/// After an error in the input, the decoder can't carry on.
+/// Skipping past damaged items in a CBOR Sequence is only available for the tree decoders,
+/// with [`tree::Decoder::decode_sequence_resync`].
+#[derive(Debug, Clone)]
+pub struct Decoder<S> {
+	source: S,
+	/// Each pending item comes with how deeply it's nested.
+	pending: Vec<(Pending, usize)>,
+	/// Where the most recently opened array, map, tag or unknown-length string at each depth is inside the one around it, outermost first.
+	///
+	/// Unlike `pending`, this keeps track of arrays and maps whose last item has started, so it can be used to build [error paths](`DecodeError::path`).
+	path: Vec<Option<PathSegment>>,
+	limits: DecodeLimits,
+	position: u64,
+	/// A string whose contents are being [streamed](`Decoder::next_event_streaming`), if there's one that isn't finished.
+	string: Option<StreamedString>,
+	require_preferred: bool,
+	require_definite: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Pending {
+	/// The items of an unknown-length array, with how many there have been so far.
+	UnknownLengthArray(u64),
+	/// The pieces of an unknown-length string, which all have to be known-length strings of this major type,
+	/// with their total length so far.
+	Chunks(u8, u64),
+	/// The items of an array, with its length and how many are left.
+	Array(u64, u64),
+	/// The pairs of a map, with its length, how many are left, and whether the next item is a key.
+	Map(u64, u64, bool),
+	/// The pairs of an unknown-length map, with how many there have been so far.
+	UnknownLengthMap(u64, bool),
+	Tag,
+}
+
+/// What an event is nested inside, which decides what it's allowed to be.
+struct Context {
+	/// The innermost item that's still pending, if any.
+	pending: Option<Pending>,
+	/// How deeply the event is nested.
+	depth: usize,
+}
+
+impl Context {
+	/// Work out where the event is inside the array or map around it, if that's what it's in.
+	///
+	/// Streaming decoders don't keep map keys around, so values aren't described by their keys.
+	fn segment(&self) -> Option<PathSegment> {
+		match self.pending? {
+			Pending::Array(len, left) => Some(PathSegment::Index(len - left)),
+			Pending::UnknownLengthArray(count) => Some(PathSegment::Index(count)),
+			Pending::Map(len, left, true) => Some(PathSegment::Key(len - left)),
+			Pending::Map(len, left, false) => Some(PathSegment::Value(len - left, None)),
+			Pending::UnknownLengthMap(count, true) => Some(PathSegment::Key(count)),
+			Pending::UnknownLengthMap(count, false) => Some(PathSegment::Value(count - 1, None)),
+			Pending::Chunks(..) | Pending::Tag => None,
+		}
+	}
+}
+
+/// The header of an event, which has been checked but not yet consumed.
+#[derive(Clone, Copy)]
+struct Header {
+	major: u8,
+	additional: u8,
+	/// This is [`None`] for unknown-length items and breaks.
+	argument: Option<u64>,
+	/// The length of the header itself, including the initial byte.
+	len: usize,
+	/// The length of the contents of a known-length string, which follow the header.
+	payload_len: u64,
+}
+
+impl Header {
+	fn has_payload(&self) -> bool {
+		matches!((self.major, self.argument), (2 | 3, Some(_)))
+	}
+
+	/// Turn the header into an event, given the contents of a known-length string.
+	///
+	/// The payload is ignored for any other kind of event.
+	fn into_event<'de>(self, payload: Cow<'de, [u8]>) -> Result<Event<'de>, DecodeErrorKind> {
+		Ok(match (self.major, self.argument) {
+			(0, Some(n)) => Event::Unsigned(n),
+			(1, Some(n)) => Event::Signed(n),
+			(2, Some(_)) => Event::ByteString(payload),
+			(2, None) => Event::UnknownLengthByteString,
+			(3, Some(_)) => Event::TextString(match payload {
+				Cow::Borrowed(contents) => match std::str::from_utf8(contents) {
+					Ok(s) => Cow::Borrowed(s),
+					// FromUtf8Error can only be made by actually trying to make a String,
+					// so do that, now that we know it's going to fail.
+					Err(_) => {
+						return Err(String::from_utf8(contents.to_owned()).unwrap_err().into())
+					}
+				},
+				Cow::Owned(contents) => Cow::Owned(String::from_utf8(contents)?),
+			}),
+			(3, None) => Event::UnknownLengthTextString,
+			(4, Some(len)) => Event::Array(len),
+			(4, None) => Event::UnknownLengthArray,
+			(5, Some(len)) => Event::Map(len),
+			(5, None) => Event::UnknownLengthMap,
+			(6, Some(tag)) => Event::Tag(tag),
+			(7, Some(n)) => match self.additional {
+				0..=24 => Event::Simple(n as u8),
+				25 => Event::Float16(f16::from_bits(n as u16)),
+				26 => Event::Float32(f32::from_bits(n as u32)),
+				27 => Event::Float64(f64::from_bits(n)),
+				_ => unreachable!(),
+			},
+			(7, None) => Event::Break,
+			// Anything else was rejected when the header was read.
+			_ => unreachable!(),
+		})
+	}
+}
+
+/// Check that an event's header is in preferred serialization.
+fn check_preferred(
+	major: u8,
+	additional: u8,
+	argument: Option<u64>,
+) -> Result<(), NotPreferredKind> {
+	let argument = match argument {
+		Some(argument) => argument,
+		None => return Ok(()),
+	};
+	let preferred = match (major, additional) {
+		// A float is preferred if it can't be narrowed without changing it, which is exactly what the encoder checks.
+		(7, 26) => f64_to_f16(f32_to_f64(f32::from_bits(argument as u32))).is_none(),
+		(7, 27) => f64_to_f32(f64::from_bits(argument)).is_none(),
+		// Simple values below 32 can't be encoded in two bytes at all.
+		(7, _) => true,
+		(_, 24) => argument >= 24,
+		(_, 25) => argument > 0xFF,
+		(_, 26) => argument > 0xFFFF,
+		(_, 27) => argument > 0xFFFF_FFFF,
+		_ => true,
+	};
+	match (preferred, major) {
+		(true, _) => Ok(()),
+		(false, 0 | 1) => Err(NotPreferredKind::Integer(argument)),
+		(false, 2..=5) => Err(NotPreferredKind::Length(argument)),
+		(false, 6) => Err(NotPreferredKind::Tag(argument)),
+		(false, _) => Err(NotPreferredKind::Float),
+	}
+}
+
+/// What [`Decoder::decode_event`] does with the contents of known-length strings.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum StringContents {
+	/// Put them in the event.
+	Take,
+	/// Throw them away.
+	Skip,
+	/// Leave them to be read through a [`StringReader`].
+	Stream,
+}
+
+/// A known-length string whose contents are being read through a [`StringReader`].
+#[derive(Debug, Clone)]
+struct StreamedString {
+	remaining: u64,
+	/// This is only there for text strings.
+	utf8: Option<Utf8Validator>,
+}
+
+/// Checks that a text string is valid UTF-8, a piece at a time.
+#[derive(Debug, Clone, Default)]
+struct Utf8Validator {
+	/// The start of a character that was cut off at the end of the last piece.
+	partial: [u8; 4],
+	partial_len: usize,
+}
+
+impl Utf8Validator {
+	/// Check the next piece of the string, which starts at `offset` in the input.
+	fn feed(&mut self, mut piece: &[u8], mut offset: u64) -> Result<(), DecodeError> {
+		if self.partial_len > 0 {
+			// Only valid starts of characters are ever kept, so this is always more than is already there.
+			let width = match self.partial[0] {
+				0xC0..=0xDF => 2,
+				0xE0..=0xEF => 3,
+				_ => 4,
+			};
+			let take = (width - self.partial_len).min(piece.len());
+			self.partial[self.partial_len..self.partial_len + take].copy_from_slice(&piece[..take]);
+			self.partial_len += take;
+			piece = &piece[take..];
+			offset += take as u64;
+			let character = &self.partial[..self.partial_len];
+			match std::str::from_utf8(character) {
+				Ok(_) => self.partial_len = 0,
+				Err(e) if e.error_len().is_some() => {
+					return Err(invalid_utf8(character, offset - character.len() as u64))
+				}
+				// The character still isn't finished, so the whole piece went into it.
+				Err(_) => return Ok(()),
+			}
+		}
+		match std::str::from_utf8(piece) {
+			Ok(_) => Ok(()),
+			Err(e) => {
+				let rest = &piece[e.valid_up_to()..];
+				match e.error_len() {
+					Some(_) => Err(invalid_utf8(rest, offset + e.valid_up_to() as u64)),
+					None => {
+						self.partial[..rest.len()].copy_from_slice(rest);
+						self.partial_len = rest.len();
+						Ok(())
+					}
+				}
+			}
+		}
+	}
+
+	/// Check that the string didn't end partway through a character, given the offset of its end.
+	fn finish(&self, offset: u64) -> Result<(), DecodeError> {
+		match self.partial_len {
+			0 => Ok(()),
+			len => Err(invalid_utf8(&self.partial[..len], offset - len as u64)),
+		}
+	}
+}
+
+fn invalid_utf8(bytes: &[u8], offset: u64) -> DecodeError {
+	// FromUtf8Error can only be made by actually trying to make a String.
+	DecodeError::new(
+		String::from_utf8(bytes.to_owned()).unwrap_err().into(),
+		offset,
+	)
+}
+
+/// Turn an error from reading the contents of a streamed string into an I/O error.
+fn string_read_error(kind: DecodeErrorKind, offset: u64) -> std::io::Error {
+	let io_kind = match kind {
+		// Errors from the underlying reader are passed through as they are.
+		DecodeErrorKind::IoError(e) => return e,
+		DecodeErrorKind::Insufficient => std::io::ErrorKind::UnexpectedEof,
+		_ => std::io::ErrorKind::InvalidData,
+	};
+	std::io::Error::new(io_kind, DecodeError::new(kind, offset))
+}
+
+impl<T: Read> Decoder<ReadSource<T>> {
+	/// Create a decoder that reads from a [`Read`]er.
+	///
+	/// This reads exactly as much as each event needs, and nothing past the end of the CBOR.
+	/// If the reader is buffered, [`Decoder::from_buf_read`] is much faster.
+	pub fn new(source: T) -> Self {
+		Self::from_source(ReadSource::new(source))
+	}
+
+	/// End the decoding.
+	///
+	/// This is [checked](`Self::ready_to_finish`) and will return [`DecodeErrorKind::Insufficient`] if the CBOR is incomplete.
+	/// If you've performed the check already, try [`Self::force_finish`].
+	pub fn finish(self) -> Result<T, DecodeError> {
+		if self.ready_to_finish() {
+			Ok(self.source.reader)
+		} else {
+			Err(DecodeError::new(
+				DecodeErrorKind::Insufficient,
+				self.position,
+			))
+		}
+	}
+
+	/// End the decoding, without checking whether the decoder is finished or not.
+	///
+	/// This does not return the original reader,
+	/// but the reader returned behaves as if it were the original reader.
+	/// (The discrepancy is because [`ReadSource`] contains an internal buffer.
+	/// Rest assured it behaves as if this buffer were not used.)
+	pub fn force_finish(self) -> impl Read {
+		std::io::Cursor::new(self.source.buffer.into_vec()).chain(self.source.reader)
+	}
+
+	/// Start decoding from a different reader, reusing the memory this decoder has already allocated.
+	///
+	/// The decoder keeps its limits and settings, but is otherwise as good as new.
+	/// This returns the old reader; anything that was read from it but not decoded is dropped.
+	pub fn reset(&mut self, source: T) -> T {
+		self.reset_state();
+		self.source.buffer.clear();
+		self.source.recording = None;
+		std::mem::replace(&mut self.source.reader, source)
+	}
+}
+
+impl<T: BufRead> Decoder<BufReadSource<T>> {
+	/// Create a decoder that reads from a [`BufRead`]er.
+	///
+	/// This is much faster than [`Decoder::new`], since it decodes straight out of the reader's buffer,
+	/// but like that, it doesn't consume anything past the end of the CBOR.
+	pub fn from_buf_read(source: T) -> Self {
+		Self::from_source(BufReadSource::new(source))
+	}
+
+	/// End the decoding.
+	///
+	/// This is [checked](`Self::ready_to_finish`) and will return [`DecodeErrorKind::Insufficient`] if the CBOR is incomplete.
+	/// If you've performed the check already, try [`Self::force_finish`].
+	pub fn finish(self) -> Result<T, DecodeError> {
+		if self.ready_to_finish() {
+			Ok(self.source.reader)
+		} else {
+			Err(DecodeError::new(
+				DecodeErrorKind::Insufficient,
+				self.position,
+			))
+		}
+	}
+
+	/// End the decoding, without checking whether the decoder is finished or not.
+	///
+	/// Like the version for a [`ReadSource`],
+	/// this returns a reader that behaves as if it were the original reader.
+	pub fn force_finish(self) -> impl BufRead {
+		std::io::Cursor::new(self.source.buffer).chain(self.source.reader)
+	}
+
+	/// Start decoding from a different reader, reusing the memory this decoder has already allocated.
+	///
+	/// See [the version for a `ReadSource`](`Decoder::reset`) for details.
+	pub fn reset(&mut self, source: T) -> T {
+		self.reset_state();
+		self.source.buffer.clear();
+		self.source.recording = None;
+		std::mem::replace(&mut self.source.reader, source)
+	}
+}
+
+impl<'a> Decoder<SliceSource<'a>> {
+	/// Create a decoder that reads from a byte slice.
+	///
+	/// Unlike a decoder created with [`Decoder::new`],
+	/// this one borrows the contents of byte- and text-strings from the slice instead of copying them.
+	pub fn from_slice(input: &'a [u8]) -> Self {
+		Self::from_source(SliceSource::new(input))
+	}
+
+	/// End the decoding.
+	///
+	/// This is [checked](`Self::ready_to_finish`) and will return [`DecodeErrorKind::Insufficient`] if the CBOR is incomplete.
+	/// On success, it returns the part of the slice that has not been decoded.
+	pub fn finish(self) -> Result<&'a [u8], DecodeError> {
+		if self.ready_to_finish() {
+			Ok(self.source.input)
+		} else {
+			Err(DecodeError::new(
+				DecodeErrorKind::Insufficient,
+				self.position,
+			))
+		}
+	}
+
+	/// End the decoding, without checking whether the decoder is finished or not.
+	///
+	/// This returns the part of the slice that has not been decoded.
+	pub fn force_finish(self) -> &'a [u8] {
+		self.source.input
+	}
+
+	/// Start decoding a different slice, reusing the memory this decoder has already allocated.
+	///
+	/// The decoder keeps its limits and settings, but is otherwise as good as new.
+	pub fn reset(&mut self, input: &'a [u8]) {
+		self.reset_state();
+		self.source = SliceSource::new(input);
+	}
+}
+
+impl Decoder<PushSource> {
+	/// Create a decoder that is given its input piece by piece, through [`Self::feed`].
+	pub fn new_push() -> Self {
+		Self::from_source(PushSource::new())
+	}
+
+	/// Give the decoder some more input.
+	pub fn feed(&mut self, input: &[u8]) {
+		self.source.feed(input);
+	}
+
+	/// Try to pull an event from the decoder.
+	///
+	/// Unlike [`Self::next_event`], running out of input isn't an error:
+	/// instead, this reports how much more input is needed.
+	/// Nothing is consumed in that case, so it's fine to [feed](`Self::feed`) in more and try again.
+	pub fn try_next_event(&mut self) -> Result<TryNextEventOutcome<'static>, DecodeError> {
+		self.source.shortfall = None;
+		if let Err(e) = self.skip_rest_of_string() {
+			return match (e.kind(), self.source.shortfall.take()) {
+				(DecodeErrorKind::Insufficient, Some(n)) => Ok(TryNextEventOutcome::Needs(n)),
+				_ => Err(e),
+			};
+		}
+		let start = self.position;
+		let context = self.context();
+		match self.decode_event(StringContents::Take) {
+			Ok((event, _)) => Ok(TryNextEventOutcome::GotEvent(event)),
+			Err(kind) => match (&kind, self.source.shortfall.take()) {
+				(DecodeErrorKind::Insufficient, Some(n)) => Ok(TryNextEventOutcome::Needs(n)),
+				// Working out the path takes time proportional to how deeply nested the event is,
+				// so it's left until it's certain the error will be returned.
+				_ => Err(self.error(kind, start, &context)),
+			},
+		}
+	}
+
+	/// End the decoding.
+	///
+	/// This is [checked](`Self::ready_to_finish`) and will return [`DecodeErrorKind::Insufficient`] if the CBOR is incomplete,
+	/// or if there is input left over that hasn't been decoded.
+	/// If you've performed the check already, try [`Self::force_finish`].
+	pub fn finish(self) -> Result<(), DecodeError> {
+		if self.ready_to_finish() {
+			Ok(())
+		} else {
+			Err(DecodeError::new(
+				DecodeErrorKind::Insufficient,
+				self.position,
+			))
+		}
+	}
+
+	/// End the decoding, without checking whether the decoder is finished or not.
+	///
+	/// This returns any input that was fed in, but hasn't been decoded.
+	pub fn force_finish(self) -> Vec<u8> {
+		self.source.buffer.into_vec()
+	}
+
+	/// Start decoding afresh, reusing the memory this decoder has already allocated.
+	///
+	/// The decoder keeps its limits and settings, but any input that has been fed in and not decoded is dropped.
+	pub fn reset(&mut self) {
+		self.reset_state();
+		self.source.buffer.clear();
+		self.source.shortfall = None;
+		self.source.recording = None;
+	}
+}
+
+impl<'de, S: Source<'de>> Decoder<S> {
+	pub fn from_source(source: S) -> Self {
+		Decoder {
+			source,
+			pending: Vec::new(),
+			path: Vec::new(),
+			limits: Default::default(),
+			position: 0,
+			string: None,
+			require_preferred: false,
+			require_definite: false,
+		}
+	}
+
+	/// Forget everything about the input decoded so far, for the `reset` methods.
+	fn reset_state(&mut self) {
+		self.pending.clear();
+		self.path.clear();
+		self.position = 0;
+		self.string = None;
+	}
+
+	config_accessors!(limits, DecodeLimits, limits, limits_mut, set_limits);
+
+	config_accessors!(
+		require_preferred,
+		bool,
+		require_preferred,
+		require_preferred_mut,
+		set_require_preferred
+	);
+
+	config_accessors!(
+		require_definite,
+		bool,
+		require_definite,
+		require_definite_mut,
+		set_require_definite
+	);
+
+	/// Get how many bytes of input have been decoded so far.
+	///
+	/// This is also the offset of the start of the next event.
+	pub fn position(&self) -> u64 {
+		self.position
+	}
+
+	/// Get how deeply the innermost item that's still pending is nested, or 0 between items.
+	pub(crate) fn depth(&self) -> usize {
+		self.pending.last().map_or(0, |(_, depth)| *depth)
+	}
+
+	/// Make an error about the event at `offset`, which is nested inside `context`, including its path.
+	fn error(&self, kind: DecodeErrorKind, offset: u64, context: &Context) -> DecodeError {
+		let outer = &self.path[..(context.depth - 1).min(self.path.len())];
+		std::iter::once(context.segment())
+			.chain(outer.iter().rev().cloned())
+			.flatten()
+			.fold(DecodeError::new(kind, offset), DecodeError::within)
+	}
+
+	/// Work out what the next event is nested inside.
+	fn context(&self) -> Context {
+		match self.pending.last() {
+			Some((pending, depth)) => Context {
+				pending: Some(*pending),
+				depth: depth + 1,
+			},
+			None => Context {
+				pending: None,
+				depth: 1,
+			},
+		}
+	}
+
+	/// Pull an event from the decoder.
+	///
+	/// Whether the contents of byte- and text-strings are borrowed depends on the [`Source`].
+	/// A [`SliceSource`] lends them out of the original slice,
+	/// but a [`ReadSource`] has to copy them.
+	///
+	/// If this fails, the error's [offset](`DecodeError::offset`) is the start of the event that couldn't be decoded.
+	pub fn next_event(&mut self) -> Result<Event<'de>, DecodeError> {
+		self.next_event_detailed().map(|(event, _)| event)
+	}
+
+	/// Pull an event from the decoder, along with how its argument was encoded.
+	///
+	/// [`Event`]s don't say whether, for instance, `1` was encoded as `0x01` or `0x18 0x01`,
+	/// or whether a float was encoded as an f16, f32 or f64, but the [`ArgumentWidth`] does.
+	/// Feeding both to [`Encoder::feed_event_detailed`] produces exactly the same bytes as were decoded.
+	/// (The way unknown-length strings are split into chunks is already part of the events.)
+	pub fn next_event_detailed(&mut self) -> Result<(Event<'de>, ArgumentWidth), DecodeError> {
+		self.skip_rest_of_string()?;
+		let start = self.position;
+		let context = self.context();
+		self.decode_event(StringContents::Take)
+			.map_err(|kind| self.error(kind, start, &context))
+	}
+
+	/// Pull an event from the decoder, leaving the contents of known-length strings to be read separately.
+	///
+	/// This is meant for strings too large to hold in memory.
+	/// Instead of a whole byte- or text-string, this returns just its length,
+	/// and its contents can then be read a piece at a time from [`Self::string_reader`].
+	/// Text strings are checked for valid UTF-8 as they are read.
+	///
+	/// If the contents aren't read to the end, whatever is left of them is skipped, without being checked,
+	/// as soon as the decoder is asked for anything else.
+	pub fn next_event_streaming(&mut self) -> Result<StreamingEvent<'de>, DecodeError> {
+		self.skip_rest_of_string()?;
+		let start = self.position;
+		let context = self.context();
+		let (event, _) = self
+			.decode_event(StringContents::Stream)
+			.map_err(|kind| self.error(kind, start, &context))?;
+		let len = self.string.as_ref().map_or(0, |string| string.remaining);
+		Ok(match event {
+			Event::ByteString(_) => StreamingEvent::ByteString(len),
+			Event::TextString(_) => StreamingEvent::TextString(len),
+			event => StreamingEvent::Event(event),
+		})
+	}
+
+	/// Get an iterator over the events of the rest of the current data item.
+	///
+	/// Between items, this means all of the next one.
+	/// The iterator ends after the event that completes the item, or after an error,
+	/// so for a CBOR Sequence, call this again for each item.
+	/// If the input ends between items, the iterator is empty.
+	pub fn events(&mut self) -> Events<'_, 'de, S> {
+		Events {
+			decoder: self,
+			done: false,
+			_input: PhantomData,
+		}
+	}
+
+	/// Get a reader for the contents of the string that was just [pulled](`Self::next_event_streaming`).
+	///
+	/// If there is no such string, or it has already been read to the end, the reader is empty.
+	pub fn string_reader(&mut self) -> StringReader<'_, S> {
+		StringReader { decoder: self }
+	}
+
+	/// Skip whatever hasn't been read of a string whose contents were being streamed.
+	fn skip_rest_of_string(&mut self) -> Result<(), DecodeError> {
+		if let Some(string) = &self.string {
+			let remaining = string.remaining;
+			self.source
+				.skip(remaining)
+				.map_err(|kind| DecodeError::new(kind, self.position))?;
+			self.position += remaining;
+			self.string = None;
+		}
+		Ok(())
+	}
+
+	/// Read part of the contents of a string whose contents are being streamed.
+	fn read_string(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		let string = match &mut self.string {
+			Some(string) => string,
+			None => return Ok(0),
+		};
+		let len = usize::try_from(string.remaining)
+			.map_or(buf.len(), |remaining| remaining.min(buf.len()));
+		let start = self.position;
+		let got = self
+			.source
+			.read_into(&mut buf[..len])
+			.map_err(|kind| string_read_error(kind, start))?;
+		self.position += got as u64;
+		string.remaining -= got as u64;
+		if let Some(utf8) = &mut string.utf8 {
+			let checked = match string.remaining {
+				0 => utf8
+					.feed(&buf[..got], start)
+					.and_then(|()| utf8.finish(self.position)),
+				_ => utf8.feed(&buf[..got], start),
+			};
+			checked.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+		}
+		if string.remaining == 0 {
+			self.string = None;
+		}
+		Ok(got)
+	}
+
+	/// Look at the next event without consuming it.
+	///
+	/// This decodes the event just like [`Self::next_event`], errors included, but leaves the decoder as it was,
+	/// so the next call to `next_event` returns the same event.
+	/// The contents of strings are borrowed if the [`Source`] can lend them out;
+	/// otherwise they are copied, once for the peek and again when the event is actually pulled.
+	pub fn peek_event(&mut self) -> Result<Event<'de>, DecodeError> {
+		self.skip_rest_of_string()?;
+		let start = self.position;
+		let context = self.context();
+		self.peek_event_at(0, &context)
+			.map_err(|kind| self.error(kind, start, &context))
+	}
+
+	/// Look at the major type of the next event without consuming it.
+	///
+	/// This only reads the event's first byte, so unlike [`Self::peek_event`],
+	/// it doesn't check whether the event is actually valid.
+	pub fn peek_major_type(&mut self) -> Result<u8, DecodeError> {
+		self.skip_rest_of_string()?;
+		match self.source.fill(1) {
+			Ok(input) => Ok(input[0] >> 5),
+			Err(kind) => Err(DecodeError::new(kind, self.position)),
+		}
+	}
+
+	/// Look at the event after the next one, which must be a tag, without consuming either of them.
+	///
+	/// This also returns the offset of the tagged event, for reporting errors about it.
+	#[cfg(feature = "chrono")]
+	pub(crate) fn peek_tagged_event(&mut self) -> Result<(Event<'de>, u64), DecodeError> {
+		self.skip_rest_of_string()?;
+		let start = self.position;
+		let context = self.context();
+		let tag = self
+			.read_header(0, &context, true)
+			.map_err(|kind| self.error(kind, start, &context))?;
+		debug_assert_eq!(tag.major, 6);
+		let tagged_start = start + tag.len as u64;
+		let tagged_context = Context {
+			pending: Some(Pending::Tag),
+			depth: context.depth + 1,
+		};
+		// A tag doesn't add anything to the path, so the tagged event is where the tag is.
+		match self.peek_event_at(tag.len, &tagged_context) {
+			Ok(event) => Ok((event, tagged_start)),
+			Err(kind) => Err(self.error(kind, tagged_start, &context)),
+		}
+	}
+
+	/// Skip over the next data item, including everything nested inside it.
+	///
+	/// The contents of byte- and text-strings are read past without being stored,
+	/// so this works for strings far too large to hold in memory.
+	/// Text strings aren't checked for valid UTF-8, since nothing looks at them.
+	///
+	/// This returns `false` if the first thing it encountered was a break (`0xFF`), in which case only that is consumed.
+	/// This may or may not be acceptable depending on the situation,
+	/// so `skip_item` doesn't count it as a failure.
+	///
+	/// If this fails, part of the item may already have been consumed,
+	/// so the decoder shouldn't be used any more.
+	/// In particular, a push-mode decoder can't be [fed](`Decoder::feed`) more input to carry on.
+	pub fn skip_item(&mut self) -> Result<bool, DecodeError> {
+		// Anything this item leaves pending is nested at least this deeply,
+		// so it's finished once nothing that deep is pending any more.
+		let depth = self.context().depth;
+		if let Event::Break = self.skip_event()? {
+			return Ok(false);
+		}
+		while matches!(self.pending.last(), Some((_, d)) if *d >= depth) {
+			self.skip_event()?;
+		}
+		Ok(true)
+	}
+
+	/// Read the next data item without decoding it, and return its encoded bytes exactly as they appear in the input.
+	///
+	/// This works like [`Self::skip_item`], including its caveats, except that the bytes it reads past are kept.
+	/// Whether they are borrowed depends on the [`Source`], as with [`Self::next_event`].
+	///
+	/// This returns [`None`] if the first thing it encountered was a break (`0xFF`), in which case only that is consumed.
+	pub fn read_raw_item(&mut self) -> Result<Option<Cow<'de, [u8]>>, DecodeError> {
+		// The rest of a streamed string isn't part of the item.
+		self.skip_rest_of_string()?;
+		self.source.start_recording();
+		let skipped = self.skip_item();
+		let raw = self.source.end_recording();
+		Ok(skipped?.then_some(raw))
+	}
+
+	/// Pull an event from the decoder, skipping the contents of strings.
+	fn skip_event(&mut self) -> Result<Event<'de>, DecodeError> {
+		self.skip_rest_of_string()?;
+		let start = self.position;
+		let context = self.context();
+		self.decode_event(StringContents::Skip)
+			.map(|(event, _)| event)
+			.map_err(|kind| self.error(kind, start, &context))
+	}
+
+	/// Read and check the header of the event starting `offset` bytes into the available input, without consuming anything.
+	///
+	/// Everything that could make the event invalid is checked here,
+	/// so that once this succeeds, the event can be consumed without anything going wrong halfway through.
+	/// If `fill_payload` is set, the contents of a known-length string are made available too.
+	fn read_header(
+		&mut self,
+		offset: usize,
+		context: &Context,
+		fill_payload: bool,
+	) -> Result<Header, DecodeErrorKind> {
+		let initial = self.source.fill(offset + 1)?[offset];
+		let major = initial >> 5;
+		let additional = initial & 0b11111;
+
+		// remember that this includes the initial
+		let len = match additional {
+			0..=23 | 31 => 1,
+			24 => 2,
+			25 => 3,
+			26 => 5,
+			27 => 9,
+			28..=30 => return Err(MalformedKind::ReservedAdditionalInfo(additional).into()),
+			32..=u8::MAX => unreachable!(),
+		};
+		if let Some(Pending::Chunks(chunk_major, _)) = context.pending {
+			if !((major == chunk_major && additional != 31) || initial == 0xFF) {
+				return Err(MalformedKind::InvalidChunk.into());
+			}
+		}
+		let header = &self.source.fill(offset + len)?[offset..];
+		let argument = match additional {
+			n @ 0..=23 => Some(n as u64),
+			24 => Some(header[1] as u64),
+			25 => Some(read_be_u16(&header[1..]) as u64),
+			26 => Some(read_be_u32(&header[1..]) as u64),
+			27 => Some(read_be_u64(&header[1..])),
+			_ => None,
+		};
+		if self.require_preferred {
+			check_preferred(major, additional, argument)?;
+		}
+		match (major, argument) {
+			(0 | 1 | 6, None) => return Err(MalformedKind::IndefiniteLength(major).into()),
+			(2..=5, None) if self.require_definite => {
+				return Err(DecodeErrorKind::NotDefinite(major))
+			}
+			(7, Some(n)) if additional == 24 && n < 32 => {
+				return Err(MalformedKind::InvalidSimple(n as u8).into())
+			}
+			(7, None) => match context.pending {
+				// A break can't come between a map key and its value.
+				Some(
+					Pending::UnknownLengthArray(_)
+					| Pending::Chunks(..)
+					| Pending::UnknownLengthMap(_, true),
+				) => (),
+				_ => return Err(MalformedKind::UnexpectedBreak.into()),
+			},
+			_ => (),
+		}
+
+		let opens_item = match (major, argument) {
+			(2..=5, None) | (6, _) => true,
+			(4 | 5, Some(len)) => len > 0,
+			_ => false,
+		};
+		if opens_item && matches!(self.limits.max_depth, Some(max) if context.depth > max) {
+			return Err(DecodeErrorKind::TooDeep);
+		}
+
+		let payload_len = match (major, argument) {
+			(2 | 3, Some(payload_len)) => {
+				// The pieces of an unknown-length string count towards the limit together.
+				let total = match context.pending {
+					Some(Pending::Chunks(_, so_far)) => so_far.saturating_add(payload_len),
+					_ => payload_len,
+				};
+				if matches!(self.limits.max_string_len, Some(max) if total > max) {
+					return Err(DecodeErrorKind::StringTooLong);
+				}
+				payload_len
+			}
+			(4 | 5, Some(items)) => {
+				if matches!(self.limits.max_collection_len, Some(max) if items > max) {
+					return Err(DecodeErrorKind::CollectionTooLong);
+				}
+				0
+			}
+			_ => 0,
+		};
+		// Unknown-length arrays and maps don't declare how long they are, so they're counted as they go.
+		if let Some(Pending::UnknownLengthArray(items) | Pending::UnknownLengthMap(items, true)) =
+			context.pending
+		{
+			let is_break = major == 7 && argument.is_none();
+			if !is_break && matches!(self.limits.max_collection_len, Some(max) if items >= max) {
+				return Err(DecodeErrorKind::CollectionTooLong);
+			}
+		}
+		if let Some(max) = self.limits.max_input_len {
+			if self
+				.position
+				.saturating_add((offset + len) as u64)
+				.saturating_add(payload_len)
+				> max
+			{
+				return Err(DecodeErrorKind::InputTooLong);
+			}
+		}
+		// Strings are followed by their contents, which have to be available before anything is consumed.
+		// Otherwise, running out of input would leave the decoder in an inconsistent state.
+		if fill_payload {
+			// A string that doesn't even fit in the address space is too long to hold no matter the limits.
+			let end = usize::try_from(payload_len)
+				.ok()
+				.and_then(|payload_len| payload_len.checked_add(offset + len))
+				.ok_or(DecodeErrorKind::StringTooLong)?;
+			let input = self.source.fill(end)?;
+			// Text has to be checked now too, so that invalid text isn't consumed either.
+			if major == 3 && argument.is_some() {
+				let contents = &input[offset + len..end];
+				if std::str::from_utf8(contents).is_err() {
+					// FromUtf8Error can only be made by actually trying to make a String.
+					return Err(String::from_utf8(contents.to_owned()).unwrap_err().into());
+				}
+			}
+		}
+
+		Ok(Header {
+			major,
+			additional,
+			argument,
+			len,
+			payload_len,
+		})
+	}
+
+	/// Decode the event starting `offset` bytes into the available input, without consuming anything.
+	fn peek_event_at(
+		&mut self,
+		offset: usize,
+		context: &Context,
+	) -> Result<Event<'de>, DecodeErrorKind> {
+		let header = self.read_header(offset, context, true)?;
+		let payload = if header.has_payload() {
+			// This fits, since the payload has been filled.
+			self.source
+				.peek(offset + header.len, header.payload_len as usize)?
+		} else {
+			Cow::Borrowed(&[][..])
+		};
+		header.into_event(payload)
+	}
+
+	/// Decode the next event.
+	///
+	/// Unless the contents of known-length strings are being taken,
+	/// the events for them are left empty.
+	///
+	/// Along with the event, this returns how its argument was encoded.
+	fn decode_event(
+		&mut self,
+		contents: StringContents,
+	) -> Result<(Event<'de>, ArgumentWidth), DecodeErrorKind> {
+		let context = self.context();
+		let header = self.read_header(0, &context, contents == StringContents::Take)?;
+
+		// The event is known to be valid now, so it's safe to start consuming it.
+		let mut pop_pending = false;
+		match self.pending.last_mut().map(|(pending, _)| pending) {
+			Some(Pending::Array(_, ref mut n)) => {
+				*n -= 1;
+				if *n == 0 {
+					pop_pending = true;
+				}
+			}
+			Some(Pending::Map(_, ref mut n, ref mut can_stop)) => {
+				*can_stop = !*can_stop;
+				if *can_stop {
+					*n -= 1;
+					if *n == 0 {
+						pop_pending = true;
+					}
+				}
+			}
+			Some(Pending::UnknownLengthMap(ref mut n, ref mut can_stop)) => {
+				if *can_stop {
+					*n += 1;
+				}
+				*can_stop = !*can_stop;
+			}
+			Some(Pending::UnknownLengthArray(ref mut n)) => {
+				*n += 1;
+			}
+			Some(Pending::Chunks(_, ref mut total)) => {
+				*total += header.payload_len;
 			}
 			Some(Pending::Tag) => {
 				pop_pending = true;
 			}
-			Some(Pending::Break) | None => (),
+			None => (),
+		}
+		if pop_pending {
+			self.pending.pop();
+		}
+
+		self.source.consume(header.len)?;
+		self.position += header.len as u64;
+		let payload = if !header.has_payload() {
+			Cow::Borrowed(&[][..])
+		} else {
+			match contents {
+				StringContents::Take => {
+					self.position += header.payload_len;
+					self.source.take(header.payload_len as usize)?
+				}
+				StringContents::Skip => {
+					self.source.skip(header.payload_len)?;
+					self.position += header.payload_len;
+					Cow::Borrowed(&[][..])
+				}
+				StringContents::Stream => {
+					self.string = Some(StreamedString {
+						remaining: header.payload_len,
+						utf8: (header.major == 3).then(Utf8Validator::default),
+					});
+					Cow::Borrowed(&[][..])
+				}
+			}
+		};
+		let event = header.into_event(payload)?;
+
+		// An array, for instance, is popped as soon as its last item starts,
+		// but that item is still nested inside it, so this uses the depth from before anything was popped.
+		let pending = match event {
+			Event::UnknownLengthByteString | Event::UnknownLengthTextString => {
+				Some(Pending::Chunks(header.major, 0))
+			}
+			Event::Array(len) if len > 0 => Some(Pending::Array(len, len)),
+			Event::UnknownLengthArray => Some(Pending::UnknownLengthArray(0)),
+			Event::Map(len) if len > 0 => Some(Pending::Map(len, len, true)),
+			Event::UnknownLengthMap => Some(Pending::UnknownLengthMap(0, true)),
+			Event::Tag(_) => Some(Pending::Tag),
+			Event::Break => {
+				self.pending.pop();
+				None
+			}
+			_ => None,
+		};
+		if let Some(pending) = pending {
+			self.pending.push((pending, context.depth));
+			self.path.truncate(context.depth - 1);
+			self.path.push(context.segment());
+		}
+		Ok((event, ArgumentWidth::from_additional(header.additional)))
+	}
+
+	/// Check whether it is possible to end the decoding now.
+	///
+	/// If this returns true, it means cutting off the CBOR now results in a complete object, _and_ there is no extra data in the internal buffer.
+	/// There can be extra data in the internal buffer if a partial CBOR event has just been read.
+	pub fn ready_to_finish(&self) -> bool {
+		self.pending.is_empty() && self.string.is_none() && self.source.is_buffer_empty()
+	}
+}
+
+/// An event encountered while decoding or encoding CBOR using a streaming basic implementation.
+#[derive(Debug, Clone)]
+pub enum Event<'a> {
+	/// An unsigned integer.
+	Unsigned(u64),
+	/// A signed integer in a slightly odd representation.
+	///
+	/// The actual value of the integer is -1 minus the provided value.
+	/// Some integers that can be CBOR encoded underflow [`i64`].
+	/// Use one of the `interpret_signed` associated functions to resolve this.
+	Signed(u64),
+	/// A byte string.
+	ByteString(Cow<'a, [u8]>),
+	/// The start of a byte string whose length is unknown.
+	///
+	/// After this event come a series of `ByteString` events, followed by a `Break`.
+	/// To get the true value of the byte string, concatenate the `ByteString` events together.
+	UnknownLengthByteString,
+	/// A text string.
+	TextString(Cow<'a, str>),
+	/// The start of a text string whose length is unknown.
+	///
+	/// After this event come a series of `TextString` events, followed by a `Break`.
+	/// To get the true value of the text string, concatenate the `TextString` events together.
+	UnknownLengthTextString,
+	/// The start of an array with a known length.
+	Array(u64),
+	/// The start of an array whose length is unknown.
+	///
+	/// After this event come a series of events representing the items in the array.
+	/// The array ends at the matching `Break` event.
+	UnknownLengthArray,
+	/// The start of a map with a known length.
+	///
+	/// Note that the actual number of sub-items is _twice_ the length given.
+	/// The first in each pair is a key, and the second is the value.
+	Map(u64),
+	/// The start of a map with an unknown length.
+	UnknownLengthMap,
+	/// Additional type information for the next CBOR item.
+	Tag(u64),
+	/// A CBOR simple value.
+	///
+	/// Most notably, simple values 20, 21, 22, and 23 represent false, true, null, and undefined, respectively.
+	Simple(u8),
+	/// A half-precision floating-point number.
+	///
+	/// Floats keep the width they were encoded with, and the exact bits, including any NaN payload.
+	/// Use [`Event::as_f64`] to get the value no matter the width,
+	/// or [`Event::create_float`] to pick the narrowest width for a value.
+	Float16(f16),
+	/// A single-precision floating-point number.
+	Float32(f32),
+	/// A double-precision floating-point number.
+	Float64(f64),
+	/// The end of an unknown-length item.
+	Break,
+}
+
+impl Event<'_> {
+	/// Convert this [`Event`] to an owned value.
+	pub fn into_owned(self) -> Event<'static> {
+		match self {
+			Self::Unsigned(n) => Event::Unsigned(n),
+			Self::Signed(n) => Event::Signed(n),
+			Self::ByteString(b) => Event::ByteString(Cow::Owned(b.into_owned())),
+			Self::UnknownLengthByteString => Event::UnknownLengthByteString,
+			Self::TextString(t) => Event::TextString(Cow::Owned(t.into_owned())),
+			Self::UnknownLengthTextString => Event::UnknownLengthTextString,
+			Self::Array(l) => Event::Array(l),
+			Self::UnknownLengthArray => Event::UnknownLengthArray,
+			Self::Map(l) => Event::Map(l),
+			Self::UnknownLengthMap => Event::UnknownLengthMap,
+			Self::Tag(t) => Event::Tag(t),
+			Self::Simple(s) => Event::Simple(s),
+			Self::Float16(f) => Event::Float16(f),
+			Self::Float32(f) => Event::Float32(f),
+			Self::Float64(f) => Event::Float64(f),
+			Self::Break => Event::Break,
+		}
+	}
+
+	/// Get the value of a float, of any width, as an [`f64`].
+	///
+	/// Every f16 and f32 can be widened to an f64 exactly, and this keeps NaN payloads intact too.
+	/// If this isn't a float, this returns [`None`].
+	pub fn as_f64(&self) -> Option<f64> {
+		match *self {
+			Self::Float16(f) => Some(f16_to_f64(f)),
+			Self::Float32(f) => Some(f32_to_f64(f)),
+			Self::Float64(f) => Some(f),
+			_ => None,
+		}
+	}
+
+	/// Create a float event of the narrowest width that holds the value exactly.
+	///
+	/// This is the width that [preferred serialization](https://www.rfc-editor.org/rfc/rfc8949.html#section-4.1) calls for.
+	/// NaN payloads are kept exactly, so a NaN is only narrowed if the bits that would be cut off are all zero.
+	pub fn create_float(val: f64) -> Event<'static> {
+		if let Some(f) = f64_to_f16(val) {
+			Event::Float16(f)
+		} else if let Some(f) = f64_to_f32(val) {
+			Event::Float32(f)
+		} else {
+			Event::Float64(val)
+		}
+	}
+
+	/// Interpret a [`Event::Signed`] value.
+	///
+	/// # Overflow behavior
+	///
+	/// On overflow, this function will panic if overflow checks are enabled (default in debug mode)
+	/// and wrap if overflow checks are disabled (default in release mode).
+	pub fn interpret_signed(val: u64) -> i64 {
+		-1 - (val as i64)
+	}
+
+	/// Interpret a [`Event::Signed`] value.
+	///
+	/// # Overflow behavior
+	///
+	/// On overflow, this function will return [`None`].
+	pub fn interpret_signed_checked(val: u64) -> Option<i64> {
+		match val {
+			n if n <= i64::MAX as u64 => Some(-1 - (n as i64)),
+			_ => None,
+		}
+	}
+
+	/// Interpret a [`Event::Signed`] value.
+	///
+	/// # Overflow behavior
+	///
+	/// This function does not overflow, because it returns an [`i128`].
+	pub fn interpret_signed_wide(val: u64) -> i128 {
+		-1 - (val as i128)
+	}
+
+	/// Create a [`Event::Signed`] or [`Event::Unsigned`] value.
+	pub fn create_signed(val: i64) -> Event<'static> {
+		if val.is_negative() {
+			Event::Signed(val.abs_diff(-1))
+		} else {
+			Event::Unsigned(val as _)
+		}
+	}
+
+	/// Create a [`Event::Signed`] or [`Event::Unsigned`] value.
+	///
+	/// Because this takes an [`i128`], it can express all the numbers CBOR can encode.
+	/// However, some [`i128`]s cannot be encoded in basic CBOR integers.
+	/// In this case, it will return [`None`].
+	pub fn create_signed_wide(val: i128) -> Option<Event<'static>> {
+		if val.is_negative() {
+			match val.abs_diff(-1).try_into() {
+				Ok(val) => Some(Event::Signed(val)),
+				Err(_) => None,
+			}
+		} else {
+			Some(Event::Unsigned(val as _))
+		}
+	}
+}
+
+/// How the argument in the header of an event is encoded.
+///
+/// CBOR allows most arguments to be encoded in more than one way,
+/// for instance, `1` can be `0x01`, `0x18 0x01`, `0x19 0x00 0x01` and so on.
+/// This records which was used, so that it can be used again; see [`Decoder::next_event_detailed`].
+/// For floats, this is the width of the float itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ArgumentWidth {
+	/// The argument is in the initial byte.
+	Immediate,
+	/// The argument is in the following byte.
+	U8,
+	/// The argument is in the following 2 bytes, or the float is an f16.
+	U16,
+	/// The argument is in the following 4 bytes, or the float is an f32.
+	U32,
+	/// The argument is in the following 8 bytes, or the float is an f64.
+	U64,
+	/// There is no argument, because the event is an unknown-length item or a break.
+	Indefinite,
+}
+
+impl ArgumentWidth {
+	/// Get the width that a header's additional information (its low 5 bits) indicates.
+	fn from_additional(additional: u8) -> Self {
+		match additional {
+			0..=23 => ArgumentWidth::Immediate,
+			24 => ArgumentWidth::U8,
+			25 => ArgumentWidth::U16,
+			26 => ArgumentWidth::U32,
+			27 => ArgumentWidth::U64,
+			_ => ArgumentWidth::Indefinite,
+		}
+	}
+
+	/// Get the additional information for an argument encoded with this width.
+	fn additional(self, argument: u64) -> u8 {
+		match self {
+			ArgumentWidth::Immediate => argument as u8,
+			ArgumentWidth::U8 => 24,
+			ArgumentWidth::U16 => 25,
+			ArgumentWidth::U32 => 26,
+			ArgumentWidth::U64 => 27,
+			ArgumentWidth::Indefinite => 31,
+		}
+	}
+
+	/// Get how many bytes follow the initial byte.
+	fn len(self) -> usize {
+		match self {
+			ArgumentWidth::Immediate | ArgumentWidth::Indefinite => 0,
+			ArgumentWidth::U8 => 1,
+			ArgumentWidth::U16 => 2,
+			ArgumentWidth::U32 => 4,
+			ArgumentWidth::U64 => 8,
+		}
+	}
+
+	/// Get the shortest width an argument fits in.
+	pub fn shortest(argument: u64) -> Self {
+		match argument {
+			0..=23 => ArgumentWidth::Immediate,
+			24..=0xFF => ArgumentWidth::U8,
+			0x100..=0xFFFF => ArgumentWidth::U16,
+			0x1_0000..=0xFFFF_FFFF => ArgumentWidth::U32,
+			_ => ArgumentWidth::U64,
+		}
+	}
+
+	/// Check whether an argument fits in this width.
+	pub fn fits(self, argument: u64) -> bool {
+		match self {
+			ArgumentWidth::Immediate => argument <= 23,
+			ArgumentWidth::Indefinite => false,
+			_ => argument >> (self.len() * 8 - 1) >> 1 == 0,
+		}
+	}
+}
+
+/// A set of map keys, for finding duplicates while decoding a map with a [`Decoder`].
+///
+/// Keys are compared by [data-model equality](`tree::Item::data_model_eq`),
+/// the same way the tree decoders do for their [`DuplicateKeyPolicy`](`tree::DuplicateKeyPolicy`).
+/// Use one set for each map, and either decode each key yourself and [`insert`](`Self::insert`) it,
+/// or pass the output of [`Decoder::read_raw_item`] to [`insert_encoded`](`Self::insert_encoded`).
+#[derive(Debug, Clone, Default)]
+pub struct KeySet {
+	keys: Vec<tree::Item>,
+	index: tree::KeyIndex,
+}
+
+impl KeySet {
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	/// Add a key to the set.
+	///
+	/// Returns `true` if it wasn't already there, or `false` if it's a duplicate.
+	pub fn insert(&mut self, key: tree::Item) -> bool {
+		match self
+			.index
+			.find_or_add(self.keys.len(), |index| &self.keys[index], &key)
+		{
+			Some(_) => false,
+			None => {
+				self.keys.push(key);
+				true
+			}
+		}
+	}
+
+	/// Decode an encoded key and add it to the set.
+	///
+	/// Offsets in any errors are relative to the start of `key`.
+	pub fn insert_encoded(&mut self, key: &[u8]) -> Result<bool, DecodeError> {
+		Ok(self.insert(tree::Decoder::new().decode_slice(key)?))
+	}
+
+	/// Returns the number of distinct keys in the set.
+	pub fn len(&self) -> usize {
+		self.keys.len()
+	}
+
+	/// Returns `true` if no keys have been added yet.
+	pub fn is_empty(&self) -> bool {
+		self.keys.is_empty()
+	}
+}
+
+/// A streaming encoder for the CBOR basic data model.
+///
+/// Each event is checked against the containers it's inside before anything is written,
+/// so invalid events are rejected with an error saying where they would have gone, and the encoder can carry on.
+/// An encoder made with [`Self::new`] writes exactly one data item: any events after that item is complete are rejected with [`EncodeError::Excess`].
+/// To write any number of items one after another, making a CBOR Sequence, use [`Self::new_sequence`] instead.
+#[derive(Debug, Clone)]
+pub struct Encoder<T: Write> {
+	dest: T,
+	/// Each open container comes with how many items have been fed into it so far.
+	/// For maps, this counts keys and values separately.
+	open: Vec<(OpenContainer, u64)>,
+	/// Whether more than one top-level item may be written.
+	sequence: bool,
+	/// Whether a whole data item has been written.
+	complete: bool,
+}
+
+/// A container that an [`Encoder`] is partway through.
+#[derive(Debug, Clone, Copy)]
+enum OpenContainer {
+	/// An array, and its length, if it's known.
+	Array(Option<u64>),
+	/// A map, and its length in pairs, if it's known.
+	Map(Option<u64>),
+	/// A tag, which contains exactly one item.
+	Tag(u64),
+	/// An unknown-length string, and its major type.
+	Chunks(u8),
+}
+
+impl<T: Write> Encoder<T> {
+	pub fn new(dest: T) -> Self {
+		Encoder {
+			dest,
+			open: Vec::new(),
+			sequence: false,
+			complete: false,
+		}
+	}
+
+	/// Make an encoder for a CBOR Sequence ([RFC 8742](https://www.rfc-editor.org/rfc/rfc8742.html)),
+	/// which accepts any number of top-level items, one after another.
+	pub fn new_sequence(dest: T) -> Self {
+		Encoder {
+			sequence: true,
+			..Self::new(dest)
+		}
+	}
+
+	pub fn feed_event(&mut self, event: Event) -> Result<(), EncodeError> {
+		self.encode_event(event, None)
+	}
+
+	/// Feed an event to the encoder, encoding its argument with the given width.
+	///
+	/// This is the counterpart to [`Decoder::next_event_detailed`].
+	/// It fails with [`EncodeError::InvalidWidth`] if the argument doesn't fit in the width,
+	/// or if the event is a float of a different width.
+	pub fn feed_event_detailed(
+		&mut self,
+		event: Event,
+		width: ArgumentWidth,
+	) -> Result<(), EncodeError> {
+		self.encode_event(event, Some(width))
+	}
+
+	/// Work out where the next event would go.
+	fn context(&self) -> EventContext {
+		match self.open.last() {
+			None => EventContext::TopLevel,
+			Some(&(OpenContainer::Array(len), count)) => EventContext::Array { len, index: count },
+			Some(&(OpenContainer::Map(len), count)) if count % 2 == 0 => EventContext::MapKey {
+				len,
+				index: count / 2,
+			},
+			Some(&(OpenContainer::Map(len), count)) => EventContext::MapValue {
+				len,
+				index: count / 2,
+			},
+			Some(&(OpenContainer::Tag(tag), _)) => EventContext::Tagged(tag),
+			Some(&(OpenContainer::Chunks(major), count)) => EventContext::Chunk {
+				major,
+				index: count,
+			},
+		}
+	}
+
+	/// Encode an event, using the shortest width if none is given.
+	fn encode_event(
+		&mut self,
+		event: Event,
+		width: Option<ArgumentWidth>,
+	) -> Result<(), EncodeError> {
+		if self.complete {
+			return Err(EncodeError::Excess);
+		}
+		let top = self.open.last().copied();
+		match (top, &event) {
+			(Some((OpenContainer::Chunks(2), _)), Event::ByteString(_))
+			| (Some((OpenContainer::Chunks(3), _)), Event::TextString(_)) => (),
+			(Some((OpenContainer::Chunks(_), _)), Event::Break) => (),
+			(Some((OpenContainer::Chunks(_), _)), _) => {
+				return Err(EncodeError::InvalidChunk(self.context()))
+			}
+			(Some((OpenContainer::Array(None), _)), Event::Break) => (),
+			// A break can't come between a map key and its value.
+			(Some((OpenContainer::Map(None), count)), Event::Break) if count % 2 == 0 => (),
+			(_, Event::Break) => return Err(EncodeError::InvalidBreak(self.context())),
+			_ => (),
+		}
+		let (header, header_len) = encode_header(&event, width)?;
+
+		// The event is known to be valid now, so it's safe to start writing it.
+		// Like the decoder, this closes a known-length container as soon as its last item starts.
+		if let Event::Break = event {
+			self.open.pop();
+		} else if let Some((container, count)) = self.open.last_mut() {
+			*count += 1;
+			let full = match *container {
+				OpenContainer::Array(Some(len)) => *count == len,
+				OpenContainer::Map(Some(len)) => *count / 2 == len,
+				OpenContainer::Tag(_) => true,
+				_ => false,
+			};
+			if full {
+				self.open.pop();
+			}
+		}
+
+		self.dest.write_all(&header[..header_len])?;
+		let opened = match event {
+			Event::ByteString(bytes) => {
+				self.dest.write_all(&bytes)?;
+				None
+			}
+			Event::TextString(text) => {
+				self.dest.write_all(text.as_bytes())?;
+				None
+			}
+			Event::UnknownLengthByteString => Some(OpenContainer::Chunks(2)),
+			Event::UnknownLengthTextString => Some(OpenContainer::Chunks(3)),
+			Event::Array(len) if len > 0 => Some(OpenContainer::Array(Some(len))),
+			Event::UnknownLengthArray => Some(OpenContainer::Array(None)),
+			Event::Map(len) if len > 0 => Some(OpenContainer::Map(Some(len))),
+			Event::UnknownLengthMap => Some(OpenContainer::Map(None)),
+			Event::Tag(tag) => Some(OpenContainer::Tag(tag)),
+			_ => None,
+		};
+		match opened {
+			Some(container) => self.open.push((container, 0)),
+			None => self.complete = !self.sequence && self.open.is_empty(),
+		}
+
+		Ok(())
+	}
+
+	pub fn ready_to_finish(&self) -> bool {
+		self.open.is_empty()
+	}
+
+	/// End the encoding.
+	///
+	/// This is [checked](`Self::ready_to_finish`) and will return [`EncodeError::Insufficient`] if the CBOR is incomplete.
+	/// Otherwise, it flushes the writer and returns it.
+	/// If you've performed the check already, or want the writer back regardless, try [`Self::force_finish`].
+	pub fn finish(mut self) -> Result<T, EncodeError> {
+		if self.ready_to_finish() {
+			self.dest.flush()?;
+			Ok(self.dest)
+		} else {
+			Err(EncodeError::Insufficient)
+		}
+	}
+
+	/// End the encoding, without checking whether the encoder is finished or not.
+	///
+	/// This returns the writer as it is, without flushing it.
+	pub fn force_finish(self) -> T {
+		self.dest
+	}
+}
+
+/// Encode the header of an event, including the whole of a float, using the shortest width if none is given.
+///
+/// Returns the buffer and how much of it is used.
+fn encode_header(
+	event: &Event,
+	width: Option<ArgumentWidth>,
+) -> Result<([u8; 9], usize), EncodeError> {
+	let (major, argument) = match *event {
+		Event::Unsigned(n) => (0, Some(n)),
+		Event::Signed(n) => (1, Some(n)),
+		Event::ByteString(ref bytes) => (2, Some(bytes.len() as u64)),
+		Event::UnknownLengthByteString => (2, None),
+		Event::TextString(ref text) => (3, Some(text.len() as u64)),
+		Event::UnknownLengthTextString => (3, None),
+		Event::Array(n) => (4, Some(n)),
+		Event::UnknownLengthArray => (4, None),
+		Event::Map(n) => (5, Some(n)),
+		Event::UnknownLengthMap => (5, None),
+		Event::Tag(n) => (6, Some(n)),
+		Event::Float16(_) | Event::Float32(_) | Event::Float64(_) => {
+			return encode_float(event, width)
+		}
+		Event::Simple(n) => (7, Some(n as u64)),
+		Event::Break => (7, None),
+	};
+	let width = match (argument, width) {
+		(None, None | Some(ArgumentWidth::Indefinite)) => ArgumentWidth::Indefinite,
+		(Some(n), None) => ArgumentWidth::shortest(n),
+		(Some(n), Some(width)) if width.fits(n) => width,
+		_ => return Err(EncodeError::InvalidWidth),
+	};
+	// The CBOR spec requires that simple values 0-23 be encoded as a single byte,
+	// and simple values 32-255 be encoded as two bytes.
+	// Why this is required when overlong arguments are otherwise legal is a mystery to me,
+	// but in any case, the shortest encoding is always the right one.
+	// Any wider, and it would be the prefix to a float.
+	// That leaves no valid encoding at all for 24-31.
+	if let Event::Simple(n) = *event {
+		if (24..32).contains(&n) {
+			return Err(EncodeError::InvalidSimple(n));
+		}
+		if width != ArgumentWidth::shortest(n as _) {
+			return Err(EncodeError::InvalidWidth);
+		}
+	}
+
+	let mut header = [0u8; 9];
+	header[0] = major << 5 | width.additional(argument.unwrap_or(0));
+	let len = 1 + width.len();
+	let argument = argument.unwrap_or(0).to_be_bytes();
+	header[1..len].copy_from_slice(&argument[8 - width.len()..]);
+	Ok((header, len))
+}
+
+/// Encode a float event, which always has the width it was created with.
+fn encode_float(
+	event: &Event,
+	width: Option<ArgumentWidth>,
+) -> Result<([u8; 9], usize), EncodeError> {
+	let mut header = [0u8; 9];
+	let own_width = match *event {
+		Event::Float16(f) => {
+			header[1..3].copy_from_slice(&f.to_be_bytes());
+			ArgumentWidth::U16
+		}
+		Event::Float32(f) => {
+			header[1..5].copy_from_slice(&f.to_be_bytes());
+			ArgumentWidth::U32
+		}
+		Event::Float64(f) => {
+			header[1..9].copy_from_slice(&f.to_be_bytes());
+			ArgumentWidth::U64
+		}
+		_ => unreachable!(),
+	};
+	if matches!(width, Some(width) if width != own_width) {
+		return Err(EncodeError::InvalidWidth);
+	}
+	header[0] = 0xE0 | own_width.additional(0);
+	Ok((header, 1 + own_width.len()))
+}
+
+#[cfg(feature = "async")]
+async fn async_read(
+	reader: &mut (impl AsyncRead + Unpin),
+	buf: &mut [u8],
+) -> std::io::Result<usize> {
+	loop {
+		match poll_fn(|cx| Pin::new(&mut *reader).poll_read(cx, buf)).await {
+			Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+			other => return other,
+		}
+	}
+}
+
+#[cfg(feature = "async")]
+async fn async_write_all(
+	writer: &mut (impl AsyncWrite + Unpin),
+	mut buf: &[u8],
+) -> std::io::Result<()> {
+	while !buf.is_empty() {
+		match poll_fn(|cx| Pin::new(&mut *writer).poll_write(cx, buf)).await {
+			Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+			Ok(n) => buf = &buf[n..],
+			Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(())
+}
+
+#[cfg(feature = "async")]
+async fn async_flush(writer: &mut (impl AsyncWrite + Unpin)) -> std::io::Result<()> {
+	poll_fn(|cx| Pin::new(&mut *writer).poll_flush(cx)).await
+}
+
+#[cfg(feature = "async")]
+include!("../extended/forward_config_accessors.in.rs");
+
+/// An asynchronous streaming decoder for the CBOR basic data model.
+///
+/// This works just like a [`Decoder`] reading from a [`Read`]er,
+/// except that it reads from an [`AsyncRead`]er instead.
+/// It never reads past the end of the data it decodes.
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct AsyncDecoder<T: AsyncRead + Unpin> {
+	reader: T,
+	decoder: Decoder<PushSource>,
+}
+
+#[cfg(feature = "async")]
+impl<T: AsyncRead + Unpin> AsyncDecoder<T> {
+	pub fn new(source: T) -> Self {
+		AsyncDecoder {
+			reader: source,
+			decoder: Decoder::new_push(),
+		}
+	}
+
+	forward_config_accessors!(
+		decoder: DecodeLimits,
+		limits,
+		limits_mut,
+		set_limits,
+		"the limits on what the decoder will accept."
+	);
+
+	forward_config_accessors!(
+		decoder: bool,
+		require_preferred,
+		require_preferred_mut,
+		set_require_preferred,
+		"whether the decoder only accepts preferred serialization.",
+		"See [the synchronous counterpart](`Decoder::set_require_preferred`) for details."
+	);
+
+	forward_config_accessors!(
+		decoder: bool,
+		require_definite,
+		require_definite_mut,
+		set_require_definite,
+		"whether the decoder rejects indefinite-length items.",
+		"See [the synchronous counterpart](`Decoder::set_require_definite`) for details."
+	);
+
+	/// Get how many bytes of input have been decoded so far.
+	///
+	/// See [the synchronous counterpart](`Decoder::position`) for details.
+	pub fn position(&self) -> u64 {
+		self.decoder.position()
+	}
+
+	/// Pull an event from the decoder.
+	///
+	/// Because the input arrives piece by piece, the contents of byte- and text-strings are always copied.
+	pub async fn next_event(&mut self) -> Result<Event<'static>, DecodeError> {
+		let mut chunk = [0u8; 4096];
+		loop {
+			match self.decoder.try_next_event()? {
+				TryNextEventOutcome::GotEvent(event) => return Ok(event),
+				TryNextEventOutcome::Needs(n) => {
+					// Reading no more than is needed keeps us from overrunning the end of the CBOR.
+					let len = n.get().min(chunk.len());
+					let read = async_read(&mut self.reader, &mut chunk[..len]).await;
+					match read.map_err(|e| DecodeError::new(e.into(), self.decoder.position()))? {
+						0 => {
+							return Err(DecodeError::new(
+								DecodeErrorKind::Insufficient,
+								self.decoder.position(),
+							))
+						}
+						got => self.decoder.feed(&chunk[..got]),
+					}
+				}
+			}
+		}
+	}
+
+	/// Check whether it is possible to end the decoding now.
+	///
+	/// See [the synchronous counterpart](`Decoder::ready_to_finish`) for details.
+	pub fn ready_to_finish(&self) -> bool {
+		self.decoder.ready_to_finish()
+	}
+
+	/// End the decoding.
+	///
+	/// This is [checked](`Self::ready_to_finish`) and will return [`DecodeErrorKind::Insufficient`] if the CBOR is incomplete.
+	/// If you've performed the check already, try [`Self::force_finish`].
+	pub fn finish(self) -> Result<T, DecodeError> {
+		if self.ready_to_finish() {
+			Ok(self.reader)
+		} else {
+			Err(DecodeError::new(
+				DecodeErrorKind::Insufficient,
+				self.decoder.position(),
+			))
+		}
+	}
+
+	/// End the decoding, without checking whether the decoder is finished or not.
+	///
+	/// This does not return the original reader,
+	/// but the reader returned behaves as if it were the original reader,
+	/// just like with [the synchronous counterpart](`Decoder::force_finish`).
+	pub fn force_finish(self) -> impl AsyncRead + Unpin {
+		Unread {
+			buffer: self.decoder.force_finish(),
+			pos: 0,
+			reader: self.reader,
+		}
+	}
+}
+
+/// A reader that gives back input that was read but not decoded, and then carries on with the reader it came from.
+#[cfg(feature = "async")]
+#[derive(Debug)]
+struct Unread<T> {
+	buffer: Vec<u8>,
+	pos: usize,
+	reader: T,
+}
+
+#[cfg(feature = "async")]
+impl<T: AsyncRead + Unpin> AsyncRead for Unread<T> {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut std::task::Context<'_>,
+		buf: &mut [u8],
+	) -> Poll<std::io::Result<usize>> {
+		let this = &mut *self;
+		let rest = &this.buffer[this.pos..];
+		if rest.is_empty() {
+			return Pin::new(&mut this.reader).poll_read(cx, buf);
+		}
+		let len = rest.len().min(buf.len());
+		buf[..len].copy_from_slice(&rest[..len]);
+		this.pos += len;
+		Poll::Ready(Ok(len))
+	}
+}
+
+/// An asynchronous streaming encoder for the CBOR basic data model.
+///
+/// This works just like an [`Encoder`], except that it writes to an [`AsyncWrite`]r instead.
+///
+/// Each event is written out in full before [`Self::feed_event`] returns.
+/// If the future returned by `feed_event` is dropped before then,
+/// the output is left in an unknown state and the encoder should not be used any more.
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct AsyncEncoder<T: AsyncWrite + Unpin> {
+	writer: T,
+	encoder: Encoder<Vec<u8>>,
+}
+
+#[cfg(feature = "async")]
+impl<T: AsyncWrite + Unpin> AsyncEncoder<T> {
+	pub fn new(dest: T) -> Self {
+		AsyncEncoder {
+			writer: dest,
+			encoder: Encoder::new(Vec::new()),
+		}
+	}
+
+	/// Make an encoder for a CBOR Sequence, like [`Encoder::new_sequence`].
+	pub fn new_sequence(dest: T) -> Self {
+		AsyncEncoder {
+			writer: dest,
+			encoder: Encoder::new_sequence(Vec::new()),
+		}
+	}
+
+	/// Feed an event to the encoder.
+	pub async fn feed_event(&mut self, event: Event<'_>) -> Result<(), EncodeError> {
+		let fed = self.encoder.feed_event(event);
+		let written = async_write_all(&mut self.writer, &self.encoder.dest).await;
+		self.encoder.dest.clear();
+		fed?;
+		Ok(written?)
+	}
+
+	pub fn ready_to_finish(&self) -> bool {
+		self.encoder.ready_to_finish()
+	}
+
+	/// End the encoding.
+	///
+	/// This is [checked](`Self::ready_to_finish`) and will return [`EncodeError::Insufficient`] if the CBOR is incomplete.
+	/// Otherwise, it flushes the writer and returns it.
+	pub async fn finish(mut self) -> Result<T, EncodeError> {
+		if self.ready_to_finish() {
+			async_flush(&mut self.writer).await?;
+			Ok(self.writer)
+		} else {
+			Err(EncodeError::Insufficient)
+		}
+	}
+
+	/// End the encoding, without checking whether the encoder is finished or not, and without flushing the writer.
+	///
+	/// Every event is written out as soon as it's fed, so there's nothing left to write.
+	pub fn force_finish(self) -> T {
+		self.writer
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+	use super::*;
+	use std::io::Cursor;
+
+	/// Generate some pseudo-random inputs for fuzz-style tests.
+	///
+	/// This uses a fixed seed, so failures are repeatable.
+	/// The bytes are biased towards ones that start long or nested items, so that the interesting paths get exercised.
+	pub(crate) fn fuzz_inputs(count: usize) -> impl Iterator<Item = Vec<u8>> {
+		const INTERESTING: &[u8] = &[
+			0x18, 0x19, 0x1A, 0x1B, 0x1F, 0x3B, 0x5B, 0x5F, 0x7B, 0x7F, 0x81, 0x9B, 0x9F, 0xA1,
+			0xBB, 0xBF, 0xC0, 0xC1, 0xDB, 0xF8, 0xF9, 0xFB, 0xFF,
+		];
+		let mut state = 0x2545F4914F6CDD1Du64;
+		let mut next = move || {
+			state ^= state << 13;
+			state ^= state >> 7;
+			state ^= state << 17;
+			state
+		};
+		(0..count).map(move |_| {
+			let len = next() % 48;
+			(0..len)
+				.map(|_| match next() % 4 {
+					0 => INTERESTING[next() as usize % INTERESTING.len()],
+					_ => next() as u8,
+				})
+				.collect()
+		})
+	}
+
+	/// Run every kind of decoder over some input until it stops.
+	///
+	/// The point is only that none of them panic, so errors are ignored.
+	fn decode_everything(input: &[u8]) {
+		let mut decoder = Decoder::new(Cursor::new(input));
+		while decoder.next_event().is_ok() {}
+		let mut decoder = Decoder::from_slice(input);
+		while decoder.next_event().is_ok() {}
+		let mut decoder = Decoder::from_buf_read(std::io::BufReader::with_capacity(3, input));
+		while decoder.next_event().is_ok() {}
+		let mut decoder = Decoder::new_push();
+		for byte in input {
+			decoder.feed(&[*byte]);
+			while let Ok(TryNextEventOutcome::GotEvent(_)) = decoder.try_next_event() {}
+		}
+	}
+
+	macro_rules! decode_test {
+		(match $decoder:ident: $in:expr => $out:pat if $cond:expr) => {
+			match $decoder.next_event().map_err(DecodeError::into_kind) {
+				$out if $cond => (),
+				other => panic!(concat!("{:X?} -> {:X?} instead of ", stringify!($out), " if ", stringify!($cond)), $in, other),
+			}
+		};
+		(match $decoder:ident: $in:expr => $out:pat) => {
+			decode_test!(match $decoder: $in => $out if true);
+		};
+		(match $decoder:ident: $out:pat if $cond:expr) => {
+			match $decoder.next_event().map_err(DecodeError::into_kind) {
+				$out if $cond => (),
+				other => panic!("? -> {:X?}", other),
+			}
+		};
+		(match $decoder:ident: $out:pat) => {
+			decode_test!(match $decoder: $out if true);
+		};
+		($in:expr => $out:pat if $cond:expr) => {
+			let mut decoder = Decoder::new(Cursor::new($in));
+			decode_test!(match decoder: $in => $out if $cond);
+			decoder.finish().unwrap();
+		};
+		($in:expr => $out:pat) => {
+			decode_test!($in => $out if true);
+		};
+		(small $in:expr) => {
+			let mut decoder = Decoder::new(Cursor::new($in));
+			decode_test!(match decoder: $in => Err(DecodeErrorKind::Insufficient));
+			assert!(!decoder.ready_to_finish());
+		};
+	}
+
+	macro_rules! encode_test {
+		($($in:expr),+ => $out:expr, check finish if $cond:expr, expecting $expect:expr; $event:ident) => {
+			let mut buf = Vec::new();
+			let mut encoder = Encoder::new(Cursor::new(&mut buf));
+			for (idx, $event) in [$($in),+].into_iter().enumerate() {
+				let check_finish = $cond;
+				let expected = $expect;
+				encoder.feed_event($event).unwrap();
+				if check_finish {
+					assert_eq!(encoder.ready_to_finish(), expected, "readiness to finish was not as expected after event #{}", idx);
+				}
+			}
+			encoder.finish().unwrap();
+			assert_eq!(buf, $out);
+		};
+		($($in:expr),+ => $out:expr, check finish expecting $expect:expr; $event:ident) => {
+			encode_test!($($in),+ => $out, check finish if true, expecting $expect; $event);
+		};
+		($($in:expr),+ => $out:expr, check finish if $cond:expr; $event:ident) => {
+			encode_test!($($in),+ => $out, check finish if $cond, expecting true; $event);
+		};
+		($($in:expr),+ => $out:expr) => {
+			encode_test!($($in),+ => $out, check finish if false; event);
+		};
+		($($in:expr),+ => $out:expr, check finish) => {
+			encode_test!($($in),+ => $out, check finish if true; event);
+		};
+	}
+
+	#[test]
+	fn decode_uint_tiny() {
+		for i1 in 0..=0x17u8 {
+			decode_test!([i1] => Ok(Event::Unsigned(i2)) if i2 == i1 as _);
 		}
-		if pop_pending {
-			self.pending.pop();
+	}
+
+	#[test]
+	fn encode_uint_tiny() {
+		for i in 0..=0x17u8 {
+			encode_test!(Event::Unsigned(i as _) => [i]);
+		}
+	}
+
+	#[test]
+	fn decode_uint_8bit() {
+		decode_test!([0x18u8, 0x01] => Ok(Event::Unsigned(0x01)));
+	}
+
+	#[test]
+	fn decode_uint_8bit_bounds() {
+		decode_test!(small b"\x18");
+	}
+
+	#[test]
+	fn encode_uint_8bit() {
+		encode_test!(Event::Unsigned(0x3F) => [0x18, 0x3F]);
+	}
+
+	#[test]
+	fn decode_uint_16bit() {
+		decode_test!([0x19u8, 0x01, 0x02] => Ok(Event::Unsigned(0x0102)));
+	}
+
+	#[test]
+	fn decode_uint_16bit_bounds() {
+		decode_test!(small b"\x19\x00");
+	}
+
+	#[test]
+	fn encode_uint_16bit() {
+		encode_test!(Event::Unsigned(0x1234) => [0x19, 0x12, 0x34]);
+	}
+
+	#[test]
+	fn decode_uint_32bit() {
+		decode_test!([0x1Au8, 0x01, 0x02, 0x03, 0x04] => Ok(Event::Unsigned(0x01020304)));
+	}
+
+	#[test]
+	fn decode_uint_32bit_bounds() {
+		decode_test!(small b"\x1A\x00\x00\x00");
+	}
+
+	#[test]
+	fn encode_uint_32bit() {
+		encode_test!(Event::Unsigned(0x12345678) => [0x1A, 0x12, 0x34, 0x56, 0x78]);
+	}
+
+	#[test]
+	fn decode_uint_64bit() {
+		decode_test!([0x1Bu8, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08] => Ok(Event::Unsigned(0x0102030405060708)));
+	}
+
+	#[test]
+	fn decode_uint_64bit_bounds() {
+		decode_test!(small b"\x1B\x00\x00\x00\x00\x00\x00\x00");
+	}
+
+	#[test]
+	fn encode_uint_64bit() {
+		encode_test!(Event::Unsigned(0x123456789ABCDEF0) => [0x1B, 0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0]);
+	}
+
+	#[test]
+	fn decode_negint() {
+		decode_test!([0x20u8] => Ok(Event::Signed(0)));
+		decode_test!([0x37u8] => Ok(Event::Signed(0x17)));
+		decode_test!([0x38, 0x01] => Ok(Event::Signed(0x01)));
+		decode_test!([0x39, 0x01, 0x02] => Ok(Event::Signed(0x0102)));
+		decode_test!([0x3A, 0x01, 0x02, 0x03, 0x04] => Ok(Event::Signed(0x01020304)));
+		decode_test!([0x3B, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08] => Ok(Event::Signed(0x0102030405060708)));
+		decode_test!(small b"\x3B\x00\x00\x00\x00\x00\x00\x00");
+	}
+
+	#[test]
+	fn encode_negint() {
+		encode_test!(Event::Signed(0) => [0x20]);
+		encode_test!(Event::Signed(0x17) => [0x37]);
+		encode_test!(Event::Signed(0xAA) => [0x38, 0xAA]);
+		encode_test!(Event::Signed(0x0102) => [0x39, 0x01, 0x02]);
+		encode_test!(Event::Signed(0x01020304) => [0x3A, 0x01, 0x02, 0x03, 0x04]);
+		encode_test!(Event::Signed(0x010203040506) => [0x3B, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+	}
+
+	#[test]
+	fn interpret_signed() {
+		assert_eq!(Event::interpret_signed(0), -1);
+		assert_eq!(Event::interpret_signed_checked(0), Some(-1));
+		assert_eq!(Event::interpret_signed_checked(u64::MAX), None);
+		assert_eq!(
+			Event::interpret_signed_checked(i64::MAX as u64),
+			Some(i64::MIN)
+		);
+		assert_eq!(Event::interpret_signed_wide(0), -1);
+		assert_eq!(
+			Event::interpret_signed_wide(u64::MAX),
+			-1 - u64::MAX as i128
+		);
+	}
+
+	#[test]
+	fn create_signed() {
+		assert!(matches!(Event::create_signed(0), Event::Unsigned(0)));
+		assert!(matches!(Event::create_signed(22), Event::Unsigned(22)));
+		assert!(matches!(Event::create_signed(-1), Event::Signed(0)));
+		assert!(matches!(Event::create_signed(-22), Event::Signed(21)));
+	}
+
+	#[test]
+	fn decode_bytes() {
+		decode_test!([0x40] => Ok(Event::ByteString(Cow::Owned(x))) if x == b"");
+		decode_test!(b"\x45Hello" => Ok(Event::ByteString(Cow::Owned(x))) if x == b"Hello");
+		decode_test!(b"\x58\x04Halo" => Ok(Event::ByteString(Cow::Owned(x))) if x == b"Halo");
+		decode_test!(b"\x59\x00\x07Goodbye" => Ok(Event::ByteString(Cow::Owned(x))) if x == b"Goodbye");
+		decode_test!(b"\x5A\x00\x00\x00\x0DLong message!" => Ok(Event::ByteString(Cow::Owned(x))) if x == b"Long message!");
+		decode_test!(b"\x5B\x00\x00\x00\x00\x00\x00\x00\x01?" => Ok(Event::ByteString(Cow::Owned(x))) if x == b"?");
+	}
+
+	#[test]
+	fn encode_bytes() {
+		encode_test!(Event::ByteString(Cow::Borrowed(b"")) => b"\x40");
+		encode_test!(Event::ByteString(Cow::Borrowed(b"abcd")) => b"\x44abcd");
+
+		macro_rules! test {
+			($size:expr, $prefix:expr) => {
+				let size: usize = $size;
+				let prefix = $prefix;
+				let input = {
+					let mut it = Vec::with_capacity(size);
+					it.resize(size, 0x0Fu8);
+					it
+				};
+				let mut output = Vec::with_capacity(size + prefix.len());
+				let mut encoder = Encoder::new(Cursor::new(&mut output));
+				encoder
+					.feed_event(Event::ByteString(Cow::Borrowed(&input)))
+					.unwrap();
+				assert_eq!(output[..prefix.len()], prefix);
+				assert_eq!(output[prefix.len()..], input);
+			};
+		}
+
+		test!(0x30, [0x58, 0x30]);
+		test!(0x02FA, [0x59, 0x02, 0xFA]);
+		test!(0x010000, [0x5A, 0x00, 0x01, 0x00, 0x00]);
+		// Allocates about 8 GiB of memory! And iterates 4Gi times! Wow!
+		test!(
+			2usize.pow(32),
+			[0x5B, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]
+		);
+	}
+
+	#[test]
+	fn decode_bytes_segmented() {
+		let mut decoder = Decoder::new(Cursor::new(b"\x5F\x44abcd\x43efg\xFF"));
+		decode_test!(match decoder: Ok(Event::UnknownLengthByteString));
+		assert!(!decoder.ready_to_finish());
+		decode_test!(match decoder: Ok(Event::ByteString(Cow::Owned(x))) if x == b"abcd");
+		assert!(!decoder.ready_to_finish());
+		decode_test!(match decoder: Ok(Event::ByteString(Cow::Owned(x))) if x == b"efg");
+		assert!(!decoder.ready_to_finish());
+		decode_test!(match decoder: Ok(Event::Break));
+		assert!(decoder.ready_to_finish());
+	}
+
+	#[test]
+	fn decode_bytes_segmented_small() {
+		let mut decoder = Decoder::new(Cursor::new(b"\x5F\x44abcd"));
+		decode_test!(match decoder: Ok(Event::UnknownLengthByteString));
+		decode_test!(match decoder: Ok(Event::ByteString(Cow::Owned(x))) if x == b"abcd");
+		decode_test!(match decoder: Err(DecodeErrorKind::Insufficient));
+		assert!(!decoder.ready_to_finish());
+	}
+
+	#[test]
+	fn encode_bytes_segmented() {
+		encode_test!(
+			Event::UnknownLengthByteString,
+			Event::ByteString(Cow::Borrowed(b"abcd")),
+			Event::ByteString(Cow::Borrowed(b"efg")),
+			Event::Break
+			=> b"\x5F\x44abcd\x43efg\xFF",
+			check finish expecting matches!(event, Event::Break); event
+		);
+	}
+
+	#[test]
+	fn decode_segmented_wrong() {
+		for (input, offset) in [
+			(&b"\x5F\x00"[..], 1),
+			(b"\x5F\x41a\x80", 3),
+			(b"\x5F\x5F\xFF\xFF", 1),
+			(b"\x5F\x61a\xFF", 1),
+			(b"\x7F\x41a\xFF", 1),
+			(b"\x7F\x7F\xFF\xFF", 1),
+			(b"\x7F\xC0\x61a\xFF", 1),
+		] {
+			let mut decoder = Decoder::from_slice(input);
+			let error = loop {
+				if let Err(e) = decoder.next_event() {
+					break e;
+				}
+			};
+			assert!(
+				matches!(
+					error.kind(),
+					DecodeErrorKind::Malformed(MalformedKind::InvalidChunk)
+				),
+				"{:X?} -> {:?}",
+				input,
+				error
+			);
+			assert_eq!(error.offset(), offset);
 		}
+	}
+
+	#[test]
+	fn encode_segmented_wrong() {
+		for (start, chunk) in [
+			(Event::UnknownLengthByteString, Event::Unsigned(0)),
+			(
+				Event::UnknownLengthByteString,
+				Event::TextString(Cow::Borrowed("a")),
+			),
+			(
+				Event::UnknownLengthByteString,
+				Event::UnknownLengthByteString,
+			),
+			(
+				Event::UnknownLengthTextString,
+				Event::ByteString(Cow::Borrowed(b"a")),
+			),
+			(Event::UnknownLengthTextString, Event::Array(0)),
+		] {
+			let mut encoder = Encoder::new(Vec::new());
+			encoder.feed_event(start).unwrap();
+			assert!(matches!(
+				encoder.feed_event(chunk),
+				Err(EncodeError::InvalidChunk(EventContext::Chunk {
+					index: 0,
+					..
+				}))
+			));
+			encoder.feed_event(Event::Break).unwrap();
+			assert!(encoder.ready_to_finish());
+		}
+	}
+
+	#[test]
+	fn encode_context() {
+		let mut encoder = Encoder::new(Vec::new());
+		encoder.feed_event(Event::Map(2)).unwrap();
+		encoder.feed_event(Event::Unsigned(0)).unwrap();
+		encoder.feed_event(Event::Unsigned(1)).unwrap();
+		encoder.feed_event(Event::Tag(0)).unwrap();
+		encoder.feed_event(Event::UnknownLengthMap).unwrap();
+		encoder.feed_event(Event::Unsigned(2)).unwrap();
+		assert_eq!(
+			encoder.feed_event(Event::Break).unwrap_err().to_string(),
+			"break at invalid time (at value #0 of a map of unknown length)"
+		);
+		encoder.feed_event(Event::Unsigned(3)).unwrap();
+		encoder.feed_event(Event::Break).unwrap();
+		assert!(matches!(
+			encoder.feed_event(Event::Break),
+			Err(EncodeError::InvalidBreak(EventContext::MapValue {
+				len: Some(2),
+				index: 1
+			}))
+		));
+		encoder.feed_event(Event::Array(1)).unwrap();
+		assert!(matches!(
+			encoder.feed_event(Event::Break),
+			Err(EncodeError::InvalidBreak(EventContext::Array {
+				len: Some(1),
+				index: 0
+			}))
+		));
+		encoder.feed_event(Event::Simple(22)).unwrap();
+		assert!(encoder.ready_to_finish());
+		assert!(matches!(
+			encoder.feed_event(Event::Simple(22)),
+			Err(EncodeError::Excess)
+		));
+		assert_eq!(
+			encoder.finish().unwrap(),
+			[0xA2, 0x00, 0x01, 0xC0, 0xBF, 0x02, 0x03, 0xFF, 0x81, 0xF6]
+		);
+
+		let mut encoder = Encoder::new(Vec::new());
+		assert!(matches!(
+			encoder.feed_event(Event::Break),
+			Err(EncodeError::InvalidBreak(EventContext::TopLevel))
+		));
+		encoder.feed_event(Event::Tag(1)).unwrap();
+		assert!(matches!(
+			encoder.feed_event(Event::Break),
+			Err(EncodeError::InvalidBreak(EventContext::Tagged(1)))
+		));
+	}
+
+	#[test]
+	fn decode_text() {
+		decode_test!([0x60] => Ok(Event::TextString(x)) if x.is_empty());
+		decode_test!(b"\x65Hello" => Ok(Event::TextString(x)) if x == "Hello");
+		decode_test!(b"\x78\x04Halo" => Ok(Event::TextString(x)) if x == "Halo");
+		decode_test!(b"\x79\x00\x07Goodbye" => Ok(Event::TextString(x)) if x == "Goodbye");
+		decode_test!(b"\x7A\x00\x00\x00\x0DLong message!" => Ok(Event::TextString(x)) if x == "Long message!");
+		decode_test!(b"\x7B\x00\x00\x00\x00\x00\x00\x00\x01?" => Ok(Event::TextString(x)) if x == "?");
+	}
+
+	#[test]
+	fn decode_text_64bit_bounds() {
+		decode_test!(small b"\x7B\x00\x00\x00\x00\x00\x00\x00");
+		decode_test!(small b"\x7B\x00\x00\x00\x00\x00\x00\x00\x01");
+	}
 
-		match event {
-			Event::Unsigned(n) => {
-				write_initial_and_argument!(0, n);
-			}
-			Event::Signed(n) => {
-				write_initial_and_argument!(1, n);
-			}
-			Event::ByteString(bytes) => {
-				write_initial_and_argument!(2, bytes.len() as _);
-				self.dest.write_all(&bytes)?;
-			}
-			Event::UnknownLengthByteString => {
-				self.dest.write_all(&[0x5F])?;
-				self.pending.push(Pending::Break);
-			}
-			Event::TextString(text) => {
-				write_initial_and_argument!(3, text.len() as _);
-				self.dest.write_all(text.as_bytes())?;
-			}
-			Event::UnknownLengthTextString => {
-				self.dest.write_all(&[0x7F])?;
-				self.pending.push(Pending::Break);
-			}
-			Event::Array(n) => {
-				write_initial_and_argument!(4, n);
-				self.pending.push(Pending::Array(n));
-			}
-			Event::UnknownLengthArray => {
-				self.dest.write_all(&[0x9F])?;
-				self.pending.push(Pending::Break);
-			}
-			Event::Map(n) => {
-				write_initial_and_argument!(5, n);
-				self.pending.push(Pending::Map(n, true));
-			}
-			Event::UnknownLengthMap => {
-				self.dest.write_all(&[0xBF])?;
-				self.pending.push(Pending::UnknownLengthMap(true));
-			}
-			Event::Tag(n) => {
-				write_initial_and_argument!(6, n);
-				self.pending.push(Pending::Tag);
-			}
-			Event::Float(n64) => {
-				let n32 = n64 as f32;
-				if n32 as f64 == n64 {
-					let n16 = half::f16::from_f64(n64);
-					if n16.to_f64() == n64 {
-						self.dest.write_all(&[0xF9])?;
-						self.dest.write_all(&n16.to_be_bytes())?;
-					} else {
-						self.dest.write_all(&[0xFA])?;
-						self.dest.write_all(&n32.to_be_bytes())?;
-					}
-				} else {
-					self.dest.write_all(&[0xFB])?;
-					self.dest.write_all(&n64.to_be_bytes())?;
-				}
-			}
-			Event::Simple(n) => {
-				// The CBOR spec requires that simple values 0-24 be encoded as a single byte,
-				// and simple values 25-255 be encoded as two bytes.
-				// Why this is required when overlong arguments are otherwise legal is a mystery to me,
-				// but in any case, we always generate the shortest encoding anyway, so it's fine.
-				//
-				// Also, since n is a u8, it'll never exceed 255, so we can just do this:
-				write_initial_and_argument!(7, n as _);
-				// and not worry about accidentally generating the prefix to a float.
-			}
-			Event::Break => match self.pending.pop() {
-				Some(Pending::Break | Pending::UnknownLengthMap(false)) => {
-					self.dest.write_all(&[0xFF])?
-				}
-				_ => return Err(EncodeError::InvalidBreak),
-			},
+	#[test]
+	fn encode_text() {
+		encode_test!(Event::TextString(Cow::Borrowed("")) => b"\x60");
+		encode_test!(Event::TextString(Cow::Borrowed("abcd")) => b"\x64abcd");
+
+		macro_rules! test {
+			($size:expr, $prefix:expr) => {
+				let size: usize = $size;
+				let prefix = $prefix;
+				let input = {
+					let mut it = Vec::with_capacity(size);
+					it.resize(size, b"A"[0]);
+					unsafe { String::from_utf8_unchecked(it) }
+				};
+				let mut output = Vec::with_capacity(size + prefix.len());
+				let mut encoder = Encoder::new(Cursor::new(&mut output));
+				encoder
+					.feed_event(Event::TextString(Cow::Borrowed(&input)))
+					.unwrap();
+				assert_eq!(output[..prefix.len()], prefix);
+				assert_eq!(output[prefix.len()..], input.into_bytes());
+			};
 		}
 
-		Ok(())
+		test!(0x30, [0x78, 0x30]);
+		test!(0x02FA, [0x79, 0x02, 0xFA]);
+		test!(0x010000, [0x7A, 0x00, 0x01, 0x00, 0x00]);
+		// Allocates about 8 GiB of memory! And iterates 4Gi times! Wowie wow wow!
+		test!(
+			2usize.pow(32),
+			[0x7B, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]
+		);
 	}
 
-	pub fn ready_to_finish(&self) -> bool {
-		self.pending.is_empty()
+	#[test]
+	fn decode_text_segmented() {
+		let mut decoder = Decoder::new(Cursor::new(b"\x7F\x64abcd\x63efg\xFF"));
+		decode_test!(match decoder: Ok(Event::UnknownLengthTextString));
+		assert!(!decoder.ready_to_finish());
+		decode_test!(match decoder: Ok(Event::TextString(x)) if x == "abcd");
+		assert!(!decoder.ready_to_finish());
+		decode_test!(match decoder: Ok(Event::TextString(x)) if x == "efg");
+		assert!(!decoder.ready_to_finish());
+		decode_test!(match decoder: Ok(Event::Break));
+		assert!(decoder.ready_to_finish());
 	}
-}
 
-#[cfg(test)]
-mod test {
-	use super::*;
-	use std::io::Cursor;
+	#[test]
+	fn decode_text_segmented_small() {
+		let mut decoder = Decoder::new(Cursor::new(b"\x7F\x64abcd"));
+		decode_test!(match decoder: Ok(Event::UnknownLengthTextString));
+		decode_test!(match decoder: Ok(Event::TextString(x)) if x == "abcd");
+		decode_test!(match decoder: Err(DecodeErrorKind::Insufficient));
+		assert!(!decoder.ready_to_finish());
+	}
 
-	macro_rules! decode_test {
-		(match $decoder:ident: $in:expr => $out:pat if $cond:expr) => {
-			match $decoder.next_event() {
-				$out if $cond => (),
-				other => panic!(concat!("{:X?} -> {:X?} instead of ", stringify!($out), " if ", stringify!($cond)), $in, other),
-			}
-		};
-		(match $decoder:ident: $in:expr => $out:pat) => {
-			decode_test!(match $decoder: $in => $out if true);
-		};
-		(match $decoder:ident: $out:pat if $cond:expr) => {
-			match $decoder.next_event() {
-				$out if $cond => (),
-				other => panic!("? -> {:X?}", other),
-			}
-		};
-		(match $decoder:ident: $out:pat) => {
-			decode_test!(match $decoder: $out if true);
-		};
-		($in:expr => $out:pat if $cond:expr) => {
-			let mut decoder = Decoder::new(Cursor::new($in));
-			decode_test!(match decoder: $in => $out if $cond);
-			decoder.finish().unwrap();
-		};
-		($in:expr => $out:pat) => {
-			decode_test!($in => $out if true);
-		};
-		(small $in:expr) => {
-			let mut decoder = Decoder::new(Cursor::new($in));
-			decode_test!(match decoder: $in => Err(DecodeError::Insufficient));
+	#[test]
+	fn decode_slice_borrowed() {
+		let input = b"\x82\x45Hello\x65world\x00";
+		let mut decoder = Decoder::from_slice(input);
+		decode_test!(match decoder: Ok(Event::Array(2)));
+		decode_test!(match decoder: Ok(Event::ByteString(Cow::Borrowed(x))) if x == b"Hello");
+		decode_test!(match decoder: Ok(Event::TextString(Cow::Borrowed(x))) if x == "world");
+		assert_eq!(decoder.finish().unwrap(), b"\x00");
+	}
+
+	#[test]
+	fn decode_slice_small() {
+		let mut decoder = Decoder::from_slice(b"\x5F\x44ab");
+		decode_test!(match decoder: Ok(Event::UnknownLengthByteString));
+		decode_test!(match decoder: Err(DecodeErrorKind::Insufficient));
+		assert!(!decoder.ready_to_finish());
+		assert_eq!(decoder.force_finish(), b"\x44ab");
+	}
+
+	#[test]
+	fn decode_push() {
+		use TryNextEventOutcome::*;
+		let mut decoder = Decoder::new_push();
+		assert!(matches!(decoder.try_next_event(), Ok(Needs(n)) if n.get() == 1));
+		decoder.feed(b"\x82\x19");
+		assert!(matches!(
+			decoder.try_next_event(),
+			Ok(GotEvent(Event::Array(2)))
+		));
+		assert!(matches!(decoder.try_next_event(), Ok(Needs(n)) if n.get() == 2));
+		decoder.feed(b"\x01");
+		assert!(matches!(decoder.try_next_event(), Ok(Needs(n)) if n.get() == 1));
+		decoder.feed(b"\x02\x43a");
+		assert!(matches!(
+			decoder.try_next_event(),
+			Ok(GotEvent(Event::Unsigned(0x0102)))
+		));
+		assert!(matches!(decoder.try_next_event(), Ok(Needs(n)) if n.get() == 2));
+		assert!(!decoder.ready_to_finish());
+		decoder.feed(b"bc");
+		assert!(
+			matches!(decoder.try_next_event(), Ok(GotEvent(Event::ByteString(x))) if x == &b"abc"[..])
+		);
+		decoder.finish().unwrap();
+	}
+
+	#[test]
+	fn decode_push_excess() {
+		let mut decoder = Decoder::new_push();
+		decoder.feed(b"\x00\x01");
+		decode_test!(match decoder: Ok(Event::Unsigned(0)));
+		assert!(!decoder.ready_to_finish());
+		assert_eq!(decoder.force_finish(), b"\x01");
+	}
+
+	#[cfg(feature = "async")]
+	#[test]
+	fn decode_async() {
+		futures::executor::block_on(async {
+			let mut decoder =
+				AsyncDecoder::new(futures::io::Cursor::new(b"\x82\x43abc\x19\x01\x02\x00"));
+			assert!(matches!(decoder.next_event().await, Ok(Event::Array(2))));
+			assert!(
+				matches!(decoder.next_event().await, Ok(Event::ByteString(x)) if x == &b"abc"[..])
+			);
 			assert!(!decoder.ready_to_finish());
-		};
+			assert!(matches!(
+				decoder.next_event().await.map_err(DecodeError::into_kind),
+				Ok(Event::Unsigned(0x0102))
+			));
+			// The decoder mustn't have read the trailing zero.
+			assert_eq!(decoder.finish().unwrap().position(), 8);
+		});
 	}
 
-	macro_rules! encode_test {
-		($($in:expr),+ => $out:expr, check finish if $cond:expr, expecting $expect:expr; $event:ident) => {
-			let mut buf = Vec::new();
-			let mut encoder = Encoder::new(Cursor::new(&mut buf));
-			for (idx, $event) in [$($in),+].into_iter().enumerate() {
-				let check_finish = $cond;
-				let expected = $expect;
-				encoder.feed_event($event).unwrap();
-				if check_finish {
-					assert_eq!(encoder.ready_to_finish(), expected, "readiness to finish was not as expected after event #{}", idx);
-				}
-			}
-			assert!(encoder.ready_to_finish());
-			std::mem::drop(encoder);
-			assert_eq!(buf, $out);
-		};
-		($($in:expr),+ => $out:expr, check finish expecting $expect:expr; $event:ident) => {
-			encode_test!($($in),+ => $out, check finish if true, expecting $expect; $event);
-		};
-		($($in:expr),+ => $out:expr, check finish if $cond:expr; $event:ident) => {
-			encode_test!($($in),+ => $out, check finish if $cond, expecting true; $event);
-		};
-		($($in:expr),+ => $out:expr) => {
-			encode_test!($($in),+ => $out, check finish if false; event);
-		};
-		($($in:expr),+ => $out:expr, check finish) => {
-			encode_test!($($in),+ => $out, check finish if true; event);
-		};
+	#[cfg(feature = "async")]
+	#[test]
+	fn decode_async_small() {
+		futures::executor::block_on(async {
+			let mut decoder = AsyncDecoder::new(futures::io::Cursor::new(b"\x82\x43ab"));
+			assert!(matches!(decoder.next_event().await, Ok(Event::Array(2))));
+			assert!(matches!(
+				decoder.next_event().await.map_err(DecodeError::into_kind),
+				Err(DecodeErrorKind::Insufficient)
+			));
+			assert!(!decoder.ready_to_finish());
+			let mut rest = Vec::new();
+			futures::AsyncReadExt::read_to_end(&mut decoder.force_finish(), &mut rest)
+				.await
+				.unwrap();
+			assert_eq!(rest, b"\x43ab");
+		});
 	}
 
+	#[cfg(feature = "async")]
 	#[test]
-	fn decode_uint_tiny() {
-		for i1 in 0..=0x17u8 {
-			decode_test!([i1] => Ok(Event::Unsigned(i2)) if i2 == i1 as _);
-		}
+	fn encode_async() {
+		futures::executor::block_on(async {
+			let mut encoder = AsyncEncoder::new(futures::io::Cursor::new(Vec::new()));
+			encoder.feed_event(Event::Array(2)).await.unwrap();
+			encoder
+				.feed_event(Event::TextString(Cow::Borrowed("abc")))
+				.await
+				.unwrap();
+			assert!(!encoder.ready_to_finish());
+			encoder.feed_event(Event::Unsigned(0x0102)).await.unwrap();
+			let buf = encoder.finish().await.unwrap().into_inner();
+			assert_eq!(buf, b"\x82\x63abc\x19\x01\x02");
+
+			let mut encoder = AsyncEncoder::new(futures::io::Cursor::new(Vec::new()));
+			encoder.feed_event(Event::Array(2)).await.unwrap();
+			encoder.feed_event(Event::Unsigned(1)).await.unwrap();
+			assert_eq!(encoder.force_finish().into_inner(), b"\x82\x01");
+		});
 	}
 
 	#[test]
-	fn encode_uint_tiny() {
-		for i in 0..=0x17u8 {
-			encode_test!(Event::Unsigned(i as _) => [i]);
+	fn decode_limits_depth() {
+		let mut limits = DecodeLimits::new();
+		limits.set_max_depth(Some(2));
+		let mut decoder = Decoder::new(Cursor::new(b"\x81\xC1\x00"));
+		decoder.set_limits(limits.clone());
+		while !decoder.ready_to_finish() {
+			decoder.next_event().unwrap();
 		}
+		let mut decoder = Decoder::new(Cursor::new(b"\x81\x9F\x5F"));
+		decoder.set_limits(limits);
+		decode_test!(match decoder: Ok(Event::Array(1)));
+		decode_test!(match decoder: Ok(Event::UnknownLengthArray));
+		decode_test!(match decoder: Err(DecodeErrorKind::TooDeep));
 	}
 
 	#[test]
-	fn decode_uint_8bit() {
-		decode_test!([0x18u8, 0x01] => Ok(Event::Unsigned(0x01)));
+	fn decode_limits_string() {
+		let mut limits = DecodeLimits::new();
+		limits.set_max_string_len(Some(3));
+		let mut decoder = Decoder::new(Cursor::new(b"\x63abc"));
+		decoder.set_limits(limits.clone());
+		decode_test!(match decoder: Ok(Event::TextString(_)));
+		// The contents shouldn't be needed to know that the string is too long.
+		let mut decoder = Decoder::new(Cursor::new(b"\x5B\x00\x00\x00\x01\x00\x00\x00\x00"));
+		decoder.set_limits(limits.clone());
+		decode_test!(match decoder: Err(DecodeErrorKind::StringTooLong));
+		// The pieces of an unknown-length string count together.
+		let mut decoder = Decoder::new(Cursor::new(b"\x7F\x62ab\x61c\x61d\xFF"));
+		decoder.set_limits(limits);
+		decode_test!(match decoder: Ok(Event::UnknownLengthTextString));
+		decode_test!(match decoder: Ok(Event::TextString(_)));
+		decode_test!(match decoder: Ok(Event::TextString(_)));
+		decode_test!(match decoder: Err(DecodeErrorKind::StringTooLong));
 	}
 
 	#[test]
-	fn decode_uint_8bit_bounds() {
-		decode_test!(small b"\x18");
+	fn decode_limits_collection() {
+		let mut limits = DecodeLimits::new();
+		limits.set_max_collection_len(Some(1));
+		let mut decoder = Decoder::new(Cursor::new(b"\xA1\x00\x00\x82"));
+		decoder.set_limits(limits.clone());
+		decode_test!(match decoder: Ok(Event::Map(1)));
+		decode_test!(match decoder: Ok(_));
+		decode_test!(match decoder: Ok(_));
+		decode_test!(match decoder: Err(DecodeErrorKind::CollectionTooLong));
+		// Unknown-length arrays and maps are counted as they go, and their breaks don't count.
+		let mut decoder = Decoder::new(Cursor::new(b"\x9F\x9F\x00\xFF\x01"));
+		decoder.set_limits(limits.clone());
+		decode_test!(match decoder: Ok(Event::UnknownLengthArray));
+		decode_test!(match decoder: Ok(Event::UnknownLengthArray));
+		decode_test!(match decoder: Ok(_));
+		decode_test!(match decoder: Ok(Event::Break));
+		decode_test!(match decoder: Err(DecodeErrorKind::CollectionTooLong));
+		let mut decoder = Decoder::new(Cursor::new(b"\xBF\x00\x00\x01"));
+		decoder.set_limits(limits);
+		decode_test!(match decoder: Ok(Event::UnknownLengthMap));
+		decode_test!(match decoder: Ok(_));
+		decode_test!(match decoder: Ok(_));
+		decode_test!(match decoder: Err(DecodeErrorKind::CollectionTooLong));
 	}
 
 	#[test]
-	fn encode_uint_8bit() {
-		encode_test!(Event::Unsigned(0x3F) => [0x18, 0x3F]);
+	fn decode_limits_input() {
+		let mut limits = DecodeLimits::new();
+		limits.set_max_input_len(Some(5));
+		let mut decoder = Decoder::from_slice(b"\x82\x00\x43abc");
+		decoder.set_limits(limits);
+		decode_test!(match decoder: Ok(Event::Array(2)));
+		decode_test!(match decoder: Ok(_));
+		decode_test!(match decoder: Err(DecodeErrorKind::InputTooLong));
 	}
 
 	#[test]
-	fn decode_uint_16bit() {
-		decode_test!([0x19u8, 0x01, 0x02] => Ok(Event::Unsigned(0x0102)));
+	fn skip_item() {
+		// {"a": [1, (_ h'00', h'01'), [_ 2, 24(h'')]], "b": {_ 3: 1("x")}, "c": 4}
+		let input = b"\xA3\x61a\x83\x01\x5F\x41\x00\x41\x01\xFF\x9F\x02\xD8\x18\x40\xFF\x61b\xBF\x03\xC1\x61x\xFF\x61c\x04";
+		let mut decoder = Decoder::from_slice(input);
+		decode_test!(match decoder: Ok(Event::Map(3)));
+		decode_test!(match decoder: Ok(Event::TextString(k)) if k == "a");
+		assert!(decoder.skip_item().unwrap());
+		assert_eq!(decoder.position(), 17);
+		decode_test!(match decoder: Ok(Event::TextString(k)) if k == "b");
+		assert!(decoder.skip_item().unwrap());
+		decode_test!(match decoder: Ok(Event::TextString(k)) if k == "c");
+		assert!(decoder.skip_item().unwrap());
+		assert!(decoder.ready_to_finish());
+
+		let mut decoder = Decoder::new(Cursor::new(input));
+		assert!(decoder.skip_item().unwrap());
+		decoder.finish().unwrap();
+
+		let mut decoder = Decoder::from_slice(b"\x9F\x00\xFF");
+		decode_test!(match decoder: Ok(Event::UnknownLengthArray));
+		assert!(decoder.skip_item().unwrap());
+		assert!(!decoder.skip_item().unwrap());
+		assert!(decoder.ready_to_finish());
+
+		let mut decoder = Decoder::from_slice(b"\x82\x81\x80");
+		assert!(matches!(
+			decoder.skip_item().map_err(DecodeError::into_kind),
+			Err(DecodeErrorKind::Insufficient)
+		));
+
+		// A string that's cut off doesn't count towards the position.
+		let mut decoder = Decoder::new(Cursor::new(b"\x82\x43ab"));
+		let error = decoder.skip_item().unwrap_err();
+		assert!(matches!(error.kind(), DecodeErrorKind::Insufficient));
+		assert_eq!(error.offset(), 1);
+		assert_eq!(decoder.position(), 2);
 	}
 
 	#[test]
-	fn decode_uint_16bit_bounds() {
-		decode_test!(small b"\x19\x00");
+	fn read_raw_item() {
+		// [h'00', {"a": [_ 1]}, 2]
+		let input = b"\x83\x41\x00\xA1\x61a\x9F\x01\xFF\x02";
+		let item = &input[3..9];
+
+		let mut decoder = Decoder::from_slice(input);
+		decode_test!(match decoder: Ok(Event::Array(3)));
+		decode_test!(match decoder: Ok(Event::ByteString(_)));
+		assert!(
+			matches!(decoder.read_raw_item().unwrap(), Some(Cow::Borrowed(raw)) if raw == item)
+		);
+		decode_test!(match decoder: Ok(Event::Unsigned(2)));
+		assert!(decoder.ready_to_finish());
+
+		let mut decoder = Decoder::new(Cursor::new(input));
+		decode_test!(match decoder: Ok(Event::Array(3)));
+		assert_eq!(decoder.read_raw_item().unwrap().unwrap(), &input[1..3]);
+		assert_eq!(decoder.read_raw_item().unwrap().unwrap(), item);
+		assert_eq!(decoder.read_raw_item().unwrap().unwrap(), &input[9..]);
+		decoder.finish().unwrap();
+
+		let mut decoder = Decoder::new_push();
+		decoder.feed(input);
+		assert_eq!(decoder.read_raw_item().unwrap().unwrap(), &input[..]);
+		decoder.finish().unwrap();
+
+		let mut decoder = Decoder::from_slice(b"\x9F\xFF");
+		decode_test!(match decoder: Ok(Event::UnknownLengthArray));
+		assert!(decoder.read_raw_item().unwrap().is_none());
+		assert!(decoder.ready_to_finish());
+
+		// ["abc", 1], with the string only partly read before the 1 is.
+		let input = b"\x82\x63abc\x01";
+		let mut decoder = Decoder::from_slice(input);
+		let mut byte = [0];
+		decode_test!(match decoder: Ok(Event::Array(2)));
+		assert!(matches!(
+			decoder.next_event_streaming(),
+			Ok(StreamingEvent::TextString(3))
+		));
+		decoder.string_reader().read_exact(&mut byte).unwrap();
+		assert_eq!(decoder.read_raw_item().unwrap().unwrap(), &input[5..]);
+		assert!(decoder.ready_to_finish());
+
+		let mut decoder = Decoder::new(Cursor::new(input));
+		decode_test!(match decoder: Ok(Event::Array(2)));
+		assert!(matches!(
+			decoder.next_event_streaming(),
+			Ok(StreamingEvent::TextString(3))
+		));
+		decoder.string_reader().read_exact(&mut byte).unwrap();
+		assert_eq!(decoder.read_raw_item().unwrap().unwrap(), &input[5..]);
+		decoder.finish().unwrap();
 	}
 
 	#[test]
-	fn encode_uint_16bit() {
-		encode_test!(Event::Unsigned(0x1234) => [0x19, 0x12, 0x34]);
+	fn round_trip_detailed() {
+		fn round_trip(input: &[u8]) -> Option<Vec<u8>> {
+			let mut output = Vec::new();
+			let mut decoder = Decoder::from_slice(input);
+			let mut encoder = Encoder::new(&mut output);
+			loop {
+				let (event, width) = decoder.next_event_detailed().ok()?;
+				encoder.feed_event_detailed(event, width).unwrap();
+				if decoder.ready_to_finish() {
+					return Some(output);
+				}
+			}
+		}
+
+		let input = b"\x9F\x18\x01\x39\x00\x00\x5F\x41\x00\x40\xFF\x7A\x00\x00\x00\x01a\xB8\x01\xD8\x01\x00\xF8\x20\xFB\x3F\xF0\x00\x00\x00\x00\x00\x00\xFA\x80\x00\x00\x00\xF9\x3C\x00\xFF";
+		assert_eq!(round_trip(input).unwrap(), input);
+		let mut count = 0;
+		for input in fuzz_inputs(20_000) {
+			if let Some(output) = round_trip(&input) {
+				assert_eq!(output, input[..output.len()]);
+				count += 1;
+			}
+		}
+		assert!(count > 0);
 	}
 
 	#[test]
-	fn decode_uint_32bit() {
-		decode_test!([0x1Au8, 0x01, 0x02, 0x03, 0x04] => Ok(Event::Unsigned(0x01020304)));
+	fn encode_detailed_invalid() {
+		let invalid = |event, width| {
+			let mut output = Vec::new();
+			let result = Encoder::new(&mut output).feed_event_detailed(event, width);
+			matches!(result, Err(EncodeError::InvalidWidth)) && output.is_empty()
+		};
+		assert!(invalid(Event::Unsigned(24), ArgumentWidth::Immediate));
+		assert!(invalid(Event::Unsigned(0x100), ArgumentWidth::U8));
+		assert!(invalid(Event::Unsigned(0), ArgumentWidth::Indefinite));
+		assert!(invalid(Event::UnknownLengthArray, ArgumentWidth::U8));
+		assert!(invalid(Event::Simple(20), ArgumentWidth::U8));
+		assert!(invalid(Event::Simple(32), ArgumentWidth::Immediate));
+		assert!(invalid(Event::Float64(1.0), ArgumentWidth::U32));
+		assert!(invalid(Event::Float16(f16::ONE), ArgumentWidth::U8));
+
+		let mut output = Vec::new();
+		let mut encoder = Encoder::new(&mut output);
+		encoder.feed_event(Event::Array(2)).unwrap();
+		encoder
+			.feed_event_detailed(Event::Float32(-0.0), ArgumentWidth::U32)
+			.unwrap();
+		encoder
+			.feed_event_detailed(Event::Tag(1), ArgumentWidth::U64)
+			.unwrap();
+		encoder
+			.feed_event_detailed(Event::Simple(0), ArgumentWidth::Immediate)
+			.unwrap();
+		assert_eq!(
+			output,
+			b"\x82\xFA\x80\x00\x00\x00\xDB\x00\x00\x00\x00\x00\x00\x00\x01\xE0"
+		);
 	}
 
 	#[test]
-	fn decode_uint_32bit_bounds() {
-		decode_test!(small b"\x1A\x00\x00\x00");
+	fn events() {
+		// A sequence of [1, [_ h'02'], {}] and 3
+		let input = b"\x83\x01\x9F\x41\x02\xFF\xA0\x03";
+		let mut decoder = Decoder::from_slice(input);
+		let events: Vec<_> = decoder.events().collect::<Result<_, _>>().unwrap();
+		assert_eq!(
+			format!("{:?}", events),
+			format!(
+				"{:?}",
+				[
+					Event::Array(3),
+					Event::Unsigned(1),
+					Event::UnknownLengthArray,
+					Event::ByteString(Cow::Borrowed(b"\x02")),
+					Event::Break,
+					Event::Map(0),
+				]
+			)
+		);
+		assert_eq!(decoder.position(), 7);
+		assert!(matches!(
+			&decoder.events().collect::<Vec<_>>()[..],
+			[Ok(Event::Unsigned(3))]
+		));
+		assert!(decoder.ready_to_finish());
+		assert!(decoder.events().next().is_none());
+
+		// Empty input has no events, rather than running out partway through one.
+		let mut decoder = Decoder::from_slice(b"");
+		assert!(decoder.events().next().is_none());
+		let mut decoder = Decoder::new(Cursor::new(b""));
+		assert!(decoder.events().next().is_none());
+
+		// Starting in the middle of an item finishes that item.
+		let mut decoder = Decoder::from_slice(input);
+		decode_test!(match decoder: Ok(Event::Array(3)));
+		decode_test!(match decoder: Ok(Event::Unsigned(1)));
+		assert_eq!(decoder.events().count(), 4);
+		assert_eq!(decoder.position(), 7);
+
+		// Errors end the iteration.
+		let mut decoder = Decoder::from_slice(b"\x82\x01");
+		let mut events = decoder.events();
+		assert!(matches!(events.next(), Some(Ok(Event::Array(2)))));
+		assert!(matches!(events.next(), Some(Ok(Event::Unsigned(1)))));
+		assert!(matches!(events.next(), Some(Err(_))));
+		assert!(events.next().is_none());
+	}
+
+	#[test]
+	fn reset() {
+		let mut decoder = Decoder::from_slice(b"\x82\x01");
+		decoder.set_require_definite(true);
+		decode_test!(match decoder: Ok(Event::Array(2)));
+		decoder.reset(b"\x9F\xFF");
+		assert_eq!(decoder.position(), 0);
+		decode_test!(match decoder: Err(DecodeErrorKind::NotDefinite(4)));
+		decoder.reset(b"\x01");
+		decode_test!(match decoder: Ok(Event::Unsigned(1)));
+		assert!(decoder.finish().unwrap().is_empty());
+
+		let mut decoder = Decoder::new(Cursor::new(&b"\x83\x01"[..]));
+		decode_test!(match decoder: Ok(Event::Array(3)));
+		let old = decoder.reset(Cursor::new(&b"\x02"[..]));
+		assert_eq!(old.position(), 1);
+		decode_test!(match decoder: Ok(Event::Unsigned(2)));
+		decoder.finish().unwrap();
+
+		let mut decoder = Decoder::from_buf_read(std::io::BufReader::new(&b"\x5F\x41"[..]));
+		decode_test!(match decoder: Ok(Event::UnknownLengthByteString));
+		decoder.reset(std::io::BufReader::new(&b"\x41\x00"[..]));
+		decode_test!(match decoder: Ok(Event::ByteString(b)) if b[..] == [0]);
+		decoder.finish().unwrap();
+
+		let mut decoder = Decoder::new_push();
+		decoder.feed(b"\x82\x01\x19");
+		decode_test!(match decoder: Ok(Event::Array(2)));
+		decode_test!(match decoder: Ok(Event::Unsigned(1)));
+		decoder.reset();
+		decoder.feed(b"\x19\x01\x00");
+		decode_test!(match decoder: Ok(Event::Unsigned(0x100)));
+		decoder.finish().unwrap();
+	}
+
+	#[test]
+	fn key_set() {
+		// {1: 0, [1.0]: 0, 0x18 0x01: 0}
+		let input = b"\xA3\x01\x00\x81\xF9\x3C\x00\x00\x18\x01\x00";
+		let mut decoder = Decoder::from_slice(input);
+		decode_test!(match decoder: Ok(Event::Map(3)));
+		let mut keys = KeySet::new();
+		let mut duplicates = Vec::new();
+		for _ in 0..3 {
+			let key = decoder.read_raw_item().unwrap().unwrap();
+			if !keys.insert_encoded(&key).unwrap() {
+				duplicates.push(key.into_owned());
+			}
+			decoder.skip_item().unwrap();
+		}
+		decoder.finish().unwrap();
+		assert_eq!(duplicates, [b"\x18\x01"]);
+		assert_eq!(keys.len(), 2);
+
+		assert!(!keys.insert(tree::Item::Array(vec![tree::Item::Float64(1.0)])));
+		assert!(keys.insert(tree::Item::Array(vec![tree::Item::Unsigned(1)])));
+		assert!(keys.insert_encoded(b"\x81").is_err());
 	}
 
-	#[test]
-	fn encode_uint_32bit() {
-		encode_test!(Event::Unsigned(0x12345678) => [0x1A, 0x12, 0x34, 0x56, 0x78]);
+	#[test]
+	fn skip_item_huge_string() {
+		// 64 MiB of zeroes, followed by a 1.
+		let len = 1u32 << 26;
+		let mut header = vec![0x5A];
+		header.extend_from_slice(&len.to_be_bytes());
+		let input = Cursor::new(header)
+			.chain(std::io::repeat(0).take(len as u64))
+			.chain(Cursor::new([0x01]));
+		let mut decoder = Decoder::new(input);
+		assert!(decoder.skip_item().unwrap());
+		assert!(decoder.source.buffer.data.capacity() < 1024);
+		decode_test!(match decoder: Ok(Event::Unsigned(1)));
+		assert!(decoder.ready_to_finish());
+	}
+
+	#[test]
+	fn decode_buf_read_zero_length() {
+		/// A reader that fails once its input runs out, like a connection being reset.
+		struct FailAtEnd<'a>(&'a [u8]);
+
+		impl Read for FailAtEnd<'_> {
+			fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+				let len = self.fill_buf()?.len().min(buf.len());
+				buf[..len].copy_from_slice(&self.0[..len]);
+				self.consume(len);
+				Ok(len)
+			}
+		}
+
+		impl BufRead for FailAtEnd<'_> {
+			fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+				if self.0.is_empty() {
+					Err(std::io::ErrorKind::ConnectionReset.into())
+				} else {
+					Ok(self.0)
+				}
+			}
+
+			fn consume(&mut self, amt: usize) {
+				self.0 = &self.0[amt..];
+			}
+		}
+
+		let mut decoder = Decoder::from_buf_read(FailAtEnd(b"\x60"));
+		decode_test!(match decoder: Ok(Event::TextString(x)) if x.is_empty());
+		assert!(decoder.ready_to_finish());
+		let mut decoder = Decoder::from_buf_read(FailAtEnd(b"\x40"));
+		assert!(matches!(decoder.peek_event(), Ok(Event::ByteString(x)) if x.is_empty()));
+		decode_test!(match decoder: Ok(Event::ByteString(x)) if x.is_empty());
+		let mut decoder = Decoder::from_buf_read(FailAtEnd(b"\x80"));
+		assert_eq!(decoder.read_raw_item().unwrap().unwrap(), &b"\x80"[..]);
+		let mut decoder = Decoder::from_buf_read(FailAtEnd(b"\x81\x40"));
+		assert!(decoder.skip_item().unwrap());
+		assert!(decoder.ready_to_finish());
 	}
 
 	#[test]
-	fn decode_uint_64bit() {
-		decode_test!([0x1Bu8, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08] => Ok(Event::Unsigned(0x0102030405060708)));
-	}
+	fn input_buffer() {
+		let mut buffer = InputBuffer::default();
+		buffer.for_appending().extend_from_slice(b"abcd");
+		assert_eq!(buffer.consume(1), b"a");
+		// Less has been consumed than is left, so nothing is moved yet.
+		buffer.for_appending().extend_from_slice(b"e");
+		assert_eq!(buffer.start, 1);
+		assert_eq!(buffer.consume(3), b"bcd");
+		assert_eq!(buffer.len(), 1);
+		buffer.for_appending().extend_from_slice(b"f");
+		assert_eq!(buffer.start, 0);
+		assert_eq!(buffer.available(), b"ef");
+		buffer.consume(1);
+		assert_eq!(buffer.into_vec(), b"f");
 
-	#[test]
-	fn decode_uint_64bit_bounds() {
-		decode_test!(small b"\x1B\x00\x00\x00\x00\x00\x00\x00");
+		// Lots of small events don't make the buffer grow.
+		let input = vec![0x01; 100_000];
+		let mut decoder = Decoder::new(Cursor::new(&input));
+		for _ in 0..input.len() {
+			decode_test!(match decoder: Ok(Event::Unsigned(1)));
+		}
+		assert!(decoder.source.buffer.data.capacity() < 1024);
+		let mut decoder = Decoder::new_push();
+		for _ in 0..input.len() {
+			decoder.feed(&input[..1]);
+			decode_test!(match decoder: Ok(Event::Unsigned(1)));
+		}
+		assert!(decoder.source.buffer.data.capacity() < 1024);
 	}
 
 	#[test]
-	fn encode_uint_64bit() {
-		encode_test!(Event::Unsigned(0x123456789ABCDEF0) => [0x1B, 0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0]);
-	}
+	fn decode_buf_read_misbehaving() {
+		/// A reader that only hands over its buffer twice, and then fails or loses it until some is consumed.
+		struct Flaky<'a> {
+			input: &'a [u8],
+			fills: u8,
+			shrink: bool,
+		}
 
-	#[test]
-	fn decode_negint() {
-		decode_test!([0x20u8] => Ok(Event::Signed(0)));
-		decode_test!([0x37u8] => Ok(Event::Signed(0x17)));
-		decode_test!([0x38, 0x01] => Ok(Event::Signed(0x01)));
-		decode_test!([0x39, 0x01, 0x02] => Ok(Event::Signed(0x0102)));
-		decode_test!([0x3A, 0x01, 0x02, 0x03, 0x04] => Ok(Event::Signed(0x01020304)));
-		decode_test!([0x3B, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08] => Ok(Event::Signed(0x0102030405060708)));
-		decode_test!(small b"\x3B\x00\x00\x00\x00\x00\x00\x00");
-	}
+		impl Read for Flaky<'_> {
+			fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+				let len = self.fill_buf()?.len().min(buf.len());
+				buf[..len].copy_from_slice(&self.input[..len]);
+				self.consume(len);
+				Ok(len)
+			}
+		}
 
-	#[test]
-	fn encode_negint() {
-		encode_test!(Event::Signed(0) => [0x20]);
-		encode_test!(Event::Signed(0x17) => [0x37]);
-		encode_test!(Event::Signed(0xAA) => [0x38, 0xAA]);
-		encode_test!(Event::Signed(0x0102) => [0x39, 0x01, 0x02]);
-		encode_test!(Event::Signed(0x01020304) => [0x3A, 0x01, 0x02, 0x03, 0x04]);
-		encode_test!(Event::Signed(0x010203040506) => [0x3B, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
-	}
+		impl BufRead for Flaky<'_> {
+			fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+				self.fills += 1;
+				if self.fills <= 2 {
+					Ok(self.input)
+				} else if self.shrink {
+					Ok(&[])
+				} else {
+					Err(std::io::ErrorKind::Other.into())
+				}
+			}
 
-	#[test]
-	fn interpret_signed() {
-		assert_eq!(Event::interpret_signed(0), -1);
-		assert_eq!(Event::interpret_signed_checked(0), Some(-1));
-		assert_eq!(Event::interpret_signed_checked(u64::MAX), None);
-		assert_eq!(Event::interpret_signed_wide(0), -1);
-		assert_eq!(
-			Event::interpret_signed_wide(u64::MAX),
-			-1 - u64::MAX as i128
-		);
+			fn consume(&mut self, amt: usize) {
+				self.input = &self.input[amt..];
+				self.fills = 0;
+			}
+		}
+
+		for shrink in [false, true] {
+			let input = b"\x82\x43abc\x01";
+			let flaky = || Flaky {
+				input,
+				fills: 0,
+				shrink,
+			};
+			let mut decoder = Decoder::from_buf_read(flaky());
+			assert!(matches!(
+				decoder.next_event().map_err(DecodeError::into_kind),
+				Err(DecodeErrorKind::IoError(_))
+			));
+			let mut decoder = Decoder::from_buf_read(flaky());
+			assert!(decoder.peek_event().is_err());
+			let mut decoder = Decoder::from_buf_read(flaky());
+			assert!(decoder.read_raw_item().is_err());
+		}
 	}
 
 	#[test]
-	fn create_signed() {
-		assert!(matches!(Event::create_signed(0), Event::Unsigned(0)));
-		assert!(matches!(Event::create_signed(22), Event::Unsigned(22)));
-		assert!(matches!(Event::create_signed(-1), Event::Signed(0)));
-		assert!(matches!(Event::create_signed(-22), Event::Signed(21)));
+	fn decode_buf_read() {
+		// [_ h'0001', {"abc": [1.5, -1]}, 1000000], then some trailing input.
+		let input =
+			b"\x9F\x42\x00\x01\xA1\x63abc\x82\xF9\x3E\x00\x20\x1A\x00\x0F\x42\x40\xFF\x01\x02";
+		let cbor_len = input.len() - 2;
+		let inputs = std::iter::once(input[..cbor_len].to_vec()).chain(fuzz_inputs(2_000));
+		for input in inputs {
+			let mut expected = Vec::new();
+			let mut decoder = Decoder::from_slice(&input);
+			loop {
+				let event = decoder.next_event();
+				expected.push(format!("{:?}", event));
+				if event.is_err() {
+					break;
+				}
+			}
+			// Small buffers split events between refills.
+			for capacity in 1..=4 {
+				let mut decoder =
+					Decoder::from_buf_read(std::io::BufReader::with_capacity(capacity, &input[..]));
+				for expected in &expected {
+					assert_eq!(&format!("{:?}", decoder.next_event()), expected);
+				}
+			}
+		}
+
+		for capacity in [1, 3, 64] {
+			let mut decoder =
+				Decoder::from_buf_read(std::io::BufReader::with_capacity(capacity, &input[..]));
+			decode_test!(match decoder: Ok(Event::UnknownLengthArray));
+			assert!(
+				matches!(decoder.peek_event(), Ok(Event::ByteString(b)) if b == &b"\x00\x01"[..])
+			);
+			decode_test!(match decoder: Ok(Event::ByteString(b)) if b == &b"\x00\x01"[..]);
+			assert_eq!(decoder.read_raw_item().unwrap().unwrap(), &input[4..14]);
+			assert!(decoder.skip_item().unwrap());
+			decode_test!(match decoder: Ok(Event::Break));
+			// The trailing input is left in the reader.
+			let mut rest = Vec::new();
+			decoder.finish().unwrap().read_to_end(&mut rest).unwrap();
+			assert_eq!(rest, b"\x01\x02");
+		}
 	}
 
 	#[test]
-	fn decode_bytes() {
-		decode_test!([0x40] => Ok(Event::ByteString(Cow::Owned(x))) if x == b"");
-		decode_test!(b"\x45Hello" => Ok(Event::ByteString(Cow::Owned(x))) if x == b"Hello");
-		decode_test!(b"\x58\x04Halo" => Ok(Event::ByteString(Cow::Owned(x))) if x == b"Halo");
-		decode_test!(b"\x59\x00\x07Goodbye" => Ok(Event::ByteString(Cow::Owned(x))) if x == b"Goodbye");
-		decode_test!(b"\x5A\x00\x00\x00\x0DLong message!" => Ok(Event::ByteString(Cow::Owned(x))) if x == b"Long message!");
-		decode_test!(b"\x5B\x00\x00\x00\x00\x00\x00\x00\x01?" => Ok(Event::ByteString(Cow::Owned(x))) if x == b"?");
+	fn stream_string() {
+		// [h'abc', "héllo", "xyz", 1]
+		let input = b"\x84\x43abc\x66h\xC3\xA9llo\x63xyz\x01";
+		let mut decoder = Decoder::from_slice(input);
+		assert!(matches!(
+			decoder.next_event_streaming(),
+			Ok(StreamingEvent::Event(Event::Array(4)))
+		));
+		assert!(matches!(
+			decoder.next_event_streaming(),
+			Ok(StreamingEvent::ByteString(3))
+		));
+		let mut contents = Vec::new();
+		decoder.string_reader().read_to_end(&mut contents).unwrap();
+		assert_eq!(contents, b"abc");
+		assert!(matches!(
+			decoder.next_event_streaming(),
+			Ok(StreamingEvent::TextString(6))
+		));
+		// Read it a byte at a time, so the é is split between reads.
+		let mut reader = decoder.string_reader();
+		let mut contents = Vec::new();
+		let mut byte = [0];
+		while reader.read(&mut byte).unwrap() > 0 {
+			contents.push(byte[0]);
+		}
+		assert_eq!(reader.remaining(), 0);
+		assert_eq!(contents, "héllo".as_bytes());
+		// Whatever isn't read is skipped.
+		assert!(matches!(
+			decoder.next_event_streaming(),
+			Ok(StreamingEvent::TextString(3))
+		));
+		decoder.string_reader().read_exact(&mut byte).unwrap();
+		assert!(!decoder.ready_to_finish());
+		decode_test!(match decoder: Ok(Event::Unsigned(1)));
+		assert_eq!(decoder.position(), input.len() as u64);
+		decoder.finish().unwrap();
 	}
 
 	#[test]
-	fn encode_bytes() {
-		encode_test!(Event::ByteString(Cow::Borrowed(b"")) => b"\x40");
-		encode_test!(Event::ByteString(Cow::Borrowed(b"abcd")) => b"\x44abcd");
-
-		macro_rules! test {
-			($size:expr, $prefix:expr) => {
-				let size: usize = $size;
-				let prefix = $prefix;
-				let input = {
-					let mut it = Vec::with_capacity(size);
-					it.resize(size, 0x0Fu8);
-					it
-				};
-				let mut output = Vec::with_capacity(size + prefix.len());
-				let mut encoder = Encoder::new(Cursor::new(&mut output));
-				encoder
-					.feed_event(Event::ByteString(Cow::Borrowed(&input)))
-					.unwrap();
-				assert_eq!(output[..prefix.len()], prefix);
-				assert_eq!(output[prefix.len()..], input);
+	fn stream_string_invalid_utf8() {
+		fn stream_error(input: &[u8], read_len: usize) -> DecodeError {
+			let mut decoder = Decoder::new(Cursor::new(input));
+			assert!(matches!(
+				decoder.next_event_streaming(),
+				Ok(StreamingEvent::TextString(_))
+			));
+			let mut reader = decoder.string_reader();
+			let mut buf = vec![0; read_len];
+			let error = loop {
+				match reader.read(&mut buf) {
+					Ok(0) => panic!("{:X?} was accepted", input),
+					Ok(_) => (),
+					Err(e) => break e,
+				}
 			};
+			assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+			*error.into_inner().unwrap().downcast().unwrap()
 		}
 
-		test!(0x30, [0x58, 0x30]);
-		test!(0x02FA, [0x59, 0x02, 0xFA]);
-		test!(0x010000, [0x5A, 0x00, 0x01, 0x00, 0x00]);
-		// Allocates about 8 GiB of memory! And iterates 4Gi times! Wow!
-		test!(
-			2usize.pow(32),
-			[0x5B, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]
-		);
+		for read_len in [1, 2, 64] {
+			let error = stream_error(b"\x64a\xE9bc", read_len);
+			assert!(matches!(error.kind(), DecodeErrorKind::InvalidUtf8(_)));
+			assert_eq!(error.offset(), 2);
+			let error = stream_error(b"\x63ab\xE9", read_len);
+			assert!(matches!(error.kind(), DecodeErrorKind::InvalidUtf8(_)));
+			assert_eq!(error.offset(), 3);
+		}
 	}
 
 	#[test]
-	fn decode_bytes_segmented() {
-		let mut decoder = Decoder::new(Cursor::new(b"\x5F\x44abcd\x43efg\xFF"));
-		decode_test!(match decoder: Ok(Event::UnknownLengthByteString));
-		assert!(!decoder.ready_to_finish());
-		decode_test!(match decoder: Ok(Event::ByteString(Cow::Owned(x))) if x == b"abcd");
-		assert!(!decoder.ready_to_finish());
-		decode_test!(match decoder: Ok(Event::ByteString(Cow::Owned(x))) if x == b"efg");
-		assert!(!decoder.ready_to_finish());
-		decode_test!(match decoder: Ok(Event::Break));
+	fn stream_string_push() {
+		let mut decoder = Decoder::new_push();
+		decoder.feed(b"\x44ab");
+		assert!(matches!(
+			decoder.next_event_streaming(),
+			Ok(StreamingEvent::ByteString(4))
+		));
+		let mut buf = [0; 4];
+		assert_eq!(decoder.string_reader().read(&mut buf).unwrap(), 2);
+		let error = decoder.string_reader().read(&mut buf).unwrap_err();
+		assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+		decoder.feed(b"cd");
+		let mut reader = decoder.string_reader();
+		assert_eq!(reader.remaining(), 2);
+		assert_eq!(reader.read(&mut buf).unwrap(), 2);
+		assert_eq!(&buf[..2], b"cd");
+		decoder.finish().unwrap();
+	}
+
+	#[test]
+	fn stream_string_huge() {
+		// 64 MiB of zeroes, followed by a 1.
+		let len = 1u32 << 26;
+		let mut header = vec![0x5A];
+		header.extend_from_slice(&len.to_be_bytes());
+		let input = Cursor::new(header)
+			.chain(std::io::repeat(0).take(len as u64))
+			.chain(Cursor::new([0x01]));
+		let mut decoder = Decoder::new(input);
+		assert!(matches!(
+			decoder.next_event_streaming(),
+			Ok(StreamingEvent::ByteString(n)) if n == len as u64
+		));
+		let copied = std::io::copy(&mut decoder.string_reader(), &mut std::io::sink()).unwrap();
+		assert_eq!(copied, len as u64);
+		assert!(decoder.source.buffer.data.capacity() < 1024);
+		decode_test!(match decoder: Ok(Event::Unsigned(1)));
 		assert!(decoder.ready_to_finish());
 	}
 
 	#[test]
-	fn decode_bytes_segmented_small() {
-		let mut decoder = Decoder::new(Cursor::new(b"\x5F\x44abcd"));
-		decode_test!(match decoder: Ok(Event::UnknownLengthByteString));
-		decode_test!(match decoder: Ok(Event::ByteString(Cow::Owned(x))) if x == b"abcd");
-		decode_test!(match decoder: Err(DecodeError::Insufficient));
+	fn peek_event() {
+		// [_ "ab", h'', {1: 2}]
+		let input = b"\x9F\x62ab\x40\xA1\x01\x02\xFF";
+		let mut decoder = Decoder::from_slice(input);
+		loop {
+			let position = decoder.position();
+			let major = decoder.peek_major_type().unwrap();
+			let peeked = decoder.peek_event().unwrap();
+			assert_eq!(decoder.position(), position);
+			if let Event::TextString(t) = &peeked {
+				assert!(matches!(t, Cow::Borrowed(_)));
+			}
+			let event = decoder.next_event().unwrap();
+			assert_eq!(format!("{:?}", peeked), format!("{:?}", event));
+			assert_eq!(major, input[position as usize] >> 5);
+			if decoder.ready_to_finish() {
+				break;
+			}
+		}
+		assert!(matches!(
+			decoder.peek_event().map_err(DecodeError::into_kind),
+			Err(DecodeErrorKind::Insufficient)
+		));
+
+		let mut decoder = Decoder::new(Cursor::new(b"\x81\x63abc"));
+		decode_test!(match decoder: Ok(Event::Array(1)));
+		assert!(matches!(decoder.peek_event(), Ok(Event::TextString(t)) if t == "abc"));
 		assert!(!decoder.ready_to_finish());
+		decode_test!(match decoder: Ok(Event::TextString(t)) if t == "abc");
+		decoder.finish().unwrap();
+
+		// Peeking reports errors just like decoding, but doesn't consume anything.
+		let mut decoder = Decoder::from_slice(b"\x82\x00\xFF");
+		decode_test!(match decoder: Ok(Event::Array(2)));
+		decode_test!(match decoder: Ok(Event::Unsigned(0)));
+		let error = decoder.peek_event().unwrap_err();
+		assert_eq!(error.offset(), 2);
+		assert!(matches!(
+			error.kind(),
+			DecodeErrorKind::Malformed(MalformedKind::UnexpectedBreak)
+		));
+		assert_eq!(decoder.peek_major_type().unwrap(), 7);
+		assert_eq!(decoder.position(), 2);
 	}
 
 	#[test]
-	fn encode_bytes_segmented() {
-		encode_test!(
-			Event::UnknownLengthByteString,
-			Event::ByteString(Cow::Borrowed(b"abcd")),
-			Event::ByteString(Cow::Borrowed(b"efg")),
-			Event::Break
-			=> b"\x5F\x44abcd\x43efg\xFF",
-			check finish expecting matches!(event, Event::Break); event
-		);
+	fn decode_require_definite() {
+		for (input, major) in [
+			(&b"\x5F\x41\x00\xFF"[..], 2),
+			(b"\x7F\xFF", 3),
+			(b"\x9F\xFF", 4),
+			(b"\xBF\xFF", 5),
+		] {
+			let mut decoder = Decoder::from_slice(input);
+			decoder.set_require_definite(true);
+			decode_test!(match decoder: input => Err(DecodeErrorKind::NotDefinite(m)) if m == major);
+		}
+
+		// The error comes as soon as the header does, wherever it is.
+		let mut decoder = Decoder::from_slice(b"\x82\x00\x9F");
+		decoder.set_require_definite(true);
+		decode_test!(match decoder: Ok(Event::Array(2)));
+		decode_test!(match decoder: Ok(Event::Unsigned(0)));
+		let error = decoder.next_event().unwrap_err();
+		assert!(matches!(error.kind(), DecodeErrorKind::NotDefinite(4)));
+		assert_eq!(error.offset(), 2);
+
+		// Breaks are still malformed, not indefinite-length.
+		let mut decoder = Decoder::from_slice(b"\xFF");
+		decoder.set_require_definite(true);
+		decode_test!(match decoder: Err(DecodeErrorKind::Malformed(MalformedKind::UnexpectedBreak)));
+		let mut decoder = Decoder::from_slice(b"\x1F");
+		decoder.set_require_definite(true);
+		decode_test!(match decoder: Err(DecodeErrorKind::Malformed(MalformedKind::IndefiniteLength(0))));
 	}
 
 	#[test]
-	fn decode_text() {
-		decode_test!([0x60] => Ok(Event::TextString(x)) if x == "");
-		decode_test!(b"\x65Hello" => Ok(Event::TextString(x)) if x == "Hello");
-		decode_test!(b"\x78\x04Halo" => Ok(Event::TextString(x)) if x == "Halo");
-		decode_test!(b"\x79\x00\x07Goodbye" => Ok(Event::TextString(x)) if x == "Goodbye");
-		decode_test!(b"\x7A\x00\x00\x00\x0DLong message!" => Ok(Event::TextString(x)) if x == "Long message!");
-		decode_test!(b"\x7B\x00\x00\x00\x00\x00\x00\x00\x01?" => Ok(Event::TextString(x)) if x == "?");
+	fn decode_preferred() {
+		fn not_preferred(input: &[u8]) -> Option<(NotPreferredKind, u64)> {
+			let mut decoder = Decoder::from_slice(input);
+			decoder.set_require_preferred(true);
+			loop {
+				match decoder.next_event() {
+					Ok(_) if decoder.ready_to_finish() => return None,
+					Ok(_) => (),
+					Err(e) => match e.kind() {
+						DecodeErrorKind::NotPreferred(kind) => return Some((*kind, e.offset())),
+						_ => panic!("{:X?} -> {:?}", input, e),
+					},
+				}
+			}
+		}
+
+		for input in [
+			&b"\x17"[..],
+			b"\x18\x18",
+			b"\x39\x01\x00",
+			b"\x1A\x00\x01\x00\x00",
+			b"\x1B\x00\x00\x00\x01\x00\x00\x00\x00",
+			b"\x5F\x41a\xFF",
+			b"\xD8\x20\x00",
+			b"\xF8\x20",
+			b"\xF9\x3E\x00",
+			b"\xFA\x3F\x80\x00\x01",
+			b"\xFA\x7F\xC0\x00\x01",
+			b"\xFB\x3F\xF0\x00\x00\x00\x00\x00\x01",
+			b"\xFB\x7F\xF8\x00\x00\x00\x00\x00\x01",
+		] {
+			assert_eq!(not_preferred(input), None, "{:X?}", input);
+		}
+		assert_eq!(
+			not_preferred(b"\x1B\x00\x00\x00\x00\x00\x00\x00\x01"),
+			Some((NotPreferredKind::Integer(1), 0))
+		);
+		assert_eq!(
+			not_preferred(b"\x82\x00\x38\x17"),
+			Some((NotPreferredKind::Integer(23), 2))
+		);
+		assert_eq!(
+			not_preferred(b"\x79\x00\x01a"),
+			Some((NotPreferredKind::Length(1), 0))
+		);
+		assert_eq!(
+			not_preferred(b"\x5F\x58\x01a\xFF"),
+			Some((NotPreferredKind::Length(1), 1))
+		);
+		assert_eq!(
+			not_preferred(b"\xDA\x00\x00\x01\x00\x00"),
+			Some((NotPreferredKind::Tag(256), 0))
+		);
+		for input in [
+			&b"\xFA\x3F\x80\x00\x00"[..],
+			b"\xFA\x7F\xC0\x00\x00",
+			b"\xFB\x3F\xF0\x00\x00\x00\x00\x00\x00",
+			b"\xFB\x3F\xF0\x00\x00\x20\x00\x00\x00",
+			b"\xFB\x7F\xF0\x00\x00\x00\x00\x00\x00",
+			b"\xFB\x7F\xF8\x00\x00\x20\x00\x00\x00",
+		] {
+			assert_eq!(
+				not_preferred(input),
+				Some((NotPreferredKind::Float, 0)),
+				"{:X?}",
+				input
+			);
+		}
+		// None of this is checked by default.
+		let mut decoder = Decoder::from_slice(b"\x1B\x00\x00\x00\x00\x00\x00\x00\x01");
+		decode_test!(match decoder: Ok(Event::Unsigned(1)));
 	}
 
 	#[test]
-	fn decode_text_64bit_bounds() {
-		decode_test!(small b"\x7B\x00\x00\x00\x00\x00\x00\x00");
-		decode_test!(small b"\x7B\x00\x00\x00\x00\x00\x00\x00\x01");
+	fn decode_error_offset() {
+		let mut decoder = Decoder::from_slice(b"\x82\x00\x1C");
+		decoder.next_event().unwrap();
+		decoder.next_event().unwrap();
+		assert_eq!(decoder.position(), 2);
+		let error = decoder.next_event().unwrap_err();
+		assert!(matches!(
+			error.kind(),
+			DecodeErrorKind::Malformed(MalformedKind::ReservedAdditionalInfo(28))
+		));
+		assert_eq!(error.offset(), 2);
+		assert!(error.path().eq([&PathSegment::Index(1)]));
+		assert_eq!(
+			error.to_string(),
+			"malformed CBOR: reserved additional information 28 at byte 2 (in [1])"
+		);
+
+		let mut decoder = Decoder::new(Cursor::new(b"\x00\x62\xFF\xFF"));
+		decoder.next_event().unwrap();
+		let error = decoder.next_event().unwrap_err();
+		assert!(matches!(error.kind(), DecodeErrorKind::InvalidUtf8(_)));
+		assert_eq!(error.offset(), 1);
+		assert_eq!(error.path().len(), 0);
 	}
 
 	#[test]
-	fn encode_text() {
-		encode_test!(Event::TextString(Cow::Borrowed("")) => b"\x60");
-		encode_test!(Event::TextString(Cow::Borrowed("abcd")) => b"\x64abcd");
-
-		macro_rules! test {
-			($size:expr, $prefix:expr) => {
-				let size: usize = $size;
-				let prefix = $prefix;
-				let input = {
-					let mut it = Vec::with_capacity(size);
-					it.resize(size, b"A"[0]);
-					unsafe { String::from_utf8_unchecked(it) }
-				};
-				let mut output = Vec::with_capacity(size + prefix.len());
-				let mut encoder = Encoder::new(Cursor::new(&mut output));
-				encoder
-					.feed_event(Event::TextString(Cow::Borrowed(&input)))
-					.unwrap();
-				assert_eq!(output[..prefix.len()], prefix);
-				assert_eq!(output[prefix.len()..], input.into_bytes());
-			};
+	fn decode_error_path() {
+		fn path_of(input: &[u8], skip: usize) -> Vec<PathSegment> {
+			let mut decoder = Decoder::from_slice(input);
+			for _ in 0..skip {
+				decoder.next_event().unwrap();
+			}
+			let error = decoder.next_event().unwrap_err();
+			error.path().cloned().collect()
 		}
-
-		test!(0x30, [0x78, 0x30]);
-		test!(0x02FA, [0x79, 0x02, 0xFA]);
-		test!(0x010000, [0x7A, 0x00, 0x01, 0x00, 0x00]);
-		// Allocates about 8 GiB of memory! And iterates 4Gi times! Wowie wow wow!
-		test!(
-			2usize.pow(32),
-			[0x7B, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]
+		use PathSegment::*;
+		// The last item of an array, inside the value of the second pair of a map.
+		assert_eq!(
+			path_of(b"\xA2\x00\x00\x01\x82\x00\x1C", 6),
+			[Value(1, None), Index(1)]
 		);
+		// The key of the second pair of an unknown-length map, inside an unknown-length array.
+		assert_eq!(path_of(b"\x9F\x00\xBF\x00\x00\x1C", 5), [Index(1), Key(1)]);
+		// A piece of an unknown-length string, inside a tag, inside the value of an unknown-length map.
+		assert_eq!(path_of(b"\xBF\x00\xC0\x7F\x61\xFF", 4), [Value(0, None)]);
+		// Earlier containers at the same depth don't leak into the path.
+		assert_eq!(path_of(b"\x83\x81\x00\x80\x1C", 4), [Index(2)]);
+
+		// Peeking and skipping give the same path.
+		let mut decoder = Decoder::from_slice(b"\x82\x00\x81\x1C");
+		decoder.next_event().unwrap();
+		decoder.next_event().unwrap();
+		decoder.next_event().unwrap();
+		let error = decoder.peek_event().unwrap_err();
+		assert!(error.path().eq([&Index(1), &Index(0)]));
+		let error = decoder.skip_event().unwrap_err();
+		assert!(error.path().eq([&Index(1), &Index(0)]));
 	}
 
 	#[test]
-	fn decode_text_segmented() {
-		let mut decoder = Decoder::new(Cursor::new(b"\x7F\x64abcd\x63efg\xFF"));
-		decode_test!(match decoder: Ok(Event::UnknownLengthTextString));
-		assert!(!decoder.ready_to_finish());
-		decode_test!(match decoder: Ok(Event::TextString(x)) if x == "abcd");
-		assert!(!decoder.ready_to_finish());
-		decode_test!(match decoder: Ok(Event::TextString(x)) if x == "efg");
-		assert!(!decoder.ready_to_finish());
-		decode_test!(match decoder: Ok(Event::Break));
-		assert!(decoder.ready_to_finish());
+	fn decode_hostile() {
+		// Lengths that overflow usize when the header is added to them.
+		for initial in [0x5B, 0x7B] {
+			let mut input = vec![initial];
+			input.extend_from_slice(&[0xFF; 8]);
+			let mut decoder = Decoder::from_slice(&input);
+			decode_test!(match decoder: Err(DecodeErrorKind::StringTooLong));
+			let mut decoder = Decoder::new(Cursor::new(&input));
+			decode_test!(match decoder: Err(DecodeErrorKind::StringTooLong));
+		}
+
+		for input in [
+			&b"\x9B\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\x00"[..],
+			&b"\xBB\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\x00\x00"[..],
+			&[0x81; 100_000][..],
+			&[0x5F; 100_000][..],
+			&[0xFF; 100][..],
+		] {
+			decode_everything(input);
+		}
 	}
 
 	#[test]
-	fn decode_text_segmented_small() {
-		let mut decoder = Decoder::new(Cursor::new(b"\x7F\x64abcd"));
-		decode_test!(match decoder: Ok(Event::UnknownLengthTextString));
-		decode_test!(match decoder: Ok(Event::TextString(x)) if x == "abcd");
-		decode_test!(match decoder: Err(DecodeError::Insufficient));
-		assert!(!decoder.ready_to_finish());
+	fn decode_fuzz() {
+		for input in fuzz_inputs(20_000) {
+			decode_everything(&input);
+		}
 	}
 
 	#[test]
 	fn decode_text_invalid() {
 		let mut decoder = Decoder::new(Cursor::new(b"\x62\xFF\xFF"));
-		match decoder.next_event() {
-			Err(DecodeError::InvalidUtf8(_)) => (),
+		match decoder.next_event().map_err(DecodeError::into_kind) {
+			Err(DecodeErrorKind::InvalidUtf8(_)) => (),
+			_ => panic!("accepted invalid UTF-8"),
+		}
+		let mut decoder = Decoder::from_slice(b"\x62\xFF\xFF");
+		match decoder.next_event().map_err(DecodeError::into_kind) {
+			Err(DecodeErrorKind::InvalidUtf8(_)) => (),
 			_ => panic!("accepted invalid UTF-8"),
 		}
+
+		// Nothing is consumed, so the decoder stays at the start of the string.
+		let mut decoder = Decoder::new(Cursor::new(b"\x81\x62\xFF\xFF"));
+		decode_test!(match decoder: Ok(Event::Array(1)));
+		for _ in 0..2 {
+			let error = decoder.next_event().unwrap_err();
+			assert!(matches!(error.kind(), DecodeErrorKind::InvalidUtf8(_)));
+			assert_eq!(error.offset(), 1);
+			assert_eq!(decoder.position(), 1);
+			assert!(!decoder.ready_to_finish());
+		}
 	}
 
 	#[test]
@@ -1019,6 +4156,32 @@ mod test {
 		assert!(decoder.ready_to_finish());
 	}
 
+	#[test]
+	fn encode_finish() {
+		let mut encoder = Encoder::new(Vec::new());
+		encoder.feed_event(Event::Array(2)).unwrap();
+		encoder.feed_event(Event::Unsigned(1)).unwrap();
+		assert!(matches!(
+			encoder.clone().finish(),
+			Err(EncodeError::Insufficient)
+		));
+		assert_eq!(encoder.clone().force_finish(), b"\x82\x01");
+		encoder.feed_event(Event::Unsigned(2)).unwrap();
+		// An item more than the array declared is rejected, not written after it.
+		assert!(matches!(
+			encoder.feed_event(Event::Unsigned(3)),
+			Err(EncodeError::Excess)
+		));
+		assert_eq!(encoder.finish().unwrap(), b"\x82\x01\x02");
+
+		let mut encoder = Encoder::new_sequence(Vec::new());
+		encoder.feed_event(Event::Array(1)).unwrap();
+		encoder.feed_event(Event::Unsigned(1)).unwrap();
+		assert!(encoder.ready_to_finish());
+		encoder.feed_event(Event::Unsigned(2)).unwrap();
+		assert_eq!(encoder.finish().unwrap(), b"\x81\x01\x02");
+	}
+
 	#[test]
 	fn encode_array() {
 		encode_test!(
@@ -1166,45 +4329,152 @@ mod test {
 
 	#[test]
 	fn decode_simple_8bit() {
-		for n in 24..=255 {
+		for n in 32..=255 {
 			decode_test!([0xF8, n] => Ok(Event::Simple(x)) if x == n);
 		}
 	}
 
+	#[test]
+	fn decode_malformed() {
+		macro_rules! malformed_test {
+			($in:expr => $kind:pat if $cond:expr) => {
+				let input = $in;
+				let mut decoder = Decoder::from_slice(&input);
+				// The malformed part might come after some events that are fine.
+				let error = loop {
+					if let Err(e) = decoder.next_event() {
+						break e.into_kind();
+					}
+				};
+				match error {
+					DecodeErrorKind::Malformed($kind) if $cond => (),
+					other => panic!("{:X?} -> {:?}", input, other),
+				}
+			};
+			($in:expr => $kind:pat) => {
+				malformed_test!($in => $kind if true);
+			};
+		}
+
+		for n in 0..=31 {
+			malformed_test!([0xF8, n] => MalformedKind::InvalidSimple(x) if x == n);
+		}
+		for n in 28..=30 {
+			for major in 0..=7 {
+				malformed_test!([major << 5 | n] => MalformedKind::ReservedAdditionalInfo(x) if x == n);
+			}
+		}
+		for major in [0, 1, 6] {
+			malformed_test!([major << 5 | 31] => MalformedKind::IndefiniteLength(x) if x == major);
+		}
+		malformed_test!([0xFF] => MalformedKind::UnexpectedBreak);
+		malformed_test!([0x81, 0xFF] => MalformedKind::UnexpectedBreak);
+		malformed_test!([0x9F, 0x81, 0xFF] => MalformedKind::UnexpectedBreak);
+		malformed_test!([0xBF, 0x00, 0xFF] => MalformedKind::UnexpectedBreak);
+	}
+
 	#[test]
 	fn encode_simple_8bit() {
-		for n in 24..=255 {
+		for n in 32..=255 {
 			encode_test!(Event::Simple(n) => [0xF8, n]);
 		}
 	}
 
+	#[test]
+	fn encode_simple_reserved() {
+		for n in 24..=31 {
+			let mut encoder = Encoder::new(Vec::new());
+			assert!(matches!(
+				encoder.feed_event(Event::Simple(n)),
+				Err(EncodeError::InvalidSimple(m)) if m == n
+			));
+			assert!(matches!(
+				encoder.feed_event_detailed(Event::Simple(n), ArgumentWidth::U8),
+				Err(EncodeError::InvalidSimple(m)) if m == n
+			));
+			assert!(encoder.force_finish().is_empty());
+		}
+	}
+
 	#[test]
 	fn decode_float_64bit() {
-		decode_test!(b"\xFB\x7F\xF0\x00\x00\x00\x00\x00\x00" => Ok(Event::Float(n)) if n == f64::INFINITY);
+		decode_test!(b"\xFB\x7F\xF0\x00\x00\x00\x00\x00\x00" => Ok(Event::Float64(n)) if n == f64::INFINITY);
 	}
 
 	#[test]
 	fn encode_float_64bit() {
-		encode_test!(Event::Float(1.0000000000000002f64) => b"\xFB\x3F\xF0\x00\x00\x00\x00\x00\x01");
+		encode_test!(Event::Float64(1.0000000000000002f64) => b"\xFB\x3F\xF0\x00\x00\x00\x00\x00\x01");
 	}
 
 	#[test]
 	fn decode_float_32bit() {
-		decode_test!(b"\xFA\x3F\x80\x00\x00" => Ok(Event::Float(n)) if n == 1.0);
+		decode_test!(b"\xFA\x3F\x80\x00\x00" => Ok(Event::Float32(n)) if n == 1.0);
 	}
 
 	#[test]
 	fn encode_float_32bit() {
-		encode_test!(Event::Float(0.999999940395355225f32 as f64) => b"\xFA\x3F\x7F\xFF\xFF");
+		encode_test!(Event::Float32(0.999_999_94_f32) => b"\xFA\x3F\x7F\xFF\xFF");
 	}
 
 	#[test]
 	fn decode_float_16bit() {
-		decode_test!(b"\xF9\x00\x00" => Ok(Event::Float(n)) if n == 0.0);
+		decode_test!(b"\xF9\x00\x00" => Ok(Event::Float16(n)) if n == f16::ZERO);
 	}
 
 	#[test]
 	fn encode_float_16bit() {
-		encode_test!(Event::Float(f64::INFINITY) => b"\xF9\x7C\x00");
+		encode_test!(Event::Float16(f16::INFINITY) => b"\xF9\x7C\x00");
+	}
+
+	#[test]
+	fn float_nan_payload() {
+		// Signalling NaNs, with payloads, which must come back out exactly.
+		for input in [
+			&b"\xF9\x7C\x01"[..],
+			b"\xF9\xFD\x55",
+			b"\xFA\x7F\x80\x00\x01",
+			b"\xFB\x7F\xF0\x00\x00\x00\x00\x00\x01",
+		] {
+			let mut decoder = Decoder::from_slice(input);
+			let event = decoder.next_event().unwrap();
+			let mut output = Vec::new();
+			Encoder::new(&mut output).feed_event(event.clone()).unwrap();
+			assert_eq!(output, input);
+			// Widening and narrowing again doesn't lose anything either.
+			let widened = event.as_f64().unwrap();
+			assert!(widened.is_nan());
+			let mut output = Vec::new();
+			Encoder::new(&mut output)
+				.feed_event(Event::create_float(widened))
+				.unwrap();
+			assert_eq!(output, input);
+		}
+	}
+
+	#[test]
+	fn create_float() {
+		for (val, output) in [
+			(0.0, &b"\xF9\x00\x00"[..]),
+			(-0.0, b"\xF9\x80\x00"),
+			(1.5, b"\xF9\x3E\x00"),
+			(100_000.0, b"\xFA\x47\xC3\x50\x00"),
+			(1.1, b"\xFB\x3F\xF1\x99\x99\x99\x99\x99\x9A"),
+			(f64::NEG_INFINITY, b"\xF9\xFC\x00"),
+			(f64::NAN, b"\xF9\x7E\x00"),
+			(
+				f64::from_bits(0x7FF8_0000_2000_0000),
+				b"\xFA\x7F\xC0\x00\x01",
+			),
+			(
+				f64::from_bits(0x7FF8_0000_0000_0001),
+				b"\xFB\x7F\xF8\x00\x00\x00\x00\x00\x01",
+			),
+		] {
+			let mut encoded = Vec::new();
+			Encoder::new(&mut encoded)
+				.feed_event(Event::create_float(val))
+				.unwrap();
+			assert_eq!(encoded, output, "{}", val);
+		}
 	}
 }
@@ -6,11 +6,19 @@
 //! It is comparable to DOM in the XML world.
 
 use crate::{
-	basic::streaming::{Decoder as StreamingDecoder, Encoder as StreamingEncoder, Event},
-	errors::{DecodeError, EncodeError},
+	basic::streaming::{
+		DecodeLimits, Decoder as StreamingDecoder, Encoder as StreamingEncoder, Event, ReadSource,
+		SliceSource, Source,
+	},
+	errors::{DecodeError, DecodeErrorKind, EncodeError, MalformedKind, PathSegment},
 };
+use half::f16;
 use std::{
 	borrow::Cow,
+	cmp::Ordering,
+	collections::{hash_map::RandomState, HashMap},
+	fmt,
+	hash::{BuildHasher, Hash, Hasher},
 	io::{Read, Write},
 };
 
@@ -25,8 +33,14 @@ pub enum Item {
 	/// Some integers that can be CBOR encoded underflow [`i64`].
 	/// Use one of the `interpret_signed` associated functions to resolve this.
 	Signed(u64),
-	/// A floating-point number.
-	Float(f64),
+	/// A half-precision floating-point number.
+	///
+	/// Floats keep the width and exact bits they were encoded with.
+	Float16(f16),
+	/// A single-precision floating-point number.
+	Float32(f32),
+	/// A double-precision floating-point number.
+	Float64(f64),
 	/// A byte string.
 	ByteString(Vec<u8>),
 	/// A text string.
@@ -89,68 +103,529 @@ impl Item {
 			None => None,
 		}
 	}
+
+	/// Get the value of a float, of any width, as an [`f64`].
+	///
+	/// This is a convenience alias for [`super::streaming::Event::as_f64`].
+	pub fn as_f64(&self) -> Option<f64> {
+		match *self {
+			Self::Float16(f) => Event::Float16(f).as_f64(),
+			Self::Float32(f) => Event::Float32(f).as_f64(),
+			Self::Float64(f) => Some(f),
+			_ => None,
+		}
+	}
+
+	/// Create a float item of the narrowest width that holds the value exactly.
+	///
+	/// This is a convenience alias for [`super::streaming::Event::create_float`],
+	/// except that it returns an [`Item`] instead.
+	pub fn create_float(val: f64) -> Item {
+		match Event::create_float(val) {
+			Event::Float16(f) => Self::Float16(f),
+			Event::Float32(f) => Self::Float32(f),
+			Event::Float64(f) => Self::Float64(f),
+			_ => unreachable!(),
+		}
+	}
+
+	/// Check whether two items are the same in the CBOR data model.
+	///
+	/// This is how map keys are compared to find duplicates,
+	/// following [RFC 8949 section 5.6.1](https://www.rfc-editor.org/rfc/rfc8949.html#section-5.6.1).
+	/// It's stricter than `==` in some ways and looser in others:
+	/// floats are the same whatever their width, `-0.0` is the same as `0.0`, NaNs are the same if their payloads are,
+	/// and maps are the same if they have the same pairs, in any order.
+	pub fn data_model_eq(&self, other: &Item) -> bool {
+		if let (Some(a), Some(b)) = (self.as_f64(), other.as_f64()) {
+			return float_key(a) == float_key(b);
+		}
+		match (self, other) {
+			(Item::Array(a), Item::Array(b)) => {
+				a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.data_model_eq(b))
+			}
+			(Item::Map(a), Item::Map(b)) => maps_eq(a, b),
+			(Item::Tag(tag_a, a), Item::Tag(tag_b, b)) => tag_a == tag_b && a.data_model_eq(b),
+			(a, b) => a == b,
+		}
+	}
+
+	/// Put the item, and everything in it, in the form that [deterministic encoding](`Determinism::Core`) gives.
+	///
+	/// Floats are narrowed to the shortest width that holds them exactly,
+	/// and the pairs of maps are sorted by the encodings of their keys.
+	/// Afterwards, any encoder writes the item the same way a deterministic one would.
+	pub fn canonicalize(&mut self) {
+		self.canonicalize_as(Determinism::Core);
+	}
+
+	/// Put the item, and everything in it, in the form that [RFC 7049 canonical encoding](`Determinism::LengthFirst`) gives.
+	///
+	/// This is the same as [`Self::canonicalize`], except for the order of map pairs.
+	pub fn canonicalize_length_first(&mut self) {
+		self.canonicalize_as(Determinism::LengthFirst);
+	}
+
+	fn canonicalize_as(&mut self, determinism: Determinism) {
+		match self {
+			Item::Float16(_) | Item::Float32(_) | Item::Float64(_) => {
+				*self = Item::create_float(self.as_f64().unwrap());
+			}
+			Item::Array(items) => {
+				for item in items {
+					item.canonicalize_as(determinism);
+				}
+			}
+			Item::Map(pairs) => {
+				for (key, val) in pairs.iter_mut() {
+					key.canonicalize_as(determinism);
+					val.canonicalize_as(determinism);
+				}
+				// The keys are canonical now, so a plain encoder gives their deterministic encodings.
+				let encoder = Encoder::new();
+				*pairs = sort_pairs(std::mem::take(pairs), determinism, |(key, _)| {
+					let mut out = Vec::new();
+					let _ = encoder.encode(key, &mut out);
+					out
+				});
+			}
+			Item::Tag(_, item) => item.canonicalize_as(determinism),
+			_ => (),
+		}
+	}
 }
 
-/// A tree-building decoder for the CBOR basic data model.
+impl DataModel for Item {
+	fn data_model_eq(&self, other: &Self) -> bool {
+		self.data_model_eq(other)
+	}
+
+	fn data_model_hash(&self, state: &mut impl Hasher) {
+		// Floats of different widths can be the same, so they're all hashed as f64s.
+		if let Some(f) = self.as_f64() {
+			float_key(f).hash(state);
+			return;
+		}
+		std::mem::discriminant(self).hash(state);
+		match self {
+			Item::Unsigned(n) | Item::Signed(n) => n.hash(state),
+			Item::Float16(_) | Item::Float32(_) | Item::Float64(_) => (),
+			Item::ByteString(b) => b.hash(state),
+			Item::TextString(t) => t.hash(state),
+			Item::Array(a) => {
+				a.len().hash(state);
+				for item in a {
+					item.data_model_hash(state);
+				}
+			}
+			Item::Map(m) => hash_map_pairs(m, state),
+			Item::Tag(tag, item) => {
+				tag.hash(state);
+				item.data_model_hash(state);
+			}
+			Item::Simple(n) => n.hash(state),
+		}
+	}
+}
+
+/// Equality and hashing in the CBOR data model, which are needed to find duplicate map keys.
+pub(crate) trait DataModel {
+	fn data_model_eq(&self, other: &Self) -> bool;
+
+	/// Hash the item so that items that are [the same](`Self::data_model_eq`) hash the same.
+	fn data_model_hash(&self, state: &mut impl Hasher);
+}
+
+/// Turn a float into something that's equal for floats that are the same in the CBOR data model.
+pub(crate) fn float_key(f: f64) -> (bool, u64) {
+	if f.is_nan() {
+		// Only the payload matters, not the sign.
+		(true, f.to_bits() & 0x000F_FFFF_FFFF_FFFF)
+	} else if f == 0.0 {
+		(false, 0)
+	} else {
+		(false, f.to_bits())
+	}
+}
+
+/// Hash the pairs of a map so that the order they're in doesn't matter.
+pub(crate) fn hash_map_pairs<T: DataModel>(pairs: &[(T, T)], state: &mut impl Hasher) {
+	pairs.len().hash(state);
+	let sum = pairs.iter().fold(0u64, |sum, (key, value)| {
+		let mut hasher = std::collections::hash_map::DefaultHasher::new();
+		key.data_model_hash(&mut hasher);
+		value.data_model_hash(&mut hasher);
+		sum.wrapping_add(hasher.finish())
+	});
+	sum.hash(state);
+}
+
+/// Check whether two maps have the same pairs, in any order.
+pub(crate) fn maps_eq<T: DataModel>(a: &[(T, T)], b: &[(T, T)]) -> bool {
+	if a.len() != b.len() {
+		return false;
+	}
+	let state = RandomState::new();
+	let hash = |key: &T| {
+		let mut hasher = state.build_hasher();
+		key.data_model_hash(&mut hasher);
+		hasher.finish()
+	};
+	// Each pair in `b` can only be matched once, in case there are duplicates.
+	let mut unmatched: HashMap<u64, Vec<usize>> = HashMap::new();
+	for (index, (key, _)) in b.iter().enumerate() {
+		unmatched.entry(hash(key)).or_default().push(index);
+	}
+	a.iter().all(|(key, value)| {
+		let bucket = unmatched.entry(hash(key)).or_default();
+		let found = bucket
+			.iter()
+			.position(|&index| b[index].0.data_model_eq(key) && b[index].1.data_model_eq(value));
+		match found {
+			Some(position) => {
+				bucket.swap_remove(position);
+				true
+			}
+			None => false,
+		}
+	})
+}
+
+/// What a tree decoder does when a map has the same key more than once.
+///
+/// Keys are compared by [data-model equality](`Item::data_model_eq`),
+/// so, for instance, `0x01` and `0x18 0x01` are the same key, but `1` and `1.0` aren't.
+/// [RFC 8949](https://www.rfc-editor.org/rfc/rfc8949.html#section-5.6) says such maps aren't valid,
+/// but since checking takes time and memory, the default is to [allow](`Self::Allow`) them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum DuplicateKeyPolicy {
+	/// Keep every pair, duplicates and all.
+	#[default]
+	Allow,
+	/// Fail with [`DecodeErrorKind::DuplicateKey`].
+	Reject,
+	/// Keep the first pair with each key, and drop any later ones.
+	KeepFirst,
+	/// Keep the last pair with each key, in the place of the first one.
+	KeepLast,
+}
+
+/// Whether a tree encoder makes its output deterministic, and how.
+///
+/// Tree encoders always write arguments as short as possible, and never use indefinite lengths.
+/// Beyond that, they normally write floats in the width they have and map pairs in the order they're in,
+/// so the same data model value can be written in more than one way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum Determinism {
+	/// Write floats and maps as they are.
+	#[default]
+	Off,
+	/// Core Deterministic Encoding, from [RFC 8949 section 4.2.1](https://www.rfc-editor.org/rfc/rfc8949.html#section-4.2.1).
+	///
+	/// Floats are written in the shortest width that holds them exactly,
+	/// and map pairs are sorted by the bytewise lexicographic order of the encodings of their keys.
+	Core,
+	/// Canonical CBOR, from [RFC 7049 section 3.9](https://www.rfc-editor.org/rfc/rfc7049.html#section-3.9),
+	/// which some older protocols still use.
+	///
+	/// This is the same as [`Self::Core`], except that map pairs are sorted by the lengths of the encodings of their keys first,
+	/// so shorter keys always come before longer ones.
+	/// Only keys whose encodings are the same length are compared bytewise.
+	LengthFirst,
+}
+
+impl Determinism {
+	/// Compare the encodings of two map keys, to decide which one is written first.
+	pub(crate) fn compare_keys(self, a: &[u8], b: &[u8]) -> Ordering {
+		match self {
+			Determinism::Off => Ordering::Equal,
+			Determinism::Core => a.cmp(b),
+			Determinism::LengthFirst => a.len().cmp(&b.len()).then_with(|| a.cmp(b)),
+		}
+	}
+}
+
+/// Sort the pairs of a map into the order that `determinism` calls for.
+///
+/// Each key is only encoded once.
+/// The sort is stable, so pairs with the same key stay in the order they were in.
+pub(crate) fn sort_pairs<P>(
+	pairs: Vec<P>,
+	determinism: Determinism,
+	mut encode_key: impl FnMut(&P) -> Vec<u8>,
+) -> Vec<P> {
+	let mut keyed: Vec<_> = pairs
+		.into_iter()
+		.map(|pair| (encode_key(&pair), pair))
+		.collect();
+	keyed.sort_by(|(a, _), (b, _)| determinism.compare_keys(a, b));
+	keyed.into_iter().map(|(_, pair)| pair).collect()
+}
+
+/// Keeps track of where the keys of a map being decoded are, to find duplicates.
 #[derive(Debug, Clone, Default)]
-pub struct Decoder {}
+pub(crate) struct KeyIndex {
+	state: RandomState,
+	/// The indices of the keys with each hash.
+	buckets: HashMap<u64, Vec<usize>>,
+}
+
+impl KeyIndex {
+	/// Find the index of a key that's the same as `key`, out of the `len` keys that `key_at` looks up,
+	/// or if there isn't one, remember that `key` is about to be added to the end of them.
+	pub(crate) fn find_or_add<'a, K: DataModel + 'a>(
+		&mut self,
+		len: usize,
+		key_at: impl Fn(usize) -> &'a K,
+		key: &K,
+	) -> Option<usize> {
+		let mut hasher = self.state.build_hasher();
+		key.data_model_hash(&mut hasher);
+		let bucket = self.buckets.entry(hasher.finish()).or_default();
+		match bucket
+			.iter()
+			.find(|&&index| key_at(index).data_model_eq(key))
+		{
+			Some(&index) => Some(index),
+			None => {
+				bucket.push(len);
+				None
+			}
+		}
+	}
+
+	/// Add a pair to a map that's being decoded, following a duplicate key policy.
+	///
+	/// If that means rejecting it, the error needs to be given an offset and path.
+	pub(crate) fn add_pair<K: DataModel, V>(
+		&mut self,
+		pairs: &mut Vec<(K, V)>,
+		pair: (K, V),
+		policy: DuplicateKeyPolicy,
+	) -> Result<(), DecodeErrorKind> {
+		if policy == DuplicateKeyPolicy::Allow {
+			pairs.push(pair);
+			return Ok(());
+		}
+		match (
+			self.find_or_add(pairs.len(), |index| &pairs[index].0, &pair.0),
+			policy,
+		) {
+			(None, _) | (Some(_), DuplicateKeyPolicy::Allow) => pairs.push(pair),
+			(Some(_), DuplicateKeyPolicy::Reject) => return Err(DecodeErrorKind::DuplicateKey),
+			(Some(_), DuplicateKeyPolicy::KeepFirst) => (),
+			(Some(index), DuplicateKeyPolicy::KeepLast) => pairs[index] = pair,
+		}
+		Ok(())
+	}
+}
+
+/// Make a [`Vec`] for the contents of an array or map.
+///
+/// The length comes from the input, which may be lying about it,
+/// so only a modest amount of space is allocated up front.
+/// If the items really are there, the [`Vec`] will grow to fit them as usual.
+pub(crate) fn preallocate<T>(len: u64) -> Vec<T> {
+	Vec::with_capacity(len.min(1024) as usize)
+}
+
+/// How deeply a tree decoder lets items nest by default.
+pub(crate) const DEFAULT_MAX_DEPTH: usize = 256;
+
+/// A tree-building decoder for the CBOR basic data model.
+///
+/// Decoding recurses once for each level of nesting,
+/// so by default, the [limits](`Self::limits`) only allow items to be nested 256 deep.
+/// This keeps hostile input from overflowing the stack.
+/// Raise the limit with care.
+///
+/// Maps with duplicate keys are decoded as they are, unless a different [policy](`Self::duplicate_keys`) is set.
+/// Indefinite-length items are accepted unless [`Self::set_require_definite`] is used,
+/// which works the same way as [for streaming decoders](`StreamingDecoder::set_require_definite`).
+#[derive(Debug, Clone)]
+pub struct Decoder {
+	limits: DecodeLimits,
+	duplicate_keys: DuplicateKeyPolicy,
+	require_definite: bool,
+}
+
+impl Default for Decoder {
+	fn default() -> Self {
+		let mut limits = DecodeLimits::new();
+		limits.set_max_depth(Some(DEFAULT_MAX_DEPTH));
+		Self {
+			limits,
+			duplicate_keys: Default::default(),
+			require_definite: false,
+		}
+	}
+}
 
 impl Decoder {
 	pub fn new() -> Self {
 		Default::default()
 	}
 
+	config_accessors!(limits, DecodeLimits, limits, limits_mut, set_limits);
+
+	config_accessors!(
+		duplicate_keys,
+		DuplicateKeyPolicy,
+		duplicate_keys,
+		duplicate_keys_mut,
+		set_duplicate_keys
+	);
+
+	config_accessors!(
+		require_definite,
+		bool,
+		require_definite,
+		require_definite_mut,
+		set_require_definite
+	);
+
+	/// Set up a streaming decoder with this decoder's limits and settings.
+	fn configure<'de, S: Source<'de>>(&self, decoder: &mut StreamingDecoder<S>) {
+		decoder.set_limits(self.limits.clone());
+		decoder.set_require_definite(self.require_definite);
+	}
+
 	/// Parse some CBOR.
 	///
 	/// This is just a shortcut for [`Self::decode_from_stream`]
 	/// which constructs the [`streaming::Decoder`](`StreamingDecoder`) for you
-	/// and converts [`None`]s into [`DecodeError::Malformed`]s.
+	/// and converts [`None`]s into [`MalformedKind::UnexpectedBreak`]s.
+	/// The streaming decoder uses this decoder's [limits](`Self::limits`) and settings.
 	pub fn decode(self, source: impl Read) -> Result<Item, DecodeError> {
-		match self.decode_from_stream(&mut StreamingDecoder::new(source)) {
+		let mut decoder = StreamingDecoder::new(source);
+		self.configure(&mut decoder);
+		match self.decode_from_stream(&mut decoder) {
 			Ok(Some(item)) => Ok(item),
-			Ok(None) => Err(DecodeError::Malformed),
+			Ok(None) => Err(DecodeError::new(MalformedKind::UnexpectedBreak.into(), 0)),
 			Err(e) => Err(e),
 		}
 	}
 
+	/// Parse some CBOR from a byte slice.
+	///
+	/// This is like [`Self::decode`], but it uses a [slice-backed](`StreamingDecoder::from_slice`) streaming decoder,
+	/// so the contents of strings are only copied once, into the resulting [`Item`].
+	pub fn decode_slice(self, input: &[u8]) -> Result<Item, DecodeError> {
+		let mut decoder = StreamingDecoder::from_slice(input);
+		self.configure(&mut decoder);
+		match self.decode_from_stream(&mut decoder) {
+			Ok(Some(item)) => Ok(item),
+			Ok(None) => Err(DecodeError::new(MalformedKind::UnexpectedBreak.into(), 0)),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Parse a CBOR Sequence ([RFC 8742](https://www.rfc-editor.org/rfc/rfc8742.html)): any number of items, one after another.
+	///
+	/// This returns an iterator over the items, which ends when the input does.
+	/// The streaming decoder uses this decoder's [limits](`Self::limits`) and settings.
+	pub fn decode_sequence<T: Read>(self, source: T) -> SequenceDecoder<ReadSource<T>> {
+		let mut decoder = StreamingDecoder::new(source);
+		self.configure(&mut decoder);
+		self.decode_sequence_from_stream(decoder)
+	}
+
+	/// Parse a CBOR Sequence from a byte slice.
+	///
+	/// This is like [`Self::decode_sequence`], but it uses a [slice-backed](`StreamingDecoder::from_slice`) streaming decoder.
+	pub fn decode_sequence_slice(self, input: &[u8]) -> SequenceDecoder<SliceSource<'_>> {
+		let mut decoder = StreamingDecoder::from_slice(input);
+		self.configure(&mut decoder);
+		self.decode_sequence_from_stream(decoder)
+	}
+
+	/// Parse a CBOR Sequence, carrying on past any items that are damaged.
+	///
+	/// This is like [`Self::decode_sequence`], but after returning an error, the iterator doesn't stop.
+	/// Instead, it drops the damaged item and looks for the next place an item could start; see [`ResyncDecoder`].
+	pub fn decode_sequence_resync<T: Read>(self, source: T) -> ResyncDecoder<T> {
+		ResyncDecoder {
+			tree: self,
+			input: ResyncInput::new(source),
+			plausible: Box::new(|_| true),
+		}
+	}
+
+	/// Parse a CBOR Sequence from a provided streaming decoder.
+	///
+	/// This uses the streaming decoder's limits and settings, not this decoder's,
+	/// except that this decoder's depth limit applies as well; see [`Self::decode_from_stream`].
+	pub fn decode_sequence_from_stream<'de, S: Source<'de>>(
+		self,
+		decoder: StreamingDecoder<S>,
+	) -> SequenceDecoder<S> {
+		SequenceDecoder {
+			tree: self,
+			decoder,
+			failed: false,
+		}
+	}
+
 	/// Parse some CBOR from a provided streaming decoder.
 	///
+	/// This uses the streaming decoder's limits and settings, not this decoder's,
+	/// except that this decoder's depth limit applies as well, since decoding recurses once for each level of nesting.
+	/// The depth counts from the top level of the input, not from wherever the streaming decoder has got to.
+	///
 	/// If this returns `Ok(None)`, it means that the first thing it encountered was a break (`0xFF`).
 	/// This may or may not be acceptable depending on the situation,
 	/// so `decode_from_stream` doesn't count it as a failure.
-	pub fn decode_from_stream(
+	pub fn decode_from_stream<'de>(
 		&self,
-		decoder: &mut StreamingDecoder<impl Read>,
+		decoder: &mut StreamingDecoder<impl Source<'de>>,
 	) -> Result<Option<Item>, DecodeError> {
-		Ok(Some(match decoder.next_event()? {
+		let offset = decoder.position();
+		let event = decoder.next_event().map_err(DecodeError::without_path)?;
+		// Decoding recurses once for each level of nesting, so this decoder's depth limit applies,
+		// even if the streaming decoder's own limits are looser.
+		if matches!(*self.limits.max_depth(), Some(max) if decoder.depth() > max) {
+			return Err(DecodeError::new(DecodeErrorKind::TooDeep, offset));
+		}
+		Ok(Some(match event {
 			Event::Unsigned(val) => Item::Unsigned(val),
 			Event::Signed(val) => Item::Signed(val),
 			Event::ByteString(val) => Item::ByteString(val.into_owned()),
 			Event::UnknownLengthByteString => {
 				let mut buffer: Vec<u8>;
-				match decoder.next_event()? {
+				let offset = decoder.position();
+				match decoder.next_event().map_err(DecodeError::without_path)? {
 					Event::ByteString(b) => buffer = b.into_owned(),
 					Event::Break => return Ok(Some(Item::ByteString(b"".to_vec()))),
-					_ => return Err(DecodeError::Malformed),
+					_ => return Err(DecodeError::new(MalformedKind::InvalidChunk.into(), offset)),
 				}
 				loop {
-					match decoder.next_event()? {
+					let offset = decoder.position();
+					match decoder.next_event().map_err(DecodeError::without_path)? {
 						Event::ByteString(b) => buffer.extend_from_slice(&b),
 						Event::Break => return Ok(Some(Item::ByteString(buffer))),
-						_ => return Err(DecodeError::Malformed),
+						_ => {
+							return Err(DecodeError::new(
+								MalformedKind::InvalidChunk.into(),
+								offset,
+							))
+						}
 					}
 				}
 			}
 			Event::TextString(val) => Item::TextString(val.into_owned()),
 			Event::UnknownLengthTextString => {
 				let mut buffer: String;
-				match decoder.next_event()? {
+				let offset = decoder.position();
+				match decoder.next_event().map_err(DecodeError::without_path)? {
 					Event::TextString(b) => buffer = b.into_owned(),
 					Event::Break => return Ok(Some(Item::TextString("".to_owned()))),
-					_ => return Err(DecodeError::Malformed),
+					_ => return Err(DecodeError::new(MalformedKind::InvalidChunk.into(), offset)),
 				}
 				loop {
-					match decoder.next_event()? {
+					let offset = decoder.position();
+					match decoder.next_event().map_err(DecodeError::without_path)? {
 						Event::TextString(b) => {
 							let mut buffer2 = buffer.into_bytes();
 							buffer2.extend_from_slice(b.as_bytes());
@@ -159,87 +634,399 @@ impl Decoder {
 							buffer = unsafe { String::from_utf8_unchecked(buffer2) };
 						}
 						Event::Break => return Ok(Some(Item::TextString(buffer))),
-						_ => return Err(DecodeError::Malformed),
+						_ => {
+							return Err(DecodeError::new(
+								MalformedKind::InvalidChunk.into(),
+								offset,
+							))
+						}
 					}
 				}
 			}
 			Event::Array(len) => {
-				let mut arr = Vec::with_capacity(len.try_into().unwrap_or(usize::MAX));
-				for _ in 0..len {
-					match self.decode_from_stream(decoder)? {
-						None => return Err(DecodeError::Malformed),
-						Some(item) => arr.push(item),
-					}
+				let mut arr = preallocate(len);
+				for index in 0..len {
+					arr.push(self.decode_required(decoder, || PathSegment::Index(index))?);
 				}
 				assert_eq!(arr.len(), len as _);
 				Item::Array(arr)
 			}
 			Event::UnknownLengthArray => {
 				let mut arr = Vec::new();
-				loop {
-					match self.decode_from_stream(decoder)? {
+				for index in 0.. {
+					match self.decode_nested(decoder, || PathSegment::Index(index))? {
 						None => break,
 						Some(item) => arr.push(item),
 					}
 				}
 				Item::Array(arr)
 			}
-			Event::Map(len) => {
-				let mut map = Vec::with_capacity(len.try_into().unwrap_or(usize::MAX));
-				for _ in 0..len {
-					let key = match self.decode_from_stream(decoder)? {
-						None => return Err(DecodeError::Malformed),
-						Some(item) => item,
-					};
-					let val = match self.decode_from_stream(decoder)? {
-						None => return Err(DecodeError::Malformed),
-						Some(item) => item,
-					};
-					map.push((key, val));
-				}
-				Item::Map(map)
-			}
-			Event::UnknownLengthMap => {
-				let mut map = Vec::new();
-				loop {
-					let key = match self.decode_from_stream(decoder)? {
-						None => break,
-						Some(item) => item,
-					};
-					let val = match self.decode_from_stream(decoder)? {
-						None => return Err(DecodeError::Malformed),
-						Some(item) => item,
-					};
-					map.push((key, val));
+			Event::Map(len) => self.decode_map(decoder, Some(len))?,
+			Event::UnknownLengthMap => self.decode_map(decoder, None)?,
+			Event::Tag(tag) => {
+				let offset = decoder.position();
+				match self.decode_from_stream(decoder)? {
+					Some(value) => Item::Tag(tag, Box::new(value)),
+					None => {
+						return Err(DecodeError::new(
+							MalformedKind::UnexpectedBreak.into(),
+							offset,
+						))
+					}
 				}
-				Item::Map(map)
 			}
-			Event::Tag(tag) => match self.decode_from_stream(decoder) {
-				Ok(Some(value)) => Item::Tag(tag, Box::new(value)),
-				Ok(None) => return Err(DecodeError::Malformed),
-				Err(e) => return Err(e),
-			},
 			Event::Simple(val) => Item::Simple(val),
-			Event::Float(val) => Item::Float(val),
+			Event::Float16(val) => Item::Float16(val),
+			Event::Float32(val) => Item::Float32(val),
+			Event::Float64(val) => Item::Float64(val),
 			Event::Break => return Ok(None),
 		}))
 	}
+
+	/// Decode an item nested inside an array or map, recording where it is if decoding fails.
+	fn decode_nested<'de>(
+		&self,
+		decoder: &mut StreamingDecoder<impl Source<'de>>,
+		segment: impl FnOnce() -> PathSegment,
+	) -> Result<Option<Item>, DecodeError> {
+		self.decode_from_stream(decoder)
+			.map_err(|e| e.within(segment()))
+	}
+
+	/// Decode the contents of a map, with `len` pairs or up to a break.
+	///
+	/// This is separate from [`Self::decode_from_stream`] to keep its stack frame small, since that recurses.
+	fn decode_map<'de>(
+		&self,
+		decoder: &mut StreamingDecoder<impl Source<'de>>,
+		len: Option<u64>,
+	) -> Result<Item, DecodeError> {
+		let mut map = preallocate(len.unwrap_or(0));
+		let mut keys = KeyIndex::default();
+		for index in 0..len.unwrap_or(u64::MAX) {
+			let offset = decoder.position();
+			let key = if len.is_some() {
+				self.decode_required(decoder, || PathSegment::Key(index))?
+			} else {
+				match self.decode_nested(decoder, || PathSegment::Key(index))? {
+					None => break,
+					Some(item) => item,
+				}
+			};
+			let val =
+				self.decode_required(decoder, || PathSegment::Value(index, describe_key(&key)))?;
+			keys.add_pair(&mut map, (key, val), self.duplicate_keys)
+				.map_err(|e| DecodeError::new(e, offset).within(PathSegment::Key(index)))?;
+		}
+		Ok(Item::Map(map))
+	}
+
+	/// Like [`Self::decode_nested`], but finding a break instead of an item is an error.
+	fn decode_required<'de>(
+		&self,
+		decoder: &mut StreamingDecoder<impl Source<'de>>,
+		segment: impl Fn() -> PathSegment,
+	) -> Result<Item, DecodeError> {
+		let offset = decoder.position();
+		match self.decode_nested(decoder, &segment)? {
+			Some(item) => Ok(item),
+			None => Err(
+				DecodeError::new(MalformedKind::UnexpectedBreak.into(), offset).within(segment()),
+			),
+		}
+	}
 }
 
+/// Describe a map key for a [`PathSegment::Value`], if it's simple enough.
+fn describe_key(key: &Item) -> Option<String> {
+	match key {
+		Item::Unsigned(n) => Some(n.to_string()),
+		Item::Signed(n) => Some(Item::interpret_signed_wide(*n).to_string()),
+		Item::TextString(t) => Some(format!("{:?}", t)),
+		_ => None,
+	}
+}
+
+/// An iterator over the items in a CBOR Sequence, from [`Decoder::decode_sequence`] and friends.
+///
+/// The iterator ends when the input ends between two items.
+/// If it ends partway through one, or anything else goes wrong, the error is the last thing the iterator returns.
+/// A break (`0xFF`) where an item should start is a [`MalformedKind::UnexpectedBreak`].
 #[derive(Debug, Clone)]
+pub struct SequenceDecoder<S> {
+	tree: Decoder,
+	decoder: StreamingDecoder<S>,
+	failed: bool,
+}
+
+impl<S> SequenceDecoder<S> {
+	/// Get back the streaming decoder, to find out where it's got to or to [finish](`StreamingDecoder::finish`) it.
+	pub fn into_inner(self) -> StreamingDecoder<S> {
+		self.decoder
+	}
+}
+
+impl<'de, S: Source<'de>> Iterator for SequenceDecoder<S> {
+	type Item = Result<Item, DecodeError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.failed {
+			return None;
+		}
+		// Running out of input is only an error once an item has started.
+		if let Err(e) = self.decoder.peek_major_type() {
+			if matches!(e.kind(), DecodeErrorKind::Insufficient) {
+				return None;
+			}
+		}
+		let offset = self.decoder.position();
+		let result = match self.tree.decode_from_stream(&mut self.decoder) {
+			Ok(Some(item)) => Ok(item),
+			Ok(None) => Err(DecodeError::new(
+				MalformedKind::UnexpectedBreak.into(),
+				offset,
+			)),
+			Err(e) => Err(e),
+		};
+		self.failed = result.is_err();
+		Some(result)
+	}
+}
+
+impl<'de, S: Source<'de>> std::iter::FusedIterator for SequenceDecoder<S> {}
+
+/// An iterator over the items in a CBOR Sequence that carries on past damaged items, from [`Decoder::decode_sequence_resync`].
+///
+/// When an item can't be decoded, the iterator returns the error, with its offset in the whole input.
+/// It then drops the damaged input and carries on after it.
+/// If the extent of the damaged item is still clear, for instance if a text string in it isn't valid UTF-8,
+/// that whole item is dropped, including everything nested inside it.
+/// Otherwise, everything up to and including the byte the error is at is dropped.
+/// The damaged item can't be dropped entirely in that case, since where it would have ended isn't known,
+/// so a [plausibility check](`Self::set_plausible`) that rules out items that can't be real records
+/// helps to skip the rest of it.
+/// Only one error is returned for each stretch of damaged input.
+///
+/// Input is read ahead of the item being decoded, and the [limits](`Decoder::limits`) apply to each item separately.
+/// If the reader itself fails, the error is the last thing the iterator returns.
+///
+/// Resynchronizing is only available for the tree decoders.
+/// A [streaming decoder](`StreamingDecoder`) can't carry on after an error in the input.
+pub struct ResyncDecoder<T> {
+	tree: Decoder,
+	input: ResyncInput<T>,
+	plausible: Box<dyn FnMut(&Item) -> bool>,
+}
+
+impl<T: fmt::Debug> fmt::Debug for ResyncDecoder<T> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("ResyncDecoder")
+			.field("tree", &self.tree)
+			.field("input", &self.input)
+			.finish_non_exhaustive()
+	}
+}
+
+impl<T: Read> ResyncDecoder<T> {
+	/// Set a check for whether an item found after an error could be a real record.
+	///
+	/// Items that fail it are treated as more of the damaged input, and the search for the next item carries on from the byte after their start.
+	/// By default, every item is plausible.
+	pub fn set_plausible(&mut self, plausible: impl FnMut(&Item) -> bool + 'static) -> &mut Self {
+		self.plausible = Box::new(plausible);
+		self
+	}
+}
+
+impl<T: Read> Iterator for ResyncDecoder<T> {
+	type Item = Result<Item, DecodeError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let tree = &self.tree;
+		self.input.next_item(
+			|input| {
+				let mut decoder = StreamingDecoder::from_slice(input);
+				tree.configure(&mut decoder);
+				Ok(tree
+					.decode_from_stream(&mut decoder)?
+					.map(|item| (item, decoder.position())))
+			},
+			&mut *self.plausible,
+		)
+	}
+}
+
+impl<T: Read> std::iter::FusedIterator for ResyncDecoder<T> {}
+
+/// The input side of a resynchronizing decoder, which is the same for both trees.
+#[derive(Debug, Clone)]
+pub(crate) struct ResyncInput<T> {
+	reader: T,
+	buffer: Vec<u8>,
+	/// Where the input that hasn't been decoded yet starts in the buffer.
+	start: usize,
+	/// The offset of `start` in the whole input.
+	offset: u64,
+	eof: bool,
+	/// Whether the last thing returned was an error, and no item has been found since.
+	resyncing: bool,
+	failed: bool,
+}
+
+impl<T: Read> ResyncInput<T> {
+	pub(crate) fn new(reader: T) -> Self {
+		ResyncInput {
+			reader,
+			buffer: Vec::new(),
+			start: 0,
+			offset: 0,
+			eof: false,
+			resyncing: false,
+			failed: false,
+		}
+	}
+
+	/// Read some more input into the buffer.
+	///
+	/// This reads at least as much as is already buffered, so an item that's too big to fit isn't decoded over and over too many times.
+	fn read_more(&mut self) -> std::io::Result<()> {
+		self.buffer.drain(..self.start);
+		self.start = 0;
+		let wanted = self.buffer.len().max(4096) as u64;
+		let got = (&mut self.reader)
+			.take(wanted)
+			.read_to_end(&mut self.buffer)?;
+		if (got as u64) < wanted {
+			self.eof = true;
+		}
+		Ok(())
+	}
+
+	/// Drop some input from the front of what hasn't been decoded yet.
+	fn advance(&mut self, len: usize) {
+		self.start += len;
+		self.offset += len as u64;
+	}
+
+	/// Work out how much input to drop after an error at `error_offset` in the input that hasn't been decoded yet.
+	///
+	/// If the whole item the error is in can still be skipped, that's the damaged item.
+	/// Otherwise, the byte the error is at is bad, so it's dropped along with everything before it.
+	fn damaged_len(&self, error_offset: u64) -> Result<usize, DecodeErrorKind> {
+		let rest = &self.buffer[self.start..];
+		let mut decoder = StreamingDecoder::from_slice(rest);
+		match decoder.skip_item() {
+			Ok(true) => Ok(decoder.position() as usize),
+			Err(e) if matches!(e.kind(), DecodeErrorKind::Insufficient) && !self.eof => {
+				Err(DecodeErrorKind::Insufficient)
+			}
+			_ => {
+				let error_offset =
+					usize::try_from(error_offset).map_or(rest.len(), |o| o.min(rest.len()));
+				Ok((error_offset + 1).min(rest.len()))
+			}
+		}
+	}
+
+	/// Decode the next item with `decode`, which is given the input that hasn't been decoded yet,
+	/// and returns the item and how much input it took up.
+	pub(crate) fn next_item<I>(
+		&mut self,
+		mut decode: impl FnMut(&[u8]) -> Result<Option<(I, u64)>, DecodeError>,
+		plausible: &mut dyn FnMut(&I) -> bool,
+	) -> Option<Result<I, DecodeError>> {
+		while !self.failed {
+			if self.start == self.buffer.len() {
+				if self.eof {
+					return None;
+				}
+				if let Err(e) = self.read_more() {
+					self.failed = true;
+					return Some(Err(DecodeError::new(e.into(), self.offset)));
+				}
+				continue;
+			}
+
+			let error = match decode(&self.buffer[self.start..]) {
+				Ok(Some((item, len))) if !self.resyncing || plausible(&item) => {
+					self.advance(len as usize);
+					self.resyncing = false;
+					return Some(Ok(item));
+				}
+				// The start of a real item could be somewhere inside this one.
+				Ok(Some(_)) => {
+					self.advance(1);
+					continue;
+				}
+				Ok(None) => DecodeError::new(MalformedKind::UnexpectedBreak.into(), 0),
+				// The item might just not have been read in full yet.
+				Err(e) if matches!(e.kind(), DecodeErrorKind::Insufficient) && !self.eof => {
+					if let Err(e) = self.read_more() {
+						self.failed = true;
+						return Some(Err(DecodeError::new(e.into(), self.offset)));
+					}
+					continue;
+				}
+				Err(e) => e,
+			};
+			let damaged_len = match self.damaged_len(error.offset()) {
+				Ok(len) => len,
+				// Reading more and decoding again ends up back here, with more input to skip over.
+				Err(_) => {
+					if let Err(e) = self.read_more() {
+						self.failed = true;
+						return Some(Err(DecodeError::new(e.into(), self.offset)));
+					}
+					continue;
+				}
+			};
+			let offset = self.offset;
+			self.advance(damaged_len.max(1));
+			if !self.resyncing {
+				self.resyncing = true;
+				return Some(Err(error.offset_by(offset)));
+			}
+		}
+		None
+	}
+}
+
+#[derive(Debug, Clone, Default)]
 /// A tree-walking encoder for the CBOR basic data model.
-pub struct Encoder {}
+///
+/// By default, items are written as they are.
+/// Set a [`Determinism`] to make sure the same value is always written the same way, for instance so it can be signed.
+pub struct Encoder {
+	determinism: Determinism,
+}
 
 impl Encoder {
 	pub fn new() -> Self {
-		Self {}
+		Default::default()
 	}
 
+	config_accessors!(
+		determinism,
+		Determinism,
+		determinism,
+		determinism_mut,
+		set_determinism
+	);
+
 	/// Encode some CBOR.
 	///
-	/// This is just a shortcut for [`Self::encode_to_stream`] which constructs the [`streaming::Encoder`](`crate::basic::streaming::Encoder`) for you.
+	/// This is just a shortcut for [`Self::encode_to_stream`] which constructs the [`streaming::Encoder`](`crate::basic::streaming::Encoder`) for you,
+	/// and then [finishes](`StreamingEncoder::finish`) it, which flushes `dest`.
 	pub fn encode(&self, cbor: &Item, dest: impl Write) -> Result<(), EncodeError> {
-		self.encode_to_stream(cbor, &mut StreamingEncoder::new(dest))
+		let mut encoder = StreamingEncoder::new(dest);
+		self.encode_to_stream(cbor, &mut encoder)?;
+		encoder.finish()?;
+		Ok(())
+	}
+
+	/// Start writing a CBOR Sequence ([RFC 8742](https://www.rfc-editor.org/rfc/rfc8742.html)): any number of items, one after another.
+	pub fn encode_sequence<T: Write>(self, dest: T) -> SequenceEncoder<T> {
+		SequenceEncoder { tree: self, dest }
 	}
 
 	/// Encode some CBOR to a provided streaming encoder.
@@ -249,13 +1036,18 @@ impl Encoder {
 		encoder: &mut StreamingEncoder<impl Write>,
 	) -> Result<(), EncodeError> {
 		match cbor {
+			Item::Float16(_) | Item::Float32(_) | Item::Float64(_)
+				if self.determinism != Determinism::Off =>
+			{
+				encoder.feed_event(Event::create_float(cbor.as_f64().unwrap()))
+			}
 			Item::Unsigned(n) => encoder.feed_event(Event::Unsigned(*n)),
 			Item::Signed(n) => encoder.feed_event(Event::Signed(*n)),
-			Item::Float(f) => encoder.feed_event(Event::Float(*f)),
-			Item::ByteString(bytes) => {
-				encoder.feed_event(Event::ByteString(Cow::Borrowed(&*bytes)))
-			}
-			Item::TextString(text) => encoder.feed_event(Event::TextString(Cow::Borrowed(&*text))),
+			Item::Float16(f) => encoder.feed_event(Event::Float16(*f)),
+			Item::Float32(f) => encoder.feed_event(Event::Float32(*f)),
+			Item::Float64(f) => encoder.feed_event(Event::Float64(*f)),
+			Item::ByteString(bytes) => encoder.feed_event(Event::ByteString(Cow::Borrowed(bytes))),
+			Item::TextString(text) => encoder.feed_event(Event::TextString(Cow::Borrowed(text))),
 			Item::Array(arr) => {
 				encoder.feed_event(Event::Array(
 					arr.len().try_into().expect("I'm on a 128-bit system? Wow."),
@@ -269,7 +1061,20 @@ impl Encoder {
 				encoder.feed_event(Event::Map(
 					map.len().try_into().expect("I'm on a 128-bit system? Wow."),
 				))?;
-				for (key, val) in map.iter() {
+				if self.determinism == Determinism::Off {
+					for (key, val) in map.iter() {
+						self.encode_to_stream(key, encoder)?;
+						self.encode_to_stream(val, encoder)?;
+					}
+					return Ok(());
+				}
+				// A key that can't be encoded will fail again when it's written, so it doesn't matter where it goes.
+				let pairs = sort_pairs(map.iter().collect(), self.determinism, |(key, _)| {
+					let mut out = Vec::new();
+					let _ = self.encode(key, &mut out);
+					out
+				});
+				for (key, val) in pairs {
 					self.encode_to_stream(key, encoder)?;
 					self.encode_to_stream(val, encoder)?;
 				}
@@ -284,6 +1089,29 @@ impl Encoder {
 	}
 }
 
+/// A writer for a CBOR Sequence, from [`Encoder::encode_sequence`].
+#[derive(Debug, Clone)]
+pub struct SequenceEncoder<T: Write> {
+	tree: Encoder,
+	dest: T,
+}
+
+impl<T: Write> SequenceEncoder<T> {
+	/// Add an item to the end of the sequence.
+	///
+	/// If this fails, part of the item may already have been written.
+	pub fn encode(&mut self, cbor: &Item) -> Result<(), EncodeError> {
+		self.tree.encode(cbor, &mut self.dest)
+	}
+
+	/// Stop writing, and get back the destination.
+	///
+	/// Since every item is written as soon as it's given, there's nothing to finish off.
+	pub fn into_inner(self) -> T {
+		self.dest
+	}
+}
+
 #[cfg(test)]
 mod test {
 	use super::*;
@@ -291,7 +1119,7 @@ mod test {
 	macro_rules! decode_test {
 		($in:expr => $out:pat if $guard:expr) => {
 			let input = $in;
-			match Decoder::new().decode(std::io::Cursor::new(&input)) {
+			match Decoder::new().decode(std::io::Cursor::new(&input)).map_err(DecodeError::into_kind) {
 				$out if $guard => (),
 				other => panic!("{:X?} => {:?}", input, other),
 			}
@@ -321,24 +1149,126 @@ mod test {
 
 	#[test]
 	fn decode_bytes_segmented_wrong() {
-		decode_test!(b"\x5F\x00" => Err(DecodeError::Malformed));
+		decode_test!(b"\x5F\x00" => Err(DecodeErrorKind::Malformed(MalformedKind::InvalidChunk)));
 	}
 
 	#[test]
 	fn decode_text_segmented() {
 		decode_test!(b"\x7F\x62ab\x62cd\xFF" => Ok(Item::TextString(t)) if t == "abcd");
-		decode_test!(b"\x7F\xFF" => Ok(Item::TextString(t)) if t == "");
+		decode_test!(b"\x7F\xFF" => Ok(Item::TextString(t)) if t.is_empty());
 	}
 
 	#[test]
 	fn decode_text_segmented_wrong() {
-		decode_test!(b"\x7F\x00" => Err(DecodeError::Malformed));
+		decode_test!(b"\x7F\x00" => Err(DecodeErrorKind::Malformed(MalformedKind::InvalidChunk)));
+	}
+
+	#[test]
+	fn decode_slice() {
+		assert_eq!(
+			Decoder::new()
+				.decode_slice(b"\x82\x42ab\x7F\x61c\x61d\xFF")
+				.unwrap(),
+			Item::Array(vec![
+				Item::ByteString(b"ab".to_vec()),
+				Item::TextString("cd".to_owned()),
+			])
+		);
+		assert!(matches!(
+			Decoder::new()
+				.decode_slice(b"\x82\x00")
+				.map_err(DecodeError::into_kind),
+			Err(DecodeErrorKind::Insufficient)
+		));
+	}
+
+	#[test]
+	fn decode_limits() {
+		// A huge length on its own mustn't cause a huge allocation.
+		decode_test!(b"\x9B\x00\x00\x00\x01\x00\x00\x00\x00" => Err(DecodeErrorKind::Insufficient));
+		let mut decoder = Decoder::new();
+		decoder.limits_mut().set_max_depth(Some(3));
+		assert!(matches!(
+			decoder
+				.decode_slice(b"\x81\x81\x81\x81\x00")
+				.map_err(DecodeError::into_kind),
+			Err(DecodeErrorKind::TooDeep)
+		));
+	}
+
+	#[test]
+	fn decode_error_path() {
+		let error = Decoder::new()
+			.decode_slice(b"\x82\x00\xA2\x01\x00\x61a\x5F\x00")
+			.unwrap_err();
+		assert!(matches!(
+			error.kind(),
+			DecodeErrorKind::Malformed(MalformedKind::InvalidChunk)
+		));
+		assert_eq!(error.offset(), 8);
+		assert_eq!(
+			error.path().cloned().collect::<Vec<_>>(),
+			[
+				PathSegment::Index(1),
+				PathSegment::Value(1, Some("\"a\"".to_owned()))
+			]
+		);
+		assert_eq!(
+			error.to_string(),
+			"malformed CBOR: invalid piece of an unknown-length string at byte 8 (in [1]{\"a\"})"
+		);
+
+		let error = Decoder::new().decode_slice(b"\xA1\x9F\x1C").unwrap_err();
+		assert_eq!(error.offset(), 2);
+		assert_eq!(
+			error.path().cloned().collect::<Vec<_>>(),
+			[PathSegment::Key(0), PathSegment::Index(0)]
+		);
+		assert_eq!(
+			error.to_string(),
+			"malformed CBOR: reserved additional information 28 at byte 2 (in {key #0}[0])"
+		);
+	}
+
+	#[test]
+	fn decode_hostile() {
+		// Deep nesting must hit the default depth limit rather than the bottom of the stack.
+		decode_test!(vec![0x81; 100_000] => Err(DecodeErrorKind::TooDeep));
+		decode_test!(vec![0x9F; 100_000] => Err(DecodeErrorKind::TooDeep));
+		decode_test!(vec![0xC0; 100_000] => Err(DecodeErrorKind::TooDeep));
+		// That goes for streaming decoders without a depth limit of their own, too.
+		let input = vec![0x81; 100_000];
+		let mut decoder = StreamingDecoder::from_slice(&input);
+		assert!(matches!(
+			Decoder::new()
+				.decode_from_stream(&mut decoder)
+				.map_err(DecodeError::into_kind),
+			Err(DecodeErrorKind::TooDeep)
+		));
+		let mut items =
+			Decoder::new().decode_sequence_from_stream(StreamingDecoder::from_slice(&input));
+		assert!(matches!(
+			items.next().unwrap().map_err(DecodeError::into_kind),
+			Err(DecodeErrorKind::TooDeep)
+		));
+		let mut input = vec![0x81; DEFAULT_MAX_DEPTH - 1];
+		input.push(0x00);
+		decode_test!(input => Ok(_));
+		decode_test!(b"\xBB\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\x00" => Err(DecodeErrorKind::Insufficient));
+		decode_test!(b"\x5B\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\x00" => Err(DecodeErrorKind::StringTooLong));
+	}
+
+	#[test]
+	fn decode_fuzz() {
+		for input in crate::basic::streaming::test::fuzz_inputs(20_000) {
+			let _ = Decoder::new().decode_slice(&input);
+		}
 	}
 
 	#[test]
 	fn decode_array() {
 		decode_test!(b"\x80" => Ok(Item::Array(v)) if v.is_empty());
-		decode_test!(b"\x84\x00\x01\x02\x03" => Ok(Item::Array(v)) if v == [0,1,2,3].map(|x| Item::Unsigned(x)));
+		decode_test!(b"\x84\x00\x01\x02\x03" => Ok(Item::Array(v)) if v == [0,1,2,3].map(Item::Unsigned));
 	}
 
 	#[test]
@@ -372,8 +1302,8 @@ mod test {
 
 	#[test]
 	fn decode_map_wrong() {
-		decode_test!(b"\xA1\x00" => Err(DecodeError::Insufficient));
-		decode_test!(b"\xBF\x00\xFF" => Err(DecodeError::Malformed));
+		decode_test!(b"\xA1\x00" => Err(DecodeErrorKind::Insufficient));
+		decode_test!(b"\xBF\x00\xFF" => Err(DecodeErrorKind::Malformed(MalformedKind::UnexpectedBreak)));
 	}
 
 	#[test]
@@ -394,12 +1324,305 @@ mod test {
 
 	#[test]
 	fn decode_tag_wrong() {
-		decode_test!(b"\xC1" => Err(DecodeError::Insufficient));
-		decode_test!(b"\xC1\xFF" => Err(DecodeError::Malformed));
+		decode_test!(b"\xC1" => Err(DecodeErrorKind::Insufficient));
+		decode_test!(b"\xC1\xFF" => Err(DecodeErrorKind::Malformed(MalformedKind::UnexpectedBreak)));
 	}
 
 	#[test]
 	fn encode_tag() {
 		encode_test!(Item::Tag(1, Box::new(Item::Unsigned(0))) => b"\xC1\x00");
 	}
+
+	#[test]
+	fn encode_simple() {
+		encode_test!(Item::Simple(22) => b"\xF6");
+		encode_test!(Item::Simple(32) => b"\xF8\x20");
+		// 24-31 have no valid encoding, so they can't be written and read back.
+		let mut output = Vec::new();
+		assert!(matches!(
+			Encoder::new().encode(&Item::Simple(30), &mut output),
+			Err(EncodeError::InvalidSimple(30))
+		));
+		assert!(output.is_empty());
+	}
+
+	#[test]
+	fn decode_sequence() {
+		let input = b"\x01\x82\x02\x03\x61a";
+		let expected = [
+			Item::Unsigned(1),
+			Item::Array(vec![Item::Unsigned(2), Item::Unsigned(3)]),
+			Item::TextString("a".to_owned()),
+		];
+		let items: Vec<_> = Decoder::new()
+			.decode_sequence(std::io::Cursor::new(input))
+			.collect::<Result<_, _>>()
+			.unwrap();
+		assert_eq!(items, expected);
+		let items: Vec<_> = Decoder::new()
+			.decode_sequence_slice(input)
+			.collect::<Result<_, _>>()
+			.unwrap();
+		assert_eq!(items, expected);
+		assert_eq!(Decoder::new().decode_sequence_slice(b"").count(), 0);
+
+		// Ending partway through an item is an error, and the last thing returned.
+		let mut items = Decoder::new().decode_sequence_slice(&input[..3]);
+		assert!(matches!(items.next(), Some(Ok(Item::Unsigned(1)))));
+		let error = items.next().unwrap().unwrap_err();
+		assert!(matches!(error.kind(), DecodeErrorKind::Insufficient));
+		assert_eq!(error.offset(), 3);
+		assert!(items.next().is_none());
+
+		let mut items = Decoder::new().decode_sequence_slice(b"\x00\xFF\x00");
+		assert!(matches!(items.next(), Some(Ok(Item::Unsigned(0)))));
+		let error = items.next().unwrap().unwrap_err();
+		assert!(matches!(
+			error.kind(),
+			DecodeErrorKind::Malformed(MalformedKind::UnexpectedBreak)
+		));
+		assert_eq!(error.offset(), 1);
+		assert!(items.next().is_none());
+	}
+
+	#[test]
+	fn decode_sequence_resync() {
+		// 1, an array that's damaged by a reserved initial byte, 2 and 3.
+		let input = b"\x01\x82\x00\x1C\x02\x03";
+		let mut items = Decoder::new().decode_sequence_resync(std::io::Cursor::new(input));
+		assert!(matches!(items.next(), Some(Ok(Item::Unsigned(1)))));
+		let error = items.next().unwrap().unwrap_err();
+		assert!(matches!(
+			error.kind(),
+			DecodeErrorKind::Malformed(MalformedKind::ReservedAdditionalInfo(28))
+		));
+		assert_eq!(error.offset(), 3);
+		// The 0 from inside the damaged array doesn't come back.
+		assert!(matches!(items.next(), Some(Ok(Item::Unsigned(2)))));
+		assert!(matches!(items.next(), Some(Ok(Item::Unsigned(3)))));
+		assert!(items.next().is_none());
+
+		// A text string that isn't valid UTF-8 is dropped whole, since its length is known.
+		let input = b"\x63\xFF\x00\x01\x02";
+		let mut items = Decoder::new().decode_sequence_resync(std::io::Cursor::new(input));
+		let error = items.next().unwrap().unwrap_err();
+		assert_eq!(error.offset(), 0);
+		assert!(matches!(items.next(), Some(Ok(Item::Unsigned(2)))));
+		assert!(items.next().is_none());
+
+		// The whole array around a damaged text string is dropped, including the 2 after it.
+		let input = b"\x01\x82\x63\xFF\x00\x01\x02\x03";
+		let mut items = Decoder::new().decode_sequence_resync(std::io::Cursor::new(input));
+		assert!(matches!(items.next(), Some(Ok(Item::Unsigned(1)))));
+		let error = items.next().unwrap().unwrap_err();
+		assert_eq!(error.offset(), 2);
+		assert!(matches!(items.next(), Some(Ok(Item::Unsigned(3)))));
+		assert!(items.next().is_none());
+
+		// Only arrays of at least one item are plausible, so the resync skips the 0 that's left of the damaged record.
+		let input = b"\x82\x01\x1C\x00\x81\x02\x81\x03\x82";
+		let mut items = Decoder::new().decode_sequence_resync(std::io::Cursor::new(input));
+		let min_len = 1;
+		items.set_plausible(move |item| matches!(item, Item::Array(a) if a.len() >= min_len));
+		let error = items.next().unwrap().unwrap_err();
+		assert_eq!(error.offset(), 2);
+		assert!(matches!(items.next(), Some(Ok(Item::Array(a))) if a == [Item::Unsigned(2)]));
+		assert!(matches!(items.next(), Some(Ok(Item::Array(a))) if a == [Item::Unsigned(3)]));
+		// The last record is cut off.
+		let error = items.next().unwrap().unwrap_err();
+		assert!(matches!(error.kind(), DecodeErrorKind::Insufficient));
+		assert_eq!(error.offset(), 9);
+		assert!(items.next().is_none());
+
+		// Items bigger than what's read at once still work.
+		let mut input = vec![0x59, 0x27, 0x10];
+		input.extend_from_slice(&[0; 10_000]);
+		input.push(0x01);
+		let items: Vec<_> = Decoder::new()
+			.decode_sequence_resync(std::io::Cursor::new(input))
+			.collect::<Result<_, _>>()
+			.unwrap();
+		assert!(
+			matches!(&items[..], [Item::ByteString(b), Item::Unsigned(1)] if b.len() == 10_000)
+		);
+	}
+
+	#[test]
+	fn data_model_eq() {
+		assert!(Item::Float64(0.0).data_model_eq(&Item::Float64(-0.0)));
+		assert!(Item::Float64(f64::NAN).data_model_eq(&Item::Float64(-f64::NAN)));
+		assert!(!Item::Float64(f64::NAN)
+			.data_model_eq(&Item::Float64(f64::from_bits(0x7FF8_0000_0000_0001))));
+		assert!(!Item::Unsigned(1).data_model_eq(&Item::Float64(1.0)));
+		// Widths don't matter, but NaN payloads do, even when they're widened.
+		assert!(Item::Float16(f16::from_f32(1.5)).data_model_eq(&Item::Float64(1.5)));
+		assert!(Item::Float16(f16::NAN).data_model_eq(&Item::Float32(f32::NAN)));
+		assert!(!Item::Float16(f16::from_bits(0x7C01)).data_model_eq(&Item::Float16(f16::NAN)));
+		let map = |pairs: &[(u64, u64)]| {
+			Item::Map(
+				pairs
+					.iter()
+					.map(|&(k, v)| (Item::Unsigned(k), Item::Unsigned(v)))
+					.collect(),
+			)
+		};
+		assert!(map(&[(1, 2), (3, 4)]).data_model_eq(&map(&[(3, 4), (1, 2)])));
+		assert!(!map(&[(1, 2), (1, 2)]).data_model_eq(&map(&[(1, 2), (1, 3)])));
+		assert!(Item::Tag(1, Box::new(map(&[(1, 2), (3, 4)])))
+			.data_model_eq(&Item::Tag(1, Box::new(map(&[(3, 4), (1, 2)])))));
+	}
+
+	#[test]
+	fn duplicate_keys() {
+		let pairs = |pairs: &[(u64, &str)]| {
+			pairs
+				.iter()
+				.map(|&(k, v)| (Item::Unsigned(k), Item::TextString(v.to_owned())))
+				.collect::<Vec<_>>()
+		};
+		// 1 and 0x18 0x01 are the same key.
+		for input in [
+			&b"\xA3\x01\x61a\x18\x01\x61b\x02\x61c"[..],
+			b"\xBF\x01\x61a\x18\x01\x61b\x02\x61c\xFF",
+		] {
+			let decode = |policy| {
+				let mut decoder = Decoder::new();
+				decoder.set_duplicate_keys(policy);
+				decoder.decode_slice(input)
+			};
+			assert!(matches!(
+				decode(DuplicateKeyPolicy::Allow),
+				Ok(Item::Map(m)) if m == pairs(&[(1, "a"), (1, "b"), (2, "c")])
+			));
+			assert!(matches!(
+				decode(DuplicateKeyPolicy::KeepFirst),
+				Ok(Item::Map(m)) if m == pairs(&[(1, "a"), (2, "c")])
+			));
+			assert!(matches!(
+				decode(DuplicateKeyPolicy::KeepLast),
+				Ok(Item::Map(m)) if m == pairs(&[(1, "b"), (2, "c")])
+			));
+			let error = decode(DuplicateKeyPolicy::Reject).unwrap_err();
+			assert!(matches!(error.kind(), DecodeErrorKind::DuplicateKey));
+			assert_eq!(error.offset(), 4);
+			assert_eq!(
+				error.path().cloned().collect::<Vec<_>>(),
+				[PathSegment::Key(1)]
+			);
+		}
+
+		// Keys that aren't simple values are compared structurally.
+		let mut decoder = Decoder::new();
+		decoder.set_duplicate_keys(DuplicateKeyPolicy::Reject);
+		assert!(decoder
+			.clone()
+			.decode_slice(b"\xA2\xA2\x01\x02\x03\x04\x00\xA2\x03\x04\x01\x02\x00")
+			.is_err());
+		assert!(decoder
+			.clone()
+			.decode_slice(b"\xA2\xF9\x00\x00\x00\xF9\x80\x00\x00")
+			.is_err());
+		assert!(decoder
+			.clone()
+			.decode_slice(b"\xA2\xF9\x3C\x00\x00\xFB\x3F\xF0\x00\x00\x00\x00\x00\x00\x00")
+			.is_err());
+		assert!(decoder
+			.decode_slice(b"\xA2\x01\x00\xF9\x3C\x00\x00")
+			.is_ok());
+	}
+
+	#[test]
+	fn decode_require_definite() {
+		let mut decoder = Decoder::new();
+		decoder.set_require_definite(true);
+		assert!(decoder
+			.clone()
+			.decode_slice(b"\xA1\x00\x82\x01\x02")
+			.is_ok());
+		let error = decoder
+			.clone()
+			.decode_slice(b"\xA1\x00\x82\x01\x5F\xFF")
+			.unwrap_err();
+		assert!(matches!(error.kind(), DecodeErrorKind::NotDefinite(2)));
+		assert_eq!(error.offset(), 4);
+		let items: Vec<_> = decoder.decode_sequence_slice(b"\x01\x9F\xFF").collect();
+		assert!(matches!(
+			&items[..],
+			[Ok(Item::Unsigned(1)), Err(e)] if matches!(e.kind(), DecodeErrorKind::NotDefinite(4))
+		));
+	}
+
+	#[test]
+	fn encode_deterministic() {
+		// The example from RFC 8949 section 4.2.1, shuffled, with a float and a nested map among the values.
+		let text = |t: &str| Item::TextString(t.to_owned());
+		let item = Item::Map(vec![
+			(Item::Simple(20), Item::Unsigned(7)),
+			(Item::Array(vec![Item::Signed(0)]), Item::Unsigned(6)),
+			(text("aa"), Item::Float64(1.5)),
+			(Item::Unsigned(100), Item::Unsigned(1)),
+			(
+				text("z"),
+				Item::Map(vec![
+					(text("b"), Item::Simple(22)),
+					(text("a"), Item::Simple(22)),
+				]),
+			),
+			(Item::Unsigned(10), Item::Unsigned(0)),
+			(Item::Array(vec![Item::Unsigned(100)]), Item::Unsigned(5)),
+			(Item::Signed(0), Item::Unsigned(2)),
+		]);
+		let expected = b"\xA8\x0A\x00\x18\x64\x01\x20\x02\x61z\xA2\x61a\xF6\x61b\xF6\x62aa\xF9\x3E\x00\x81\x18\x64\x05\x81\x20\x06\xF4\x07";
+
+		let mut output = Vec::new();
+		let mut encoder = Encoder::new();
+		encoder.set_determinism(Determinism::Core);
+		encoder.encode(&item, &mut output).unwrap();
+		assert_eq!(output, expected);
+
+		let mut canonical = item.clone();
+		canonical.canonicalize();
+		assert!(canonical.data_model_eq(&item));
+		let mut output = Vec::new();
+		Encoder::new().encode(&canonical, &mut output).unwrap();
+		assert_eq!(output, expected);
+	}
+
+	#[test]
+	fn encode_length_first() {
+		// The example from RFC 7049 section 3.9, shuffled.
+		let item = Item::Map(vec![
+			(Item::Simple(20), Item::Unsigned(7)),
+			(Item::Array(vec![Item::Signed(0)]), Item::Unsigned(6)),
+			(Item::TextString("aa".to_owned()), Item::Unsigned(4)),
+			(Item::Unsigned(100), Item::Unsigned(1)),
+			(Item::TextString("z".to_owned()), Item::Unsigned(3)),
+			(Item::Unsigned(10), Item::Unsigned(0)),
+			(Item::Array(vec![Item::Unsigned(100)]), Item::Unsigned(5)),
+			(Item::Signed(0), Item::Unsigned(2)),
+		]);
+		let expected = b"\xA8\x0A\x00\x20\x02\xF4\x07\x18\x64\x01\x61z\x03\x81\x20\x06\x62aa\x04\x81\x18\x64\x05";
+
+		let mut output = Vec::new();
+		let mut encoder = Encoder::new();
+		encoder.set_determinism(Determinism::LengthFirst);
+		encoder.encode(&item, &mut output).unwrap();
+		assert_eq!(output, expected);
+
+		let mut canonical = item;
+		canonical.canonicalize_length_first();
+		let mut output = Vec::new();
+		Encoder::new().encode(&canonical, &mut output).unwrap();
+		assert_eq!(output, expected);
+	}
+
+	#[test]
+	fn encode_sequence() {
+		let mut encoder = Encoder::new().encode_sequence(Vec::new());
+		encoder.encode(&Item::Unsigned(1)).unwrap();
+		encoder
+			.encode(&Item::Array(vec![Item::Unsigned(2), Item::Unsigned(3)]))
+			.unwrap();
+		assert_eq!(encoder.into_inner(), b"\x01\x82\x02\x03");
+	}
 }
@@ -1,12 +1,125 @@
-use std::string::FromUtf8Error;
+use std::{fmt, string::FromUtf8Error};
 use thiserror::Error;
 
-/// Errors that can occur when decoding CBOR.
+/// An error that occurred when decoding CBOR.
+///
+/// As well as [what went wrong](`Self::kind`), this records [where in the input](`Self::offset`) it went wrong,
+/// and, if the decoder knows it, the [path](`Self::path`) through arrays and maps that leads there.
+#[derive(Debug)]
+pub struct DecodeError {
+	kind: DecodeErrorKind,
+	offset: u64,
+	/// This is stored innermost first, because that's the order it's built up in.
+	path: Vec<PathSegment>,
+}
+
+impl DecodeError {
+	/// Create an error that occurred at the given byte offset.
+	pub fn new(kind: DecodeErrorKind, offset: u64) -> Self {
+		DecodeError {
+			kind,
+			offset,
+			path: Vec::new(),
+		}
+	}
+
+	/// Get what went wrong.
+	pub fn kind(&self) -> &DecodeErrorKind {
+		&self.kind
+	}
+
+	/// Get what went wrong, discarding where it went wrong.
+	pub fn into_kind(self) -> DecodeErrorKind {
+		self.kind
+	}
+
+	/// Get the offset in the input, in bytes, of the item where the error was found.
+	pub fn offset(&self) -> u64 {
+		self.offset
+	}
+
+	/// Get the path from the top-level item down to the item where the error was found, outermost first.
+	///
+	/// Streaming decoders don't keep map keys around, so values in their paths aren't [described by their keys](`PathSegment::Value`);
+	/// the tree decoders fill those descriptions in.
+	pub fn path(&self) -> impl DoubleEndedIterator<Item = &PathSegment> + ExactSizeIterator {
+		self.path.iter().rev()
+	}
+
+	/// Record that the item where the error was found is inside another item.
+	pub(crate) fn within(mut self, segment: PathSegment) -> Self {
+		self.path.push(segment);
+		self
+	}
+
+	/// Forget the path, so that a decoder that keeps track of its own can build it up again.
+	pub(crate) fn without_path(mut self) -> Self {
+		self.path.clear();
+		self
+	}
+
+	/// Account for the input the decoder started at not being the start of the whole input.
+	pub(crate) fn offset_by(mut self, start: u64) -> Self {
+		self.offset += start;
+		self
+	}
+}
+
+impl fmt::Display for DecodeError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} at byte {}", self.kind, self.offset)?;
+		if !self.path.is_empty() {
+			f.write_str(" (in ")?;
+			for segment in self.path() {
+				write!(f, "{}", segment)?;
+			}
+			f.write_str(")")?;
+		}
+		Ok(())
+	}
+}
+
+impl std::error::Error for DecodeError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		// The kind's own message is already part of ours, so skip straight to its source.
+		std::error::Error::source(&self.kind)
+	}
+}
+
+/// One step on the path from a top-level item down to one nested inside it.
+///
+/// This is used to say where a [`DecodeError`] occurred.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PathSegment {
+	/// The item at this index in an array.
+	Index(u64),
+	/// The key of the pair at this index in a map.
+	Key(u64),
+	/// The value of the pair at this index in a map.
+	///
+	/// If the key is a text string or an integer, this includes a short description of it for display,
+	/// such as `"name"` (with the quotes) or `-7`.
+	Value(u64, Option<String>),
+}
+
+impl fmt::Display for PathSegment {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			PathSegment::Index(index) => write!(f, "[{}]", index),
+			PathSegment::Key(index) => write!(f, "{{key #{}}}", index),
+			PathSegment::Value(_, Some(key)) => write!(f, "{{{}}}", key),
+			PathSegment::Value(index, None) => write!(f, "{{value #{}}}", index),
+		}
+	}
+}
+
+/// The ways decoding CBOR can go wrong.
 #[derive(Debug, Error)]
 #[non_exhaustive]
-pub enum DecodeError {
-	#[error("malformed CBOR")]
-	Malformed,
+pub enum DecodeErrorKind {
+	#[error("malformed CBOR: {0}")]
+	Malformed(#[from] MalformedKind),
 	#[error("excess data")]
 	Excess,
 	#[error("insufficient data")]
@@ -17,11 +130,82 @@ pub enum DecodeError {
 	IoError(#[from] std::io::Error),
 	#[error("got invalid value for an item tagged {0}")]
 	TagInvalid(u64),
+	#[error("items nested too deeply")]
+	TooDeep,
+	#[error("string too long")]
+	StringTooLong,
+	#[error("array or map too long")]
+	CollectionTooLong,
+	#[error("input too long")]
+	InputTooLong,
+	#[error("not in preferred serialization: {0}")]
+	NotPreferred(#[from] NotPreferredKind),
+	#[error("duplicate map key")]
+	DuplicateKey,
+	#[error("indefinite length not allowed for major type {0}")]
+	NotDefinite(u8),
 	#[cfg(feature = "chrono")]
 	#[error("error parsing date/time")]
 	InvalidDateTime(#[from] chrono::format::ParseError),
 }
 
+/// The ways CBOR can fail to be well-formed.
+///
+/// Each of these breaks one of the rules in [RFC 8949 section 3](https://www.rfc-editor.org/rfc/rfc8949.html#section-3);
+/// the documentation for each one says which.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[non_exhaustive]
+pub enum MalformedKind {
+	/// The additional information was 28, 29, or 30, which are reserved.
+	///
+	/// See [section 3](https://www.rfc-editor.org/rfc/rfc8949.html#section-3).
+	#[error("reserved additional information {0}")]
+	ReservedAdditionalInfo(u8),
+	/// An unsigned integer, negative integer, or tag (major types 0, 1, and 6 respectively) claimed to have an indefinite length.
+	///
+	/// See [section 3.2.4](https://www.rfc-editor.org/rfc/rfc8949.html#section-3.2.4).
+	#[error("indefinite length for major type {0}")]
+	IndefiniteLength(u8),
+	/// A break appeared somewhere other than at the end of an unknown-length item.
+	///
+	/// See [section 3.2.1](https://www.rfc-editor.org/rfc/rfc8949.html#section-3.2.1).
+	#[error("unexpected break")]
+	UnexpectedBreak,
+	/// A piece of an unknown-length string was not a known-length string of the same major type.
+	///
+	/// See [section 3.2.3](https://www.rfc-editor.org/rfc/rfc8949.html#section-3.2.3).
+	#[error("invalid piece of an unknown-length string")]
+	InvalidChunk,
+	/// A simple value below 32 was encoded in two bytes.
+	///
+	/// See [section 3.3](https://www.rfc-editor.org/rfc/rfc8949.html#section-3.3).
+	#[error("two-byte encoding of simple value {0}")]
+	InvalidSimple(u8),
+}
+
+/// The ways well-formed CBOR can fail to be in preferred serialization.
+///
+/// A decoder only checks for these if it's been [told to](`crate::basic::streaming::Decoder::set_require_preferred`).
+/// See [RFC 8949 section 4.1](https://www.rfc-editor.org/rfc/rfc8949.html#section-4.1) for the rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[non_exhaustive]
+pub enum NotPreferredKind {
+	/// An unsigned or negative integer (major types 0 and 1) was encoded in more bytes than it needed.
+	///
+	/// This is the integer's argument, so for a negative integer, it's -1 minus the actual value.
+	#[error("integer argument {0} encoded in more bytes than needed")]
+	Integer(u64),
+	/// The length of a string, array, or map was encoded in more bytes than it needed.
+	#[error("length {0} encoded in more bytes than needed")]
+	Length(u64),
+	/// A tag number was encoded in more bytes than it needed.
+	#[error("tag {0} encoded in more bytes than needed")]
+	Tag(u64),
+	/// A floating-point number was encoded in more bytes than it needed to keep its exact value.
+	#[error("float encoded in more bytes than needed")]
+	Float,
+}
+
 /// Errors that can occur when encoding CBOR.
 #[derive(Debug, Error)]
 #[non_exhaustive]
@@ -32,6 +216,63 @@ pub enum EncodeError {
 	Insufficient,
 	#[error("{0}")]
 	IoError(#[from] std::io::Error),
-	#[error("break at invalid time")]
-	InvalidBreak,
+	#[error("break at invalid time ({0})")]
+	InvalidBreak(EventContext),
+	#[error("invalid piece of an unknown-length string ({0})")]
+	InvalidChunk(EventContext),
+	#[error("value doesn't fit in the requested width")]
+	InvalidWidth,
+	#[error("simple value {0} is reserved and can't be encoded")]
+	InvalidSimple(u8),
+}
+
+/// Where an event that was fed to an encoder would have gone.
+///
+/// This is used to say where an [`EncodeError`] occurred.
+/// Indices count from 0, and lengths are [`None`] for unknown-length containers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EventContext {
+	/// Outside any container.
+	TopLevel,
+	/// The item at this index in an array.
+	Array { len: Option<u64>, index: u64 },
+	/// The key of the pair at this index in a map.
+	MapKey { len: Option<u64>, index: u64 },
+	/// The value of the pair at this index in a map.
+	MapValue { len: Option<u64>, index: u64 },
+	/// The item with this tag.
+	Tagged(u64),
+	/// The piece at this index of an unknown-length string of this major type.
+	Chunk { major: u8, index: u64 },
+}
+
+impl fmt::Display for EventContext {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		fn describe_len(len: &Option<u64>) -> String {
+			match len {
+				Some(len) => format!("of length {}", len),
+				None => "of unknown length".to_owned(),
+			}
+		}
+		match self {
+			EventContext::TopLevel => write!(f, "at the top level"),
+			EventContext::Array { len, index } => {
+				write!(f, "at item #{} of an array {}", index, describe_len(len))
+			}
+			EventContext::MapKey { len, index } => {
+				write!(f, "at key #{} of a map {}", index, describe_len(len))
+			}
+			EventContext::MapValue { len, index } => {
+				write!(f, "at value #{} of a map {}", index, describe_len(len))
+			}
+			EventContext::Tagged(tag) => write!(f, "in an item tagged {}", tag),
+			EventContext::Chunk { major: 2, index } => {
+				write!(f, "at piece #{} of an unknown-length byte string", index)
+			}
+			EventContext::Chunk { index, .. } => {
+				write!(f, "at piece #{} of an unknown-length text string", index)
+			}
+		}
+	}
 }
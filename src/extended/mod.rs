@@ -11,23 +11,6 @@
 pub mod streaming;
 pub mod tree;
 
-macro_rules! config_accessors {
-	($field:ident, $type:ty, $getter:ident, $mut_getter:ident, $setter:ident) => {
-		pub fn $getter(&self) -> &$type {
-			&self.$field
-		}
-
-		pub fn $mut_getter(&mut self) -> &mut $type {
-			&mut self.$field
-		}
-
-		pub fn $setter(&mut self, value: $type) -> &mut Self {
-			self.$field = value;
-			self
-		}
-	};
-}
-
 #[derive(Debug, Clone, Default)]
 pub struct DecodeExtensionConfig {
 	date_time_style: DateTimeDecodeStyle,
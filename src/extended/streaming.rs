@@ -6,17 +6,29 @@
 //! In this way, it is comparable to SAX in the XML world.
 
 use crate::{
-	basic::streaming::{Decoder as BasicDecoder, Encoder as BasicEncoder, Event as BasicEvent},
+	basic::streaming::{
+		BufReadSource, DecodeLimits, Decoder as BasicDecoder, Encoder as BasicEncoder,
+		Event as BasicEvent, ReadSource, SliceSource, Source,
+	},
 	errors::{DecodeError, EncodeError},
 	extended::{DateTimeDecodeStyle, DateTimeEncodeStyle},
 };
+use half::f16;
 use std::{
 	borrow::Cow,
-	io::{Read, Write},
+	io::{BufRead, Read, Write},
 };
 
+#[cfg(feature = "async")]
+use crate::basic::streaming::{
+	AsyncDecoder as BasicAsyncDecoder, AsyncEncoder as BasicAsyncEncoder,
+};
+#[cfg(feature = "chrono")]
+use crate::errors::DecodeErrorKind;
 #[cfg(feature = "chrono")]
 use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+#[cfg(feature = "async")]
+use futures_io::{AsyncRead, AsyncWrite};
 
 use super::{DecodeExtensionConfig, EncodeExtensionConfig};
 
@@ -66,8 +78,14 @@ pub enum Event<'a> {
 	///
 	/// Most notably, simple values 20, 21, 22, and 23 represent false, true, null, and undefined, respectively.
 	Simple(u8),
-	/// A floating-point number.
-	Float(f64),
+	/// A half-precision floating-point number.
+	///
+	/// As with [basic events](`BasicEvent::Float16`), floats keep the width and exact bits they were encoded with.
+	Float16(f16),
+	/// A single-precision floating-point number.
+	Float32(f32),
+	/// A double-precision floating-point number.
+	Float64(f64),
 	/// The end of an unknown-length item.
 	Break,
 
@@ -95,7 +113,9 @@ impl Event<'_> {
 			Self::UnknownLengthMap => Event::UnknownLengthMap,
 			Self::UnrecognizedTag(t) => Event::UnrecognizedTag(t),
 			Self::Simple(s) => Event::Simple(s),
-			Self::Float(f) => Event::Float(f),
+			Self::Float16(f) => Event::Float16(f),
+			Self::Float32(f) => Event::Float32(f),
+			Self::Float64(f) => Event::Float64(f),
 			Self::Break => Event::Break,
 
 			#[cfg(feature = "chrono")]
@@ -148,49 +168,45 @@ impl Event<'_> {
 			None => None,
 		}
 	}
-}
-
-/// A streaming decoder for CBOR with extensions.
-#[derive(Debug, Clone)]
-pub struct Decoder<T: Read> {
-	basic: BasicDecoder<T>,
-	config: DecodeExtensionConfig,
-}
-
-include!("forward_config_accessors.in.rs");
 
-impl<T: Read> Decoder<T> {
-	pub(crate) fn new_from_config(basic: BasicDecoder<T>, config: DecodeExtensionConfig) -> Self {
-		Self { basic, config }
-	}
-
-	pub fn new_from_basic_decoder(basic: BasicDecoder<T>) -> Self {
-		Self::new_from_config(basic, Default::default())
+	/// Get the value of a float, of any width, as an [`f64`].
+	///
+	/// This is a convenience alias for [the basic `Event`'s `as_f64`](`BasicEvent::as_f64`).
+	pub fn as_f64(&self) -> Option<f64> {
+		match *self {
+			Self::Float16(f) => BasicEvent::Float16(f).as_f64(),
+			Self::Float32(f) => BasicEvent::Float32(f).as_f64(),
+			Self::Float64(f) => Some(f),
+			_ => None,
+		}
 	}
 
-	pub fn new(source: T) -> Self {
-		Self::new_from_basic_decoder(BasicDecoder::new(source))
+	/// Create a float event of the narrowest width that holds the value exactly.
+	///
+	/// This is a convenience alias for [the basic `Event`'s `create_float`](`BasicEvent::create_float`),
+	/// except that it returns an extended [`Event`] instead.
+	pub fn create_float(val: f64) -> Event<'static> {
+		match BasicEvent::create_float(val) {
+			BasicEvent::Float16(f) => Event::Float16(f),
+			BasicEvent::Float32(f) => Event::Float32(f),
+			BasicEvent::Float64(f) => Event::Float64(f),
+			_ => unreachable!(),
+		}
 	}
+}
 
-	forward_config_accessors!(
-		DateTimeDecodeStyle,
-		date_time_style,
-		date_time_style_mut,
-		set_date_time_style,
-		"the way date-times are decoded."
-	);
-
-	/// Pull an event from the decoder.
-	///
-	/// Note that the resulting event does not, at present, actually borrow the decoder.
-	/// At the moment, the decoder isn't zero-copy.
-	/// Even though [`Event`] supports borrowing the contents of byte- and text-strings,
-	/// they are never borrowed in decoding, only in encoding.
-	/// However, `next_event` is typed as if it were zero-copy for forward compatibility.
-	pub fn next_event(&mut self) -> Result<Event, DecodeError> {
-		use DateTimeDecodeStyle as DateTimeStyle;
+/// The first step of turning a basic event into an extended one.
+enum Translation<'a> {
+	/// The basic event turns directly into this extended event.
+	Event(Event<'a>),
+	/// The basic event is a tag whose extended meaning depends on the tagged item.
+	#[cfg(feature = "chrono")]
+	Tagged(u64),
+}
 
-		Ok(match self.basic.next_event()?.into_owned() {
+impl DecodeExtensionConfig {
+	fn translate<'a>(&self, event: BasicEvent<'a>) -> Translation<'a> {
+		Translation::Event(match event {
 			BasicEvent::Unsigned(n) => Event::Unsigned(n),
 			BasicEvent::Signed(n) => Event::Signed(n),
 			BasicEvent::ByteString(b) => Event::ByteString(b),
@@ -202,57 +218,117 @@ impl<T: Read> Decoder<T> {
 			BasicEvent::Map(len) => Event::Map(len),
 			BasicEvent::UnknownLengthMap => Event::UnknownLengthMap,
 			BasicEvent::Simple(s) => Event::Simple(s),
-			BasicEvent::Float(f) => Event::Float(f),
+			BasicEvent::Float16(f) => Event::Float16(f),
+			BasicEvent::Float32(f) => Event::Float32(f),
+			BasicEvent::Float64(f) => Event::Float64(f),
 			BasicEvent::Break => Event::Break,
 
-			BasicEvent::Tag(tag) => match tag {
-				0 => match self.config.date_time_style {
-					DateTimeStyle::None => Event::UnrecognizedTag(0),
-					#[cfg(feature = "chrono")]
-					DateTimeStyle::Chrono => match self.basic.next_event()? {
-						BasicEvent::TextString(t) => {
-							Event::ChronoDateTime(DateTime::parse_from_rfc3339(&t)?)
-						}
-						_ => return Err(DecodeError::TagInvalid(0)),
-					},
-				},
-				1 => match self.config.date_time_style {
-					DateTimeStyle::None => Event::UnrecognizedTag(1),
-					#[cfg(feature = "chrono")]
-					DateTimeStyle::Chrono => match self.basic.next_event()? {
-						BasicEvent::Unsigned(n) => {
-							let time: i64 = n.try_into().map_err(|_| DecodeError::TagInvalid(1))?;
-							Event::ChronoDateTime(Utc.timestamp(time, 0).into())
-						}
-						BasicEvent::Signed(n) => match BasicEvent::interpret_signed_checked(n) {
-							Some(time) => Event::ChronoDateTime(Utc.timestamp(time, 0).into()),
-							None => return Err(DecodeError::TagInvalid(1)),
-						},
-						BasicEvent::Float(f) => {
-							let seconds = (f - f.fract()) as i64;
-							let nanos = (f.fract() * 1_000_000_000f64) as i64;
-							Event::ChronoDateTime(
-								Utc.timestamp_nanos(seconds * 1_000_000_000 + nanos).into(),
-							)
-						}
-						_ => return Err(DecodeError::TagInvalid(0)),
-					},
-				},
+			BasicEvent::Tag(tag) => match (tag, &self.date_time_style) {
+				#[cfg(feature = "chrono")]
+				(0 | 1, DateTimeDecodeStyle::Chrono) => return Translation::Tagged(tag),
 				_ => Event::UnrecognizedTag(tag),
 			},
 		})
 	}
 
-	/// Check whether it is possible to end the decoding now.
-	///
-	/// See [the basic counterpart](`crate::basic::streaming::Decoder::ready_to_finish`) for details.
-	pub fn ready_to_finish(&self) -> bool {
-		self.basic.ready_to_finish()
+	/// Finish translating a [`Translation::Tagged`], given the first event of the tagged item.
+	#[cfg(feature = "chrono")]
+	fn translate_tagged<'a>(
+		&self,
+		tag: u64,
+		content: BasicEvent<'a>,
+	) -> Result<Event<'a>, DecodeErrorKind> {
+		Ok(match tag {
+			0 => match content {
+				BasicEvent::TextString(t) => {
+					Event::ChronoDateTime(DateTime::parse_from_rfc3339(&t)?)
+				}
+				_ => return Err(DecodeErrorKind::TagInvalid(0)),
+			},
+			1 => {
+				let (seconds, nanos) = match (&content, content.as_f64()) {
+					(BasicEvent::Unsigned(n), _) => ((*n).try_into().ok(), 0),
+					(BasicEvent::Signed(n), _) => (BasicEvent::interpret_signed_checked(*n), 0),
+					(_, Some(f)) if f.is_finite() => {
+						let seconds = f.floor();
+						// The fraction is less than 1, but rounding could still turn it into a whole second.
+						let nanos = (((f - seconds) * 1_000_000_000f64) as u32).min(999_999_999);
+						// This saturates, so a huge float becomes a timestamp that chrono will reject.
+						(Some(seconds as i64), nanos)
+					}
+					_ => return Err(DecodeErrorKind::TagInvalid(1)),
+				};
+				match seconds.and_then(|seconds| Utc.timestamp_opt(seconds, nanos).single()) {
+					Some(dt) => Event::ChronoDateTime(dt.into()),
+					None => return Err(DecodeErrorKind::TagInvalid(1)),
+				}
+			}
+			_ => unreachable!(),
+		})
+	}
+}
+
+impl EncodeExtensionConfig {
+	/// Turn an extended event into a basic one, which may need to be preceded by a tag.
+	fn translate<'a>(&self, event: Event<'a>) -> (Option<u64>, BasicEvent<'a>) {
+		match event {
+			Event::Unsigned(n) => (None, BasicEvent::Unsigned(n)),
+			Event::Signed(n) => (None, BasicEvent::Signed(n)),
+			Event::ByteString(b) => (None, BasicEvent::ByteString(b)),
+			Event::UnknownLengthByteString => (None, BasicEvent::UnknownLengthByteString),
+			Event::TextString(t) => (None, BasicEvent::TextString(t)),
+			Event::UnknownLengthTextString => (None, BasicEvent::UnknownLengthTextString),
+			Event::Array(len) => (None, BasicEvent::Array(len)),
+			Event::UnknownLengthArray => (None, BasicEvent::UnknownLengthArray),
+			Event::Map(len) => (None, BasicEvent::Map(len)),
+			Event::UnknownLengthMap => (None, BasicEvent::UnknownLengthMap),
+			Event::UnrecognizedTag(t) => (None, BasicEvent::Tag(t)),
+			Event::Simple(s) => (None, BasicEvent::Simple(s)),
+			Event::Float16(f) => (None, BasicEvent::Float16(f)),
+			Event::Float32(f) => (None, BasicEvent::Float32(f)),
+			Event::Float64(f) => (None, BasicEvent::Float64(f)),
+			Event::Break => (None, BasicEvent::Break),
+
+			#[cfg(feature = "chrono")]
+			Event::ChronoDateTime(dt) => match self.date_time_style {
+				DateTimeEncodeStyle::PreferText => (
+					Some(0),
+					BasicEvent::TextString(Cow::Owned(
+						dt.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true),
+					)),
+				),
+				DateTimeEncodeStyle::PreferNumeric => (
+					Some(1),
+					match dt.timestamp_subsec_nanos() {
+						0 => BasicEvent::create_signed(dt.timestamp()),
+						_ => BasicEvent::create_float(
+							dt.timestamp() as f64
+								+ (dt.timestamp_subsec_nanos() as f64) / 1_000_000_000f64,
+						),
+					},
+				),
+			},
+		}
+	}
+}
+
+/// A streaming decoder for CBOR with extensions.
+#[derive(Debug, Clone)]
+pub struct Decoder<S> {
+	basic: BasicDecoder<S>,
+	config: DecodeExtensionConfig,
+}
+
+include!("forward_config_accessors.in.rs");
+
+impl<T: Read> Decoder<ReadSource<T>> {
+	pub fn new(source: T) -> Self {
+		Self::new_from_basic_decoder(BasicDecoder::new(source))
 	}
 
 	/// End the decoding.
 	///
-	/// This is [checked](`Self::ready_to_finish`) and will return [`DecodeError::Insufficient`] if the CBOR is incomplete.
+	/// This is [checked](`Self::ready_to_finish`) and will return [`DecodeErrorKind::Insufficient`](`crate::errors::DecodeErrorKind::Insufficient`) if the CBOR is incomplete.
 	/// If you've performed the check already, try [`Self::force_finish`].
 	pub fn finish(self) -> Result<T, DecodeError> {
 		self.basic.finish()
@@ -266,6 +342,176 @@ impl<T: Read> Decoder<T> {
 	}
 }
 
+impl<T: BufRead> Decoder<BufReadSource<T>> {
+	/// Create a decoder that reads from a [`BufRead`]er.
+	///
+	/// See [the basic counterpart](`crate::basic::streaming::Decoder::from_buf_read`) for details.
+	pub fn from_buf_read(source: T) -> Self {
+		Self::new_from_basic_decoder(BasicDecoder::from_buf_read(source))
+	}
+
+	/// End the decoding.
+	///
+	/// This is [checked](`Self::ready_to_finish`) and will return [`DecodeErrorKind::Insufficient`](`crate::errors::DecodeErrorKind::Insufficient`) if the CBOR is incomplete.
+	/// If you've performed the check already, try [`Self::force_finish`].
+	pub fn finish(self) -> Result<T, DecodeError> {
+		self.basic.finish()
+	}
+
+	/// End the decoding, without checking whether the decoder is finished or not.
+	///
+	/// This returns a reader that behaves as if it were the original reader.
+	pub fn force_finish(self) -> impl BufRead {
+		self.basic.force_finish()
+	}
+}
+
+impl<'a> Decoder<SliceSource<'a>> {
+	/// Create a decoder that reads from a byte slice.
+	///
+	/// See [the basic counterpart](`crate::basic::streaming::Decoder::from_slice`) for details.
+	pub fn from_slice(input: &'a [u8]) -> Self {
+		Self::new_from_basic_decoder(BasicDecoder::from_slice(input))
+	}
+
+	/// End the decoding.
+	///
+	/// This is [checked](`Self::ready_to_finish`) and will return [`DecodeErrorKind::Insufficient`](`crate::errors::DecodeErrorKind::Insufficient`) if the CBOR is incomplete.
+	/// On success, it returns the part of the slice that has not been decoded.
+	pub fn finish(self) -> Result<&'a [u8], DecodeError> {
+		self.basic.finish()
+	}
+
+	/// End the decoding, without checking whether the decoder is finished or not.
+	///
+	/// This returns the part of the slice that has not been decoded.
+	pub fn force_finish(self) -> &'a [u8] {
+		self.basic.force_finish()
+	}
+}
+
+impl<'de, S: Source<'de>> Decoder<S> {
+	pub(crate) fn new_from_config(basic: BasicDecoder<S>, config: DecodeExtensionConfig) -> Self {
+		Self { basic, config }
+	}
+
+	pub fn new_from_basic_decoder(basic: BasicDecoder<S>) -> Self {
+		Self::new_from_config(basic, Default::default())
+	}
+
+	forward_config_accessors!(
+		DateTimeDecodeStyle,
+		date_time_style,
+		date_time_style_mut,
+		set_date_time_style,
+		"the way date-times are decoded."
+	);
+
+	forward_config_accessors!(
+		basic: DecodeLimits,
+		limits,
+		limits_mut,
+		set_limits,
+		"the limits on what the decoder will accept."
+	);
+
+	forward_config_accessors!(
+		basic: bool,
+		require_preferred,
+		require_preferred_mut,
+		set_require_preferred,
+		"whether the decoder only accepts preferred serialization.",
+		"See [the basic counterpart](`crate::basic::streaming::Decoder::set_require_preferred`) for details."
+	);
+
+	forward_config_accessors!(
+		basic: bool,
+		require_definite,
+		require_definite_mut,
+		set_require_definite,
+		"whether the decoder rejects indefinite-length items.",
+		"See [the basic counterpart](`crate::basic::streaming::Decoder::set_require_definite`) for details."
+	);
+
+	/// Get how many bytes of input have been decoded so far.
+	///
+	/// See [the basic counterpart](`crate::basic::streaming::Decoder::position`) for details.
+	pub fn position(&self) -> u64 {
+		self.basic.position()
+	}
+
+	/// Get how deeply the innermost item that's still pending is nested, or 0 between items.
+	pub(crate) fn depth(&self) -> usize {
+		self.basic.depth()
+	}
+
+	/// Pull an event from the decoder.
+	///
+	/// Whether the contents of byte- and text-strings are borrowed depends on the [`Source`];
+	/// see [the basic counterpart](`crate::basic::streaming::Decoder::next_event`) for details.
+	///
+	/// If a tag's content is invalid, the error's [offset](`DecodeError::offset`) is the start of the content.
+	pub fn next_event(&mut self) -> Result<Event<'de>, DecodeError> {
+		match self.config.translate(self.basic.next_event()?) {
+			Translation::Event(event) => Ok(event),
+			#[cfg(feature = "chrono")]
+			Translation::Tagged(tag) => {
+				let offset = self.basic.position();
+				self.config
+					.translate_tagged(tag, self.basic.next_event()?)
+					.map_err(|kind| DecodeError::new(kind, offset))
+			}
+		}
+	}
+
+	/// Look at the next event without consuming it.
+	///
+	/// If the next event is a tag whose meaning depends on the tagged item, that item's first event is peeked at too.
+	/// See [the basic counterpart](`crate::basic::streaming::Decoder::peek_event`) for details.
+	pub fn peek_event(&mut self) -> Result<Event<'de>, DecodeError> {
+		match self.config.translate(self.basic.peek_event()?) {
+			Translation::Event(event) => Ok(event),
+			#[cfg(feature = "chrono")]
+			Translation::Tagged(tag) => {
+				let (content, offset) = self.basic.peek_tagged_event()?;
+				self.config
+					.translate_tagged(tag, content)
+					.map_err(|kind| DecodeError::new(kind, offset))
+			}
+		}
+	}
+
+	/// Look at the major type of the next event without consuming it.
+	///
+	/// This is the major type of the underlying CBOR, so a date/time, for instance, is reported as a tag (6).
+	/// See [the basic counterpart](`crate::basic::streaming::Decoder::peek_major_type`) for details.
+	pub fn peek_major_type(&mut self) -> Result<u8, DecodeError> {
+		self.basic.peek_major_type()
+	}
+
+	/// Skip over the next data item, including everything nested inside it.
+	///
+	/// Tags are skipped along with the items they apply to, without being interpreted.
+	/// See [the basic counterpart](`crate::basic::streaming::Decoder::skip_item`) for details.
+	pub fn skip_item(&mut self) -> Result<bool, DecodeError> {
+		self.basic.skip_item()
+	}
+
+	/// Read the next data item without decoding it, and return its encoded bytes exactly as they appear in the input.
+	///
+	/// See [the basic counterpart](`crate::basic::streaming::Decoder::read_raw_item`) for details.
+	pub fn read_raw_item(&mut self) -> Result<Option<Cow<'de, [u8]>>, DecodeError> {
+		self.basic.read_raw_item()
+	}
+
+	/// Check whether it is possible to end the decoding now.
+	///
+	/// See [the basic counterpart](`crate::basic::streaming::Decoder::ready_to_finish`) for details.
+	pub fn ready_to_finish(&self) -> bool {
+		self.basic.ready_to_finish()
+	}
+}
+
 /// A streaming encoder for CBOR with extensions.
 #[derive(Debug, Clone)]
 pub struct Encoder<T: Write> {
@@ -286,6 +532,13 @@ impl<T: Write> Encoder<T> {
 		Self::new_from_basic_encoder(BasicEncoder::new(dest))
 	}
 
+	/// Make an encoder for a CBOR Sequence.
+	///
+	/// See [the basic counterpart](`BasicEncoder::new_sequence`) for details.
+	pub fn new_sequence(dest: T) -> Self {
+		Self::new_from_basic_encoder(BasicEncoder::new_sequence(dest))
+	}
+
 	forward_config_accessors!(
 		DateTimeEncodeStyle,
 		date_time_style,
@@ -296,48 +549,201 @@ impl<T: Write> Encoder<T> {
 
 	/// Feed an event to the encoder.
 	pub fn feed_event(&mut self, event: Event) -> Result<(), EncodeError> {
-		let basic_event = match event {
-			Event::Unsigned(n) => BasicEvent::Unsigned(n),
-			Event::Signed(n) => BasicEvent::Signed(n),
-			Event::ByteString(b) => BasicEvent::ByteString(b),
-			Event::UnknownLengthByteString => BasicEvent::UnknownLengthByteString,
-			Event::TextString(t) => BasicEvent::TextString(t),
-			Event::UnknownLengthTextString => BasicEvent::UnknownLengthTextString,
-			Event::Array(len) => BasicEvent::Array(len),
-			Event::UnknownLengthArray => BasicEvent::UnknownLengthArray,
-			Event::Map(len) => BasicEvent::Map(len),
-			Event::UnknownLengthMap => BasicEvent::UnknownLengthMap,
-			Event::UnrecognizedTag(t) => BasicEvent::Tag(t),
-			Event::Simple(s) => BasicEvent::Simple(s),
-			Event::Float(f) => BasicEvent::Float(f),
-			Event::Break => BasicEvent::Break,
+		let (tag, basic_event) = self.config.translate(event);
+		if let Some(tag) = tag {
+			self.dest.feed_event(BasicEvent::Tag(tag))?;
+		}
+		self.dest.feed_event(basic_event)
+	}
+
+	pub fn ready_to_finish(&self) -> bool {
+		self.dest.ready_to_finish()
+	}
+
+	/// End the encoding.
+	///
+	/// See [the basic counterpart](`BasicEncoder::finish`) for details.
+	pub fn finish(self) -> Result<T, EncodeError> {
+		self.dest.finish()
+	}
+
+	/// End the encoding, without checking whether the encoder is finished or not.
+	///
+	/// See [the basic counterpart](`BasicEncoder::force_finish`) for details.
+	pub fn force_finish(self) -> T {
+		self.dest.force_finish()
+	}
+}
+
+/// An asynchronous streaming decoder for CBOR with extensions.
+///
+/// See [the basic counterpart](`crate::basic::streaming::AsyncDecoder`) for details.
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct AsyncDecoder<T: AsyncRead + Unpin> {
+	basic: BasicAsyncDecoder<T>,
+	config: DecodeExtensionConfig,
+}
+
+#[cfg(feature = "async")]
+impl<T: AsyncRead + Unpin> AsyncDecoder<T> {
+	pub fn new_from_basic_decoder(basic: BasicAsyncDecoder<T>) -> Self {
+		Self {
+			basic,
+			config: Default::default(),
+		}
+	}
 
+	pub fn new(source: T) -> Self {
+		Self::new_from_basic_decoder(BasicAsyncDecoder::new(source))
+	}
+
+	forward_config_accessors!(
+		DateTimeDecodeStyle,
+		date_time_style,
+		date_time_style_mut,
+		set_date_time_style,
+		"the way date-times are decoded."
+	);
+
+	forward_config_accessors!(
+		basic: DecodeLimits,
+		limits,
+		limits_mut,
+		set_limits,
+		"the limits on what the decoder will accept."
+	);
+
+	forward_config_accessors!(
+		basic: bool,
+		require_preferred,
+		require_preferred_mut,
+		set_require_preferred,
+		"whether the decoder only accepts preferred serialization.",
+		"See [the basic counterpart](`crate::basic::streaming::Decoder::set_require_preferred`) for details."
+	);
+
+	forward_config_accessors!(
+		basic: bool,
+		require_definite,
+		require_definite_mut,
+		set_require_definite,
+		"whether the decoder rejects indefinite-length items.",
+		"See [the basic counterpart](`crate::basic::streaming::Decoder::set_require_definite`) for details."
+	);
+
+	/// Get how many bytes of input have been decoded so far.
+	///
+	/// See [the basic counterpart](`crate::basic::streaming::Decoder::position`) for details.
+	pub fn position(&self) -> u64 {
+		self.basic.position()
+	}
+
+	/// Pull an event from the decoder.
+	///
+	/// Because the input arrives piece by piece, the contents of byte- and text-strings are always copied.
+	pub async fn next_event(&mut self) -> Result<Event<'static>, DecodeError> {
+		match self.config.translate(self.basic.next_event().await?) {
+			Translation::Event(event) => Ok(event),
 			#[cfg(feature = "chrono")]
-			Event::ChronoDateTime(dt) => match self.config.date_time_style {
-				DateTimeEncodeStyle::PreferText => {
-					self.dest.feed_event(BasicEvent::Tag(0))?;
-					BasicEvent::TextString(Cow::Owned(
-						dt.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true),
-					))
-				}
-				DateTimeEncodeStyle::PreferNumeric => {
-					self.dest.feed_event(BasicEvent::Tag(1))?;
-					match dt.timestamp_subsec_nanos() {
-						0 => BasicEvent::create_signed(dt.timestamp()),
-						_ => BasicEvent::Float(
-							dt.timestamp() as f64
-								+ (dt.timestamp_subsec_nanos() as f64) / 1_000_000_000f64,
-						),
-					}
-				}
-			},
-		};
-		self.dest.feed_event(basic_event)
+			Translation::Tagged(tag) => {
+				let offset = self.basic.position();
+				self.config
+					.translate_tagged(tag, self.basic.next_event().await?)
+					.map_err(|kind| DecodeError::new(kind, offset))
+			}
+		}
+	}
+
+	/// Check whether it is possible to end the decoding now.
+	///
+	/// See [the basic counterpart](`crate::basic::streaming::Decoder::ready_to_finish`) for details.
+	pub fn ready_to_finish(&self) -> bool {
+		self.basic.ready_to_finish()
+	}
+
+	/// End the decoding.
+	///
+	/// This is [checked](`Self::ready_to_finish`) and will return [`DecodeErrorKind::Insufficient`](`crate::errors::DecodeErrorKind::Insufficient`) if the CBOR is incomplete.
+	/// If you've performed the check already, try [`Self::force_finish`].
+	pub fn finish(self) -> Result<T, DecodeError> {
+		self.basic.finish()
+	}
+
+	/// End the decoding, without checking whether the decoder is finished or not.
+	///
+	/// See [the basic counterpart](`crate::basic::streaming::AsyncDecoder::force_finish`) for details.
+	pub fn force_finish(self) -> impl AsyncRead + Unpin {
+		self.basic.force_finish()
+	}
+}
+
+/// An asynchronous streaming encoder for CBOR with extensions.
+///
+/// See [the basic counterpart](`crate::basic::streaming::AsyncEncoder`) for details.
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct AsyncEncoder<T: AsyncWrite + Unpin> {
+	dest: BasicAsyncEncoder<T>,
+	config: EncodeExtensionConfig,
+}
+
+#[cfg(feature = "async")]
+impl<T: AsyncWrite + Unpin> AsyncEncoder<T> {
+	/// Make an encoder that adds extensions to a basic encoder, with the default settings.
+	pub fn new_from_basic_encoder(dest: BasicAsyncEncoder<T>) -> Self {
+		Self {
+			dest,
+			config: Default::default(),
+		}
+	}
+
+	/// Make an encoder that writes a single top-level item to `dest`.
+	pub fn new(dest: T) -> Self {
+		Self::new_from_basic_encoder(BasicAsyncEncoder::new(dest))
+	}
+
+	/// Make an encoder for a CBOR Sequence.
+	///
+	/// See [the basic counterpart](`BasicAsyncEncoder::new_sequence`) for details.
+	pub fn new_sequence(dest: T) -> Self {
+		Self::new_from_basic_encoder(BasicAsyncEncoder::new_sequence(dest))
+	}
+
+	forward_config_accessors!(
+		DateTimeEncodeStyle,
+		date_time_style,
+		date_time_style_mut,
+		set_date_time_style,
+		"the way date-times are encoded."
+	);
+
+	/// Feed an event to the encoder.
+	pub async fn feed_event(&mut self, event: Event<'_>) -> Result<(), EncodeError> {
+		let (tag, basic_event) = self.config.translate(event);
+		if let Some(tag) = tag {
+			self.dest.feed_event(BasicEvent::Tag(tag)).await?;
+		}
+		self.dest.feed_event(basic_event).await
 	}
 
 	pub fn ready_to_finish(&self) -> bool {
 		self.dest.ready_to_finish()
 	}
+
+	/// End the encoding.
+	///
+	/// See [the basic counterpart](`crate::basic::streaming::AsyncEncoder::finish`) for details.
+	pub async fn finish(self) -> Result<T, EncodeError> {
+		self.dest.finish().await
+	}
+
+	/// End the encoding, without checking whether the encoder is finished or not.
+	///
+	/// See [the basic counterpart](`crate::basic::streaming::AsyncEncoder::force_finish`) for details.
+	pub fn force_finish(self) -> T {
+		self.dest.force_finish()
+	}
 }
 
 #[cfg(test)]
@@ -345,9 +751,135 @@ impl<T: Write> Encoder<T> {
 mod test {
 	use super::*;
 	#[cfg(feature = "chrono")]
-	use chrono::{TimeZone, Utc};
+	use chrono::{NaiveDate, TimeZone, Utc};
 	use std::io::Cursor;
 
+	#[cfg(feature = "chrono")]
+	fn utc(
+		year: i32,
+		month: u32,
+		day: u32,
+		hour: u32,
+		min: u32,
+		sec: u32,
+		milli: u32,
+	) -> DateTime<FixedOffset> {
+		let naive = NaiveDate::from_ymd_opt(year, month, day)
+			.unwrap()
+			.and_hms_milli_opt(hour, min, sec, milli)
+			.unwrap();
+		Utc.from_utc_datetime(&naive).into()
+	}
+
+	#[test]
+	fn decode_slice_borrowed() {
+		let mut decoder = Decoder::from_slice(b"\xC2\x43abc");
+		assert_eq!(decoder.next_event().unwrap(), Event::UnrecognizedTag(2));
+		assert!(matches!(
+			decoder.next_event().unwrap(),
+			Event::ByteString(Cow::Borrowed(b"abc"))
+		));
+		assert!(decoder.finish().unwrap().is_empty());
+	}
+
+	#[test]
+	fn skip_item() {
+		let mut decoder = Decoder::from_slice(b"\x82\xC1\x1B\x7F\xFF\xFF\xFF\xFF\xFF\xFF\xFF\x01");
+		#[cfg(feature = "chrono")]
+		decoder.set_date_time_style(crate::extended::DateTimeDecodeStyle::Chrono);
+		assert_eq!(decoder.next_event().unwrap(), Event::Array(2));
+		// This would be an invalid date/time, but it isn't looked at.
+		assert!(decoder.skip_item().unwrap());
+		assert_eq!(decoder.next_event().unwrap(), Event::Unsigned(1));
+		assert!(decoder.ready_to_finish());
+	}
+
+	#[test]
+	fn read_raw_item() {
+		let mut decoder = Decoder::from_slice(b"\x82\xC1\x1A\x00\x01\x00\x00\x01");
+		assert_eq!(decoder.next_event().unwrap(), Event::Array(2));
+		assert!(matches!(
+			decoder.read_raw_item().unwrap(),
+			Some(Cow::Borrowed(b"\xC1\x1A\x00\x01\x00\x00"))
+		));
+		assert_eq!(decoder.next_event().unwrap(), Event::Unsigned(1));
+	}
+
+	#[test]
+	fn peek_event() {
+		let mut decoder = Decoder::from_slice(b"\xC2\x43abc");
+		assert_eq!(decoder.peek_major_type().unwrap(), 6);
+		assert_eq!(decoder.peek_event().unwrap(), Event::UnrecognizedTag(2));
+		assert_eq!(decoder.next_event().unwrap(), Event::UnrecognizedTag(2));
+		assert_eq!(
+			decoder.peek_event().unwrap(),
+			Event::ByteString(Cow::Borrowed(b"abc"))
+		);
+		assert_eq!(decoder.position(), 1);
+	}
+
+	#[cfg(feature = "async")]
+	#[test]
+	fn decode_async() {
+		futures::executor::block_on(async {
+			let mut decoder = AsyncDecoder::new(futures::io::Cursor::new(b"\xC2\x43abc"));
+			assert_eq!(
+				decoder.next_event().await.unwrap(),
+				Event::UnrecognizedTag(2)
+			);
+			assert_eq!(
+				decoder.next_event().await.unwrap(),
+				Event::ByteString(Cow::Borrowed(b"abc"))
+			);
+			decoder.finish().unwrap();
+		});
+	}
+
+	#[cfg(feature = "async")]
+	#[test]
+	fn encode_async_sequence() {
+		futures::executor::block_on(async {
+			let mut encoder = AsyncEncoder::new_sequence(futures::io::Cursor::new(Vec::new()));
+			encoder.feed_event(Event::UnrecognizedTag(2)).await.unwrap();
+			encoder
+				.feed_event(Event::ByteString(Cow::Borrowed(b"abc")))
+				.await
+				.unwrap();
+			assert!(encoder.ready_to_finish());
+			encoder.feed_event(Event::Unsigned(1)).await.unwrap();
+			let buf = encoder.finish().await.unwrap().into_inner();
+			assert_eq!(&buf, b"\xC2\x43abc\x01");
+
+			// Without a sequence, the second item is too many.
+			let mut encoder = AsyncEncoder::new(futures::io::Cursor::new(Vec::new()));
+			encoder.feed_event(Event::Unsigned(1)).await.unwrap();
+			assert!(matches!(
+				encoder.feed_event(Event::Unsigned(2)).await,
+				Err(EncodeError::Excess)
+			));
+		});
+	}
+
+	#[cfg(all(feature = "async", feature = "chrono"))]
+	#[test]
+	fn async_chrono_round_trip() {
+		futures::executor::block_on(async {
+			let dt: DateTime<FixedOffset> = utc(1990, 12, 31, 12, 34, 56, 0);
+			let mut encoder = AsyncEncoder::new(futures::io::Cursor::new(Vec::new()));
+			encoder.feed_event(Event::ChronoDateTime(dt)).await.unwrap();
+			let buf = encoder.finish().await.unwrap().into_inner();
+			assert_eq!(&buf, b"\xC0\x741990-12-31T12:34:56Z");
+
+			let mut decoder = AsyncDecoder::new(futures::io::Cursor::new(buf));
+			decoder.set_date_time_style(crate::extended::DateTimeDecodeStyle::Chrono);
+			assert_eq!(
+				decoder.next_event().await.unwrap(),
+				Event::ChronoDateTime(dt)
+			);
+			decoder.finish().unwrap();
+		});
+	}
+
 	#[cfg(feature = "chrono")]
 	#[test]
 	fn decode_chrono_text_datetime() {
@@ -356,7 +888,7 @@ mod test {
 				.set_date_time_style(crate::extended::DateTimeDecodeStyle::Chrono)
 				.next_event()
 				.unwrap(),
-			Event::ChronoDateTime(Utc.ymd(1990, 12, 31).and_hms(12, 34, 56).into())
+			Event::ChronoDateTime(utc(1990, 12, 31, 12, 34, 56, 0))
 		);
 	}
 
@@ -368,7 +900,7 @@ mod test {
 				.set_date_time_style(crate::extended::DateTimeDecodeStyle::Chrono)
 				.next_event()
 				.unwrap(),
-			Event::ChronoDateTime(Utc.ymd(1970, 01, 01).and_hms(00, 00, 04).into())
+			Event::ChronoDateTime(utc(1970, 1, 1, 0, 0, 4, 0))
 		);
 	}
 
@@ -380,7 +912,7 @@ mod test {
 				.set_date_time_style(crate::extended::DateTimeDecodeStyle::Chrono)
 				.next_event()
 				.unwrap(),
-			Event::ChronoDateTime(Utc.ymd(1969, 12, 31).and_hms(23, 59, 59).into())
+			Event::ChronoDateTime(utc(1969, 12, 31, 23, 59, 59, 0))
 		);
 	}
 
@@ -392,10 +924,73 @@ mod test {
 				.set_date_time_style(crate::extended::DateTimeDecodeStyle::Chrono)
 				.next_event()
 				.unwrap(),
-			Event::ChronoDateTime(Utc.ymd(1970, 01, 01).and_hms_milli(00, 00, 01, 250).into())
+			Event::ChronoDateTime(utc(1970, 1, 1, 0, 0, 1, 250))
 		);
 	}
 
+	#[cfg(feature = "chrono")]
+	#[test]
+	fn decode_chrono_numeric_datetime_fractional_negative() {
+		assert_eq!(
+			Decoder::new(Cursor::new(b"\xC1\xF9\xBE\x00"))
+				.set_date_time_style(crate::extended::DateTimeDecodeStyle::Chrono)
+				.next_event()
+				.unwrap(),
+			Event::ChronoDateTime(utc(1969, 12, 31, 23, 59, 58, 500))
+		);
+	}
+
+	#[cfg(feature = "chrono")]
+	#[test]
+	fn decode_chrono_numeric_datetime_out_of_range() {
+		for input in [
+			&b"\xC1\x1B\x7F\xFF\xFF\xFF\xFF\xFF\xFF\xFF"[..],
+			&b"\xC1\x1B\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF"[..],
+			&b"\xC1\x3B\x7F\xFF\xFF\xFF\xFF\xFF\xFF\xFF"[..],
+			&b"\xC1\xFB\x7E\x37\xE4\x3C\x88\x00\x75\x9C"[..],
+			&b"\xC1\xF9\x7C\x00"[..],
+			&b"\xC1\xF9\xFC\x00"[..],
+			&b"\xC1\xF9\x7E\x00"[..],
+		] {
+			let mut decoder = Decoder::from_slice(input);
+			decoder.set_date_time_style(crate::extended::DateTimeDecodeStyle::Chrono);
+			assert!(
+				matches!(
+					decoder.next_event().map_err(DecodeError::into_kind),
+					Err(DecodeErrorKind::TagInvalid(1))
+				),
+				"{:X?}",
+				input
+			);
+		}
+	}
+
+	#[cfg(feature = "chrono")]
+	#[test]
+	fn peek_chrono() {
+		let mut decoder = Decoder::from_slice(b"\x82\xC1\x04\xC1\x60");
+		decoder.set_date_time_style(crate::extended::DateTimeDecodeStyle::Chrono);
+		assert_eq!(decoder.next_event().unwrap(), Event::Array(2));
+		let dt = Event::ChronoDateTime(utc(1970, 1, 1, 0, 0, 4, 0));
+		assert_eq!(decoder.peek_event().unwrap(), dt);
+		assert_eq!(decoder.position(), 1);
+		assert_eq!(decoder.next_event().unwrap(), dt);
+		let error = decoder.peek_event().unwrap_err();
+		assert!(matches!(error.kind(), DecodeErrorKind::TagInvalid(1)));
+		assert_eq!(error.offset(), 4);
+		assert_eq!(decoder.position(), 3);
+	}
+
+	#[cfg(feature = "chrono")]
+	#[test]
+	fn decode_chrono_fuzz() {
+		for input in crate::basic::streaming::test::fuzz_inputs(5_000) {
+			let mut decoder = Decoder::from_slice(&input);
+			decoder.set_date_time_style(crate::extended::DateTimeDecodeStyle::Chrono);
+			while decoder.next_event().is_ok() {}
+		}
+	}
+
 	#[cfg(feature = "chrono")]
 	#[test]
 	fn encode_chrono_text_datetime() {
@@ -405,9 +1000,7 @@ mod test {
 			enc.date_time_style(),
 			&crate::extended::DateTimeEncodeStyle::PreferText
 		);
-		enc.feed_event(Event::ChronoDateTime(
-			Utc.ymd(1990, 12, 31).and_hms(12, 34, 56).into(),
-		));
+		enc.feed_event(Event::ChronoDateTime(utc(1990, 12, 31, 12, 34, 56, 0)));
 		assert!(enc.ready_to_finish());
 		drop(enc);
 		assert_eq!(&buf, b"\xC0\x741990-12-31T12:34:56Z")
@@ -419,9 +1012,7 @@ mod test {
 		let mut buf = Vec::new();
 		let mut enc = Encoder::new(Cursor::new(&mut buf));
 		enc.set_date_time_style(crate::extended::DateTimeEncodeStyle::PreferNumeric);
-		enc.feed_event(Event::ChronoDateTime(
-			Utc.ymd(1970, 01, 01).and_hms(00, 00, 04).into(),
-		));
+		enc.feed_event(Event::ChronoDateTime(utc(1970, 1, 1, 0, 0, 4, 0)));
 		assert!(enc.ready_to_finish());
 		drop(enc);
 		assert_eq!(&buf, b"\xC1\x04");
@@ -436,9 +1027,7 @@ mod test {
 			enc.date_time_style(),
 			&crate::extended::DateTimeEncodeStyle::PreferText
 		);
-		enc.feed_event(Event::ChronoDateTime(
-			Utc.ymd(1876, 4, 22).and_hms_milli(13, 22, 1, 500).into(),
-		));
+		enc.feed_event(Event::ChronoDateTime(utc(1876, 4, 22, 13, 22, 1, 500)));
 		assert!(enc.ready_to_finish());
 		drop(enc);
 		assert_eq!(&buf, b"\xC0\x78\x181876-04-22T13:22:01.500Z");
@@ -450,9 +1039,7 @@ mod test {
 		let mut buf = Vec::new();
 		let mut enc = Encoder::new(Cursor::new(&mut buf));
 		enc.set_date_time_style(crate::extended::DateTimeEncodeStyle::PreferNumeric);
-		enc.feed_event(Event::ChronoDateTime(
-			Utc.ymd(1970, 01, 01).and_hms_milli(00, 00, 00, 500).into(),
-		));
+		enc.feed_event(Event::ChronoDateTime(utc(1970, 1, 1, 0, 0, 0, 500)));
 		assert!(enc.ready_to_finish());
 		drop(enc);
 		assert_eq!(&buf, b"\xC1\xF9\x38\x00");
@@ -9,11 +9,23 @@ use super::{
 	streaming::{Decoder as StreamingDecoder, Encoder as StreamingEncoder, Event},
 	DateTimeDecodeStyle, DateTimeEncodeStyle, DecodeExtensionConfig, EncodeExtensionConfig,
 };
-use crate::errors::{DecodeError, EncodeError};
+use crate::{
+	basic::{
+		streaming::{DecodeLimits, Decoder as BasicDecoder, ReadSource, SliceSource, Source},
+		tree::{
+			float_key, hash_map_pairs, maps_eq, preallocate, sort_pairs, DataModel, Determinism,
+			DuplicateKeyPolicy, KeyIndex, ResyncInput, DEFAULT_MAX_DEPTH,
+		},
+	},
+	errors::{DecodeError, DecodeErrorKind, EncodeError, MalformedKind, PathSegment},
+};
 #[cfg(feature = "chrono")]
 use chrono::{DateTime, FixedOffset};
+use half::f16;
 use std::{
 	borrow::Cow,
+	fmt,
+	hash::{Hash, Hasher},
 	io::{Read, Write},
 };
 
@@ -30,8 +42,14 @@ pub enum Item {
 	/// Some integers that can be CBOR encoded underflow [`i64`].
 	/// Use one of the `interpret_signed` associated functions to resolve this.
 	Signed(u64),
-	/// A floating-point number.
-	Float(f64),
+	/// A half-precision floating-point number.
+	///
+	/// Floats keep the width and exact bits they were encoded with.
+	Float16(f16),
+	/// A single-precision floating-point number.
+	Float32(f32),
+	/// A double-precision floating-point number.
+	Float64(f64),
 	/// A byte string.
 	ByteString(Vec<u8>),
 	/// A text string.
@@ -101,14 +119,157 @@ impl Item {
 			None => None,
 		}
 	}
+
+	/// Get the value of a float, of any width, as an [`f64`].
+	///
+	/// This is a convenience alias for [`crate::basic::streaming::Event::as_f64`].
+	pub fn as_f64(&self) -> Option<f64> {
+		match *self {
+			Self::Float16(f) => Event::Float16(f).as_f64(),
+			Self::Float32(f) => Event::Float32(f).as_f64(),
+			Self::Float64(f) => Some(f),
+			_ => None,
+		}
+	}
+
+	/// Create a float item of the narrowest width that holds the value exactly.
+	///
+	/// This is a convenience alias for [`crate::basic::streaming::Event::create_float`],
+	/// except that it returns an extended [`Item`] instead.
+	pub fn create_float(val: f64) -> Item {
+		match Event::create_float(val) {
+			Event::Float16(f) => Self::Float16(f),
+			Event::Float32(f) => Self::Float32(f),
+			Event::Float64(f) => Self::Float64(f),
+			_ => unreachable!(),
+		}
+	}
+
+	/// Check whether two items are the same in the CBOR data model.
+	///
+	/// This is how map keys are compared to find duplicates;
+	/// see [the basic counterpart](`crate::basic::tree::Item::data_model_eq`) for details.
+	pub fn data_model_eq(&self, other: &Item) -> bool {
+		if let (Some(a), Some(b)) = (self.as_f64(), other.as_f64()) {
+			return float_key(a) == float_key(b);
+		}
+		match (self, other) {
+			(Item::Array(a), Item::Array(b)) => {
+				a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.data_model_eq(b))
+			}
+			(Item::Map(a), Item::Map(b)) => maps_eq(a, b),
+			(Item::UnrecognizedTag(tag_a, a), Item::UnrecognizedTag(tag_b, b)) => {
+				tag_a == tag_b && a.data_model_eq(b)
+			}
+			(a, b) => a == b,
+		}
+	}
+
+	/// Put the item, and everything in it, in the form that [deterministic encoding](`Determinism::Core`) gives.
+	///
+	/// This works like [`crate::basic::tree::Item::canonicalize`].
+	/// Keys are sorted by how an encoder with the default settings writes them,
+	/// so if a map has date-times as keys, encoding it with a different [`Encoder::date_time_style`] may need a different order.
+	pub fn canonicalize(&mut self) {
+		self.canonicalize_as(Determinism::Core);
+	}
+
+	/// Put the item, and everything in it, in the form that [RFC 7049 canonical encoding](`Determinism::LengthFirst`) gives.
+	///
+	/// This works like [`crate::basic::tree::Item::canonicalize_length_first`],
+	/// with the same caveat about date-times as [`Self::canonicalize`].
+	pub fn canonicalize_length_first(&mut self) {
+		self.canonicalize_as(Determinism::LengthFirst);
+	}
+
+	fn canonicalize_as(&mut self, determinism: Determinism) {
+		match self {
+			Item::Float16(_) | Item::Float32(_) | Item::Float64(_) => {
+				*self = Item::create_float(self.as_f64().unwrap());
+			}
+			Item::Array(items) => {
+				for item in items {
+					item.canonicalize_as(determinism);
+				}
+			}
+			Item::Map(pairs) => {
+				for (key, val) in pairs.iter_mut() {
+					key.canonicalize_as(determinism);
+					val.canonicalize_as(determinism);
+				}
+				let mut encoder = Encoder::new();
+				*pairs = sort_pairs(std::mem::take(pairs), determinism, |(key, _)| {
+					let mut out = Vec::new();
+					let _ = encoder.encode(key, &mut out);
+					out
+				});
+			}
+			Item::UnrecognizedTag(_, item) => item.canonicalize_as(determinism),
+			_ => (),
+		}
+	}
+}
+
+impl DataModel for Item {
+	fn data_model_eq(&self, other: &Self) -> bool {
+		self.data_model_eq(other)
+	}
+
+	fn data_model_hash(&self, state: &mut impl Hasher) {
+		// Floats of different widths can be the same, so they're all hashed as f64s.
+		if let Some(f) = self.as_f64() {
+			float_key(f).hash(state);
+			return;
+		}
+		std::mem::discriminant(self).hash(state);
+		match self {
+			Item::Unsigned(n) | Item::Signed(n) => n.hash(state),
+			Item::Float16(_) | Item::Float32(_) | Item::Float64(_) => (),
+			Item::ByteString(b) => b.hash(state),
+			Item::TextString(t) => t.hash(state),
+			Item::Array(a) => {
+				a.len().hash(state);
+				for item in a {
+					item.data_model_hash(state);
+				}
+			}
+			Item::Map(m) => hash_map_pairs(m, state),
+			Item::UnrecognizedTag(tag, item) => {
+				tag.hash(state);
+				item.data_model_hash(state);
+			}
+			Item::Simple(n) => n.hash(state),
+			#[cfg(feature = "chrono")]
+			Item::ChronoDateTime(dt) => dt.hash(state),
+		}
+	}
 }
 
 include!("forward_config_accessors.in.rs");
 
 /// A tree-building decoder for CBOR with extensions.
-#[derive(Debug, Clone, Default)]
+///
+/// Like [the basic counterpart](`crate::basic::tree::Decoder`),
+/// this only allows items to be nested 256 deep by default.
+#[derive(Debug, Clone)]
 pub struct Decoder {
 	config: DecodeExtensionConfig,
+	limits: DecodeLimits,
+	duplicate_keys: DuplicateKeyPolicy,
+	require_definite: bool,
+}
+
+impl Default for Decoder {
+	fn default() -> Self {
+		let mut limits = DecodeLimits::new();
+		limits.set_max_depth(Some(DEFAULT_MAX_DEPTH));
+		Self {
+			config: Default::default(),
+			limits,
+			duplicate_keys: Default::default(),
+			require_definite: false,
+		}
+	}
 }
 
 impl Decoder {
@@ -124,57 +285,164 @@ impl Decoder {
 		"the way date-times are decoded."
 	);
 
+	config_accessors!(limits, DecodeLimits, limits, limits_mut, set_limits);
+
+	config_accessors!(
+		duplicate_keys,
+		DuplicateKeyPolicy,
+		duplicate_keys,
+		duplicate_keys_mut,
+		set_duplicate_keys
+	);
+
+	config_accessors!(
+		require_definite,
+		bool,
+		require_definite,
+		require_definite_mut,
+		set_require_definite
+	);
+
+	/// Set up a streaming decoder the same way as this one.
+	fn streaming_decoder<'de, S: Source<'de>>(
+		&self,
+		mut basic: BasicDecoder<S>,
+	) -> StreamingDecoder<S> {
+		basic.set_limits(self.limits.clone());
+		basic.set_require_definite(self.require_definite);
+		StreamingDecoder::new_from_config(basic, self.config.clone())
+	}
+
 	/// Parse some CBOR.
 	///
 	/// This is just a shortcut for [`Self::decode_from_stream`]
 	/// which constructs the [`streaming::Decoder`](`StreamingDecoder`) for you
-	/// and converts [`None`]s into [`DecodeError::Malformed`]s.
+	/// and converts [`None`]s into [`MalformedKind::UnexpectedBreak`]s.
+	/// The streaming decoder uses this decoder's extension configuration, [limits](`Self::limits`) and settings.
 	pub fn decode(&mut self, source: impl Read) -> Result<Item, DecodeError> {
-		match self.decode_from_stream(&mut StreamingDecoder::new(source)) {
+		let mut decoder = self.streaming_decoder(BasicDecoder::new(source));
+		match self.decode_from_stream(&mut decoder) {
+			Ok(Some(item)) => Ok(item),
+			Ok(None) => Err(DecodeError::new(MalformedKind::UnexpectedBreak.into(), 0)),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Parse some CBOR from a byte slice.
+	///
+	/// This is like [`Self::decode`], but it uses a [slice-backed](`StreamingDecoder::from_slice`) streaming decoder,
+	/// so the contents of strings are only copied once, into the resulting [`Item`].
+	pub fn decode_slice(&mut self, input: &[u8]) -> Result<Item, DecodeError> {
+		let mut decoder = self.streaming_decoder(BasicDecoder::from_slice(input));
+		match self.decode_from_stream(&mut decoder) {
 			Ok(Some(item)) => Ok(item),
-			Ok(None) => Err(DecodeError::Malformed),
+			Ok(None) => Err(DecodeError::new(MalformedKind::UnexpectedBreak.into(), 0)),
 			Err(e) => Err(e),
 		}
 	}
 
+	/// Parse a CBOR Sequence ([RFC 8742](https://www.rfc-editor.org/rfc/rfc8742.html)): any number of items, one after another.
+	///
+	/// This returns an iterator over the items, which ends when the input does.
+	/// The streaming decoder uses this decoder's extension configuration, [limits](`Self::limits`) and settings.
+	pub fn decode_sequence<T: Read>(&self, source: T) -> SequenceDecoder<ReadSource<T>> {
+		let decoder = self.streaming_decoder(BasicDecoder::new(source));
+		self.decode_sequence_from_stream(decoder)
+	}
+
+	/// Parse a CBOR Sequence from a byte slice.
+	///
+	/// This is like [`Self::decode_sequence`], but it uses a [slice-backed](`StreamingDecoder::from_slice`) streaming decoder.
+	pub fn decode_sequence_slice<'a>(&self, input: &'a [u8]) -> SequenceDecoder<SliceSource<'a>> {
+		let decoder = self.streaming_decoder(BasicDecoder::from_slice(input));
+		self.decode_sequence_from_stream(decoder)
+	}
+
+	/// Parse a CBOR Sequence, carrying on past any items that are damaged.
+	///
+	/// This is like [`Self::decode_sequence`], but after returning an error, the iterator doesn't stop.
+	/// Instead, it drops the damaged item and looks for the next place an item could start; see [`ResyncDecoder`].
+	pub fn decode_sequence_resync<T: Read>(&self, source: T) -> ResyncDecoder<T> {
+		ResyncDecoder {
+			tree: self.clone(),
+			input: ResyncInput::new(source),
+			plausible: Box::new(|_| true),
+		}
+	}
+
+	/// Parse a CBOR Sequence from a provided streaming decoder.
+	///
+	/// This uses the streaming decoder's extension configuration, limits and settings, not this decoder's,
+	/// except that this decoder's depth limit applies as well; see [`Self::decode_from_stream`].
+	pub fn decode_sequence_from_stream<'de, S: Source<'de>>(
+		&self,
+		decoder: StreamingDecoder<S>,
+	) -> SequenceDecoder<S> {
+		SequenceDecoder {
+			tree: self.clone(),
+			decoder,
+			failed: false,
+		}
+	}
+
 	/// Parse some CBOR from a provided streaming decoder.
 	///
+	/// This uses the streaming decoder's extension configuration, limits and settings, not this decoder's,
+	/// except that this decoder's depth limit applies as well, since decoding recurses once for each level of nesting.
+	/// The depth counts from the top level of the input, not from wherever the streaming decoder has got to.
+	///
 	/// If this returns `Ok(None)`, it means that the first thing it encountered was a break (`0xFF`).
 	/// This may or may not be acceptable depending on the situation,
 	/// so `decode_from_stream` doesn't count it as a failure.
-	pub fn decode_from_stream(
+	pub fn decode_from_stream<'de>(
 		&mut self,
-		decoder: &mut StreamingDecoder<impl Read>,
+		decoder: &mut StreamingDecoder<impl Source<'de>>,
 	) -> Result<Option<Item>, DecodeError> {
-		Ok(Some(match decoder.next_event()? {
+		let offset = decoder.position();
+		let event = decoder.next_event().map_err(DecodeError::without_path)?;
+		// Decoding recurses once for each level of nesting, so this decoder's depth limit applies,
+		// even if the streaming decoder's own limits are looser.
+		if matches!(*self.limits.max_depth(), Some(max) if decoder.depth() > max) {
+			return Err(DecodeError::new(DecodeErrorKind::TooDeep, offset));
+		}
+		Ok(Some(match event {
 			Event::Unsigned(n) => Item::Unsigned(n),
 			Event::Signed(n) => Item::Signed(n),
 			Event::ByteString(b) => Item::ByteString(b.into_owned()),
 			Event::UnknownLengthByteString => {
 				let mut buffer: Vec<u8>;
-				match decoder.next_event()? {
+				let offset = decoder.position();
+				match decoder.next_event().map_err(DecodeError::without_path)? {
 					Event::ByteString(b) => buffer = b.into_owned(),
 					Event::Break => return Ok(Some(Item::ByteString(b"".to_vec()))),
-					_ => return Err(DecodeError::Malformed),
+					_ => return Err(DecodeError::new(MalformedKind::InvalidChunk.into(), offset)),
 				}
 				loop {
-					match decoder.next_event()? {
+					let offset = decoder.position();
+					match decoder.next_event().map_err(DecodeError::without_path)? {
 						Event::ByteString(b) => buffer.extend_from_slice(&b),
 						Event::Break => return Ok(Some(Item::ByteString(buffer))),
-						_ => return Err(DecodeError::Malformed),
+						_ => {
+							return Err(DecodeError::new(
+								MalformedKind::InvalidChunk.into(),
+								offset,
+							))
+						}
 					}
 				}
 			}
 			Event::TextString(val) => Item::TextString(val.into_owned()),
 			Event::UnknownLengthTextString => {
 				let mut buffer: String;
-				match decoder.next_event()? {
+				let offset = decoder.position();
+				match decoder.next_event().map_err(DecodeError::without_path)? {
 					Event::TextString(b) => buffer = b.into_owned(),
 					Event::Break => return Ok(Some(Item::TextString("".to_owned()))),
-					_ => return Err(DecodeError::Malformed),
+					_ => return Err(DecodeError::new(MalformedKind::InvalidChunk.into(), offset)),
 				}
 				loop {
-					match decoder.next_event()? {
+					let offset = decoder.position();
+					match decoder.next_event().map_err(DecodeError::without_path)? {
 						Event::TextString(b) => {
 							let mut buffer2 = buffer.into_bytes();
 							buffer2.extend_from_slice(b.as_bytes());
@@ -183,80 +451,222 @@ impl Decoder {
 							buffer = unsafe { String::from_utf8_unchecked(buffer2) };
 						}
 						Event::Break => return Ok(Some(Item::TextString(buffer))),
-						_ => return Err(DecodeError::Malformed),
+						_ => {
+							return Err(DecodeError::new(
+								MalformedKind::InvalidChunk.into(),
+								offset,
+							))
+						}
 					}
 				}
 			}
 			Event::Array(len) => {
-				let mut arr = Vec::with_capacity(len.try_into().unwrap_or(usize::MAX));
-				for _ in 0..len {
-					match self.decode_from_stream(decoder)? {
-						None => return Err(DecodeError::Malformed),
-						Some(item) => arr.push(item),
-					}
+				let mut arr = preallocate(len);
+				for index in 0..len {
+					arr.push(self.decode_required(decoder, || PathSegment::Index(index))?);
 				}
 				assert_eq!(arr.len(), len as _);
 				Item::Array(arr)
 			}
 			Event::UnknownLengthArray => {
 				let mut arr = Vec::new();
-				loop {
-					match self.decode_from_stream(decoder)? {
+				for index in 0.. {
+					match self.decode_nested(decoder, || PathSegment::Index(index))? {
 						None => break,
 						Some(item) => arr.push(item),
 					}
 				}
 				Item::Array(arr)
 			}
-			Event::Map(len) => {
-				let mut map = Vec::with_capacity(len.try_into().unwrap_or(usize::MAX));
-				for _ in 0..len {
-					let key = match self.decode_from_stream(decoder)? {
-						None => return Err(DecodeError::Malformed),
-						Some(item) => item,
-					};
-					let val = match self.decode_from_stream(decoder)? {
-						None => return Err(DecodeError::Malformed),
-						Some(item) => item,
-					};
-					map.push((key, val));
-				}
-				Item::Map(map)
-			}
-			Event::UnknownLengthMap => {
-				let mut map = Vec::new();
-				loop {
-					let key = match self.decode_from_stream(decoder)? {
-						None => break,
-						Some(item) => item,
-					};
-					let val = match self.decode_from_stream(decoder)? {
-						None => return Err(DecodeError::Malformed),
-						Some(item) => item,
-					};
-					map.push((key, val));
+			Event::Map(len) => self.decode_map(decoder, Some(len))?,
+			Event::UnknownLengthMap => self.decode_map(decoder, None)?,
+			Event::UnrecognizedTag(tag) => {
+				let offset = decoder.position();
+				match self.decode_from_stream(decoder)? {
+					Some(value) => Item::UnrecognizedTag(tag, Box::new(value)),
+					None => {
+						return Err(DecodeError::new(
+							MalformedKind::UnexpectedBreak.into(),
+							offset,
+						))
+					}
 				}
-				Item::Map(map)
 			}
-			Event::UnrecognizedTag(tag) => match self.decode_from_stream(decoder) {
-				Ok(Some(value)) => Item::UnrecognizedTag(tag, Box::new(value)),
-				Ok(None) => return Err(DecodeError::Malformed),
-				Err(e) => return Err(e),
-			},
 			Event::Simple(val) => Item::Simple(val),
-			Event::Float(val) => Item::Float(val),
+			Event::Float16(val) => Item::Float16(val),
+			Event::Float32(val) => Item::Float32(val),
+			Event::Float64(val) => Item::Float64(val),
 			Event::Break => return Ok(None),
 
 			#[cfg(feature = "chrono")]
 			Event::ChronoDateTime(dt) => Item::ChronoDateTime(dt),
 		}))
 	}
+
+	/// Decode an item nested inside an array or map, recording where it is if decoding fails.
+	fn decode_nested<'de>(
+		&mut self,
+		decoder: &mut StreamingDecoder<impl Source<'de>>,
+		segment: impl FnOnce() -> PathSegment,
+	) -> Result<Option<Item>, DecodeError> {
+		self.decode_from_stream(decoder)
+			.map_err(|e| e.within(segment()))
+	}
+
+	/// Decode the contents of a map, with `len` pairs or up to a break.
+	///
+	/// This is separate from [`Self::decode_from_stream`] to keep its stack frame small, since that recurses.
+	fn decode_map<'de>(
+		&mut self,
+		decoder: &mut StreamingDecoder<impl Source<'de>>,
+		len: Option<u64>,
+	) -> Result<Item, DecodeError> {
+		let mut map = preallocate(len.unwrap_or(0));
+		let mut keys = KeyIndex::default();
+		for index in 0..len.unwrap_or(u64::MAX) {
+			let offset = decoder.position();
+			let key = if len.is_some() {
+				self.decode_required(decoder, || PathSegment::Key(index))?
+			} else {
+				match self.decode_nested(decoder, || PathSegment::Key(index))? {
+					None => break,
+					Some(item) => item,
+				}
+			};
+			let val =
+				self.decode_required(decoder, || PathSegment::Value(index, describe_key(&key)))?;
+			keys.add_pair(&mut map, (key, val), self.duplicate_keys)
+				.map_err(|e| DecodeError::new(e, offset).within(PathSegment::Key(index)))?;
+		}
+		Ok(Item::Map(map))
+	}
+
+	/// Like [`Self::decode_nested`], but finding a break instead of an item is an error.
+	fn decode_required<'de>(
+		&mut self,
+		decoder: &mut StreamingDecoder<impl Source<'de>>,
+		segment: impl Fn() -> PathSegment,
+	) -> Result<Item, DecodeError> {
+		let offset = decoder.position();
+		match self.decode_nested(decoder, &segment)? {
+			Some(item) => Ok(item),
+			None => Err(
+				DecodeError::new(MalformedKind::UnexpectedBreak.into(), offset).within(segment()),
+			),
+		}
+	}
+}
+
+/// Describe a map key for a [`PathSegment::Value`], if it's simple enough.
+fn describe_key(key: &Item) -> Option<String> {
+	match key {
+		Item::Unsigned(n) => Some(n.to_string()),
+		Item::Signed(n) => Some(Item::interpret_signed_wide(*n).to_string()),
+		Item::TextString(t) => Some(format!("{:?}", t)),
+		_ => None,
+	}
+}
+
+/// An iterator over the items in a CBOR Sequence, from [`Decoder::decode_sequence`] and friends.
+///
+/// See [the basic counterpart](`crate::basic::tree::SequenceDecoder`) for details.
+#[derive(Debug, Clone)]
+pub struct SequenceDecoder<S> {
+	tree: Decoder,
+	decoder: StreamingDecoder<S>,
+	failed: bool,
+}
+
+impl<S> SequenceDecoder<S> {
+	/// Get back the streaming decoder, to find out where it's got to or to finish it.
+	pub fn into_inner(self) -> StreamingDecoder<S> {
+		self.decoder
+	}
 }
 
+impl<'de, S: Source<'de>> Iterator for SequenceDecoder<S> {
+	type Item = Result<Item, DecodeError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.failed {
+			return None;
+		}
+		// Running out of input is only an error once an item has started.
+		if let Err(e) = self.decoder.peek_major_type() {
+			if matches!(e.kind(), DecodeErrorKind::Insufficient) {
+				return None;
+			}
+		}
+		let offset = self.decoder.position();
+		let result = match self.tree.decode_from_stream(&mut self.decoder) {
+			Ok(Some(item)) => Ok(item),
+			Ok(None) => Err(DecodeError::new(
+				MalformedKind::UnexpectedBreak.into(),
+				offset,
+			)),
+			Err(e) => Err(e),
+		};
+		self.failed = result.is_err();
+		Some(result)
+	}
+}
+
+impl<'de, S: Source<'de>> std::iter::FusedIterator for SequenceDecoder<S> {}
+
+/// An iterator over the items in a CBOR Sequence that carries on past damaged items, from [`Decoder::decode_sequence_resync`].
+///
+/// See [the basic counterpart](`crate::basic::tree::ResyncDecoder`) for details.
+pub struct ResyncDecoder<T> {
+	tree: Decoder,
+	input: ResyncInput<T>,
+	plausible: Box<dyn FnMut(&Item) -> bool>,
+}
+
+impl<T: fmt::Debug> fmt::Debug for ResyncDecoder<T> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("ResyncDecoder")
+			.field("tree", &self.tree)
+			.field("input", &self.input)
+			.finish_non_exhaustive()
+	}
+}
+
+impl<T: Read> ResyncDecoder<T> {
+	/// Set a check for whether an item found after an error could be a real record.
+	///
+	/// See [the basic counterpart](`crate::basic::tree::ResyncDecoder::set_plausible`) for details.
+	pub fn set_plausible(&mut self, plausible: impl FnMut(&Item) -> bool + 'static) -> &mut Self {
+		self.plausible = Box::new(plausible);
+		self
+	}
+}
+
+impl<T: Read> Iterator for ResyncDecoder<T> {
+	type Item = Result<Item, DecodeError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let tree = &mut self.tree;
+		self.input.next_item(
+			|input| {
+				let mut decoder = tree.streaming_decoder(BasicDecoder::from_slice(input));
+				Ok(tree
+					.decode_from_stream(&mut decoder)?
+					.map(|item| (item, decoder.position())))
+			},
+			&mut *self.plausible,
+		)
+	}
+}
+
+impl<T: Read> std::iter::FusedIterator for ResyncDecoder<T> {}
+
 /// A tree-walking encoder for CBOR with extensions.
+///
+/// Like [basic tree encoders](`crate::basic::tree::Encoder`), this can be given a [`Determinism`].
 #[derive(Debug, Clone, Default)]
 pub struct Encoder {
 	config: EncodeExtensionConfig,
+	determinism: Determinism,
 }
 
 impl Encoder {
@@ -272,11 +682,31 @@ impl Encoder {
 		"the way date-times are encoded."
 	);
 
+	config_accessors!(
+		determinism,
+		Determinism,
+		determinism,
+		determinism_mut,
+		set_determinism
+	);
+
 	/// Encode some CBOR.
 	///
-	/// This is just a shortcut for [`Self::encode_to_stream`] which constructs the [`streaming::Encoder`](`crate::basic::streaming::Encoder`) for you.
+	/// This is just a shortcut for [`Self::encode_to_stream`] which constructs the [`streaming::Encoder`](`crate::basic::streaming::Encoder`) for you,
+	/// and then [finishes](`StreamingEncoder::finish`) it, which flushes `dest`.
 	pub fn encode(&mut self, cbor: &Item, dest: impl Write) -> Result<(), EncodeError> {
-		self.encode_to_stream(cbor, &mut StreamingEncoder::new(dest))
+		let mut encoder = StreamingEncoder::new(dest);
+		self.encode_to_stream(cbor, &mut encoder)?;
+		encoder.finish()?;
+		Ok(())
+	}
+
+	/// Start writing a CBOR Sequence ([RFC 8742](https://www.rfc-editor.org/rfc/rfc8742.html)): any number of items, one after another.
+	pub fn encode_sequence<T: Write>(&self, dest: T) -> SequenceEncoder<T> {
+		SequenceEncoder {
+			tree: self.clone(),
+			dest,
+		}
 	}
 
 	/// Encode some CBOR to a provided streaming encoder.
@@ -286,13 +716,18 @@ impl Encoder {
 		encoder: &mut StreamingEncoder<impl Write>,
 	) -> Result<(), EncodeError> {
 		match cbor {
+			Item::Float16(_) | Item::Float32(_) | Item::Float64(_)
+				if self.determinism != Determinism::Off =>
+			{
+				encoder.feed_event(Event::create_float(cbor.as_f64().unwrap()))
+			}
 			Item::Unsigned(n) => encoder.feed_event(Event::Unsigned(*n)),
 			Item::Signed(n) => encoder.feed_event(Event::Signed(*n)),
-			Item::Float(f) => encoder.feed_event(Event::Float(*f)),
-			Item::ByteString(bytes) => {
-				encoder.feed_event(Event::ByteString(Cow::Borrowed(&*bytes)))
-			}
-			Item::TextString(text) => encoder.feed_event(Event::TextString(Cow::Borrowed(&*text))),
+			Item::Float16(f) => encoder.feed_event(Event::Float16(*f)),
+			Item::Float32(f) => encoder.feed_event(Event::Float32(*f)),
+			Item::Float64(f) => encoder.feed_event(Event::Float64(*f)),
+			Item::ByteString(bytes) => encoder.feed_event(Event::ByteString(Cow::Borrowed(bytes))),
+			Item::TextString(text) => encoder.feed_event(Event::TextString(Cow::Borrowed(text))),
 			Item::Array(arr) => {
 				encoder.feed_event(Event::Array(
 					arr.len().try_into().expect("I'm on a 128-bit system? Wow."),
@@ -306,7 +741,20 @@ impl Encoder {
 				encoder.feed_event(Event::Map(
 					map.len().try_into().expect("I'm on a 128-bit system? Wow."),
 				))?;
-				for (key, val) in map.iter() {
+				if self.determinism == Determinism::Off {
+					for (key, val) in map.iter() {
+						self.encode_to_stream(key, encoder)?;
+						self.encode_to_stream(val, encoder)?;
+					}
+					return Ok(());
+				}
+				// A key that can't be encoded will fail again when it's written, so it doesn't matter where it goes.
+				let pairs = sort_pairs(map.iter().collect(), self.determinism, |(key, _)| {
+					let mut out = Vec::new();
+					let _ = self.encode(key, &mut out);
+					out
+				});
+				for (key, val) in pairs {
 					self.encode_to_stream(key, encoder)?;
 					self.encode_to_stream(val, encoder)?;
 				}
@@ -324,6 +772,27 @@ impl Encoder {
 	}
 }
 
+/// A writer for a CBOR Sequence, from [`Encoder::encode_sequence`].
+#[derive(Debug, Clone)]
+pub struct SequenceEncoder<T: Write> {
+	tree: Encoder,
+	dest: T,
+}
+
+impl<T: Write> SequenceEncoder<T> {
+	/// Add an item to the end of the sequence.
+	///
+	/// If this fails, part of the item may already have been written.
+	pub fn encode(&mut self, cbor: &Item) -> Result<(), EncodeError> {
+		self.tree.encode(cbor, &mut self.dest)
+	}
+
+	/// Stop writing, and get back the destination.
+	pub fn into_inner(self) -> T {
+		self.dest
+	}
+}
+
 #[cfg(test)]
 mod test {
 	use super::*;
@@ -332,7 +801,7 @@ mod test {
 	macro_rules! decode_test {
 		($in:expr => $out:pat if $guard:expr) => {
 			let input = $in;
-			match Decoder::new().decode(Cursor::new(&input)) {
+			match Decoder::new().decode(Cursor::new(&input)).map_err(DecodeError::into_kind) {
 				$out if $guard => (),
 				other => panic!("{:X?} => {:?}", input, other),
 			}
@@ -354,6 +823,53 @@ mod test {
 		};
 	}
 
+	#[test]
+	fn decode_error_path() {
+		let error = Decoder::new()
+			.decode_slice(b"\xA1\x26\x81\xC2\x7F\x00")
+			.unwrap_err();
+		assert!(matches!(
+			error.kind(),
+			DecodeErrorKind::Malformed(MalformedKind::InvalidChunk)
+		));
+		assert_eq!(error.offset(), 5);
+		assert_eq!(
+			error.to_string(),
+			"malformed CBOR: invalid piece of an unknown-length string at byte 5 (in {-7}[0])"
+		);
+	}
+
+	#[cfg(feature = "chrono")]
+	#[test]
+	fn decode_error_offset_chrono() {
+		let mut decoder = Decoder::new();
+		decoder.set_date_time_style(crate::extended::DateTimeDecodeStyle::Chrono);
+		let error = decoder.decode_slice(b"\x82\x00\xC1\x41\x00").unwrap_err();
+		assert!(matches!(error.kind(), DecodeErrorKind::TagInvalid(1)));
+		assert_eq!(error.offset(), 3);
+		assert_eq!(
+			error.path().cloned().collect::<Vec<_>>(),
+			[PathSegment::Index(1)]
+		);
+	}
+
+	#[test]
+	fn decode_hostile() {
+		decode_test!(vec![0x81; 100_000] => Err(DecodeErrorKind::TooDeep));
+		decode_test!(vec![0xC2; 100_000] => Err(DecodeErrorKind::TooDeep));
+		let input = vec![0x81; 100_000];
+		let mut decoder = StreamingDecoder::from_slice(&input);
+		assert!(matches!(
+			Decoder::new()
+				.decode_from_stream(&mut decoder)
+				.map_err(DecodeError::into_kind),
+			Err(DecodeErrorKind::TooDeep)
+		));
+		for input in crate::basic::streaming::test::fuzz_inputs(5_000) {
+			let _ = Decoder::new().decode_slice(&input);
+		}
+	}
+
 	#[test]
 	fn decode_bytes_segmented() {
 		decode_test!(b"\x5F\x42ab\x42cd\xFF" => Ok(Item::ByteString(b)) if b == b"abcd");
@@ -362,24 +878,47 @@ mod test {
 
 	#[test]
 	fn decode_bytes_segmented_wrong() {
-		decode_test!(b"\x5F\x00" => Err(DecodeError::Malformed));
+		decode_test!(b"\x5F\x00" => Err(DecodeErrorKind::Malformed(MalformedKind::InvalidChunk)));
 	}
 
 	#[test]
 	fn decode_text_segmented() {
 		decode_test!(b"\x7F\x62ab\x62cd\xFF" => Ok(Item::TextString(t)) if t == "abcd");
-		decode_test!(b"\x7F\xFF" => Ok(Item::TextString(t)) if t == "");
+		decode_test!(b"\x7F\xFF" => Ok(Item::TextString(t)) if t.is_empty());
 	}
 
 	#[test]
 	fn decode_text_segmented_wrong() {
-		decode_test!(b"\x7F\x00" => Err(DecodeError::Malformed));
+		decode_test!(b"\x7F\x00" => Err(DecodeErrorKind::Malformed(MalformedKind::InvalidChunk)));
+	}
+
+	#[test]
+	fn decode_limits() {
+		let mut decoder = Decoder::new();
+		decoder.limits_mut().set_max_collection_len(Some(2));
+		assert!(matches!(
+			decoder
+				.decode(Cursor::new(b"\x83\x00\x00\x00"))
+				.map_err(DecodeError::into_kind),
+			Err(DecodeErrorKind::CollectionTooLong)
+		));
+	}
+
+	#[cfg(feature = "chrono")]
+	#[test]
+	fn decode_uses_config() {
+		let mut decoder = Decoder::new();
+		decoder.set_date_time_style(DateTimeDecodeStyle::Chrono);
+		assert!(matches!(
+			decoder.decode_slice(b"\xC1\x04"),
+			Ok(Item::ChronoDateTime(dt)) if dt.timestamp() == 4
+		));
 	}
 
 	#[test]
 	fn decode_array() {
 		decode_test!(b"\x80" => Ok(Item::Array(v)) if v.is_empty());
-		decode_test!(b"\x84\x00\x01\x02\x03" => Ok(Item::Array(v)) if v == [0,1,2,3].map(|x| Item::Unsigned(x)));
+		decode_test!(b"\x84\x00\x01\x02\x03" => Ok(Item::Array(v)) if v == [0,1,2,3].map(Item::Unsigned));
 	}
 
 	#[test]
@@ -413,8 +952,8 @@ mod test {
 
 	#[test]
 	fn decode_map_wrong() {
-		decode_test!(b"\xA1\x00" => Err(DecodeError::Insufficient));
-		decode_test!(b"\xBF\x00\xFF" => Err(DecodeError::Malformed));
+		decode_test!(b"\xA1\x00" => Err(DecodeErrorKind::Insufficient));
+		decode_test!(b"\xBF\x00\xFF" => Err(DecodeErrorKind::Malformed(MalformedKind::UnexpectedBreak)));
 	}
 
 	#[test]
@@ -427,4 +966,117 @@ mod test {
 			=> b"\xA2\x00\x01\x02\x03"
 		);
 	}
+
+	#[test]
+	fn decode_sequence_resync() {
+		// 2(h'01'), an array that's damaged by a reserved initial byte, then 2(h'02').
+		let input = b"\xC2\x41\x01\x82\x00\x1C\xC2\x41\x02";
+		let mut items = Decoder::new().decode_sequence_resync(Cursor::new(input));
+		assert!(matches!(
+			items.next(),
+			Some(Ok(Item::UnrecognizedTag(2, item))) if *item == Item::ByteString(vec![1])
+		));
+		let error = items.next().unwrap().unwrap_err();
+		assert_eq!(error.offset(), 5);
+		assert!(matches!(
+			items.next(),
+			Some(Ok(Item::UnrecognizedTag(2, item))) if *item == Item::ByteString(vec![2])
+		));
+		assert!(items.next().is_none());
+	}
+
+	#[test]
+	fn encode_deterministic() {
+		// {2(h'01'): 1.0, 1: [{"b": 0, "a": 1}]}
+		let item = Item::Map(vec![
+			(
+				Item::UnrecognizedTag(2, Box::new(Item::ByteString(vec![1]))),
+				Item::Float32(1.0),
+			),
+			(
+				Item::Unsigned(1),
+				Item::Array(vec![Item::Map(vec![
+					(Item::TextString("b".to_owned()), Item::Unsigned(0)),
+					(Item::TextString("a".to_owned()), Item::Unsigned(1)),
+				])]),
+			),
+		]);
+		let expected = b"\xA2\x01\x81\xA2\x61a\x01\x61b\x00\xC2\x41\x01\xF9\x3C\x00";
+
+		let mut output = Vec::new();
+		let mut encoder = Encoder::new();
+		encoder.set_determinism(Determinism::Core);
+		encoder.encode(&item, &mut output).unwrap();
+		assert_eq!(output, expected);
+
+		let mut canonical = item;
+		canonical.canonicalize();
+		let mut output = Vec::new();
+		Encoder::new().encode(&canonical, &mut output).unwrap();
+		assert_eq!(output, expected);
+	}
+
+	#[test]
+	fn duplicate_keys() {
+		// {2(h'01'): 0, 2(h'01'): 1}
+		let input = b"\xA2\xC2\x41\x01\x00\xC2\x41\x01\x01";
+		let mut decoder = Decoder::new();
+		decoder.set_duplicate_keys(DuplicateKeyPolicy::KeepLast);
+		assert_eq!(
+			decoder.decode_slice(input).unwrap(),
+			Item::Map(vec![(
+				Item::UnrecognizedTag(2, Box::new(Item::ByteString(vec![1]))),
+				Item::Unsigned(1)
+			)])
+		);
+		decoder.set_duplicate_keys(DuplicateKeyPolicy::Reject);
+		let error = decoder.decode_slice(input).unwrap_err();
+		assert!(matches!(error.kind(), DecodeErrorKind::DuplicateKey));
+		assert_eq!(error.offset(), 5);
+	}
+
+	#[test]
+	fn decode_require_definite() {
+		let mut decoder = Decoder::new();
+		assert!(decoder.decode_slice(b"\xC2\x5F\xFF").is_ok());
+		decoder.set_require_definite(true);
+		assert!(matches!(
+			decoder
+				.decode_slice(b"\xC2\x5F\xFF")
+				.map_err(DecodeError::into_kind),
+			Err(DecodeErrorKind::NotDefinite(2))
+		));
+	}
+
+	#[test]
+	fn sequence_round_trip() {
+		let items = [
+			Item::UnrecognizedTag(2, Box::new(Item::ByteString(b"ab".to_vec()))),
+			Item::Unsigned(1),
+			Item::Map(vec![(Item::Unsigned(0), Item::Simple(20))]),
+		];
+		let mut encoder = Encoder::new().encode_sequence(Vec::new());
+		for item in &items {
+			encoder.encode(item).unwrap();
+		}
+		let output = encoder.into_inner();
+		assert_eq!(output, b"\xC2\x42ab\x01\xA1\x00\xF4");
+
+		let decoded: Vec<_> = Decoder::new()
+			.decode_sequence(Cursor::new(&output))
+			.collect::<Result<_, _>>()
+			.unwrap();
+		assert_eq!(decoded, items);
+
+		let mut decoded = Decoder::new().decode_sequence_slice(&output[..5]);
+		assert!(matches!(
+			decoded.next(),
+			Some(Ok(Item::UnrecognizedTag(2, _)))
+		));
+		assert!(matches!(decoded.next(), Some(Ok(Item::Unsigned(1)))));
+		assert!(decoded.next().is_none());
+		let mut decoded = Decoder::new().decode_sequence_slice(&output[..6]);
+		assert_eq!(decoded.nth(2).unwrap().unwrap_err().offset(), 6);
+		assert!(decoded.next().is_none());
+	}
 }
@@ -14,7 +14,38 @@
 //! - [`extended::tree`], a tree-based encoder/decoder pair with special handling of some tags
 //!
 //! For details on the extensions currently implemented, check the documentation for the [`extended`] module.
+//!
+//! If the `async` feature is enabled, both streaming modules also provide an `AsyncDecoder`/`AsyncEncoder` pair,
+//! which work with the `AsyncRead` and `AsyncWrite` traits from the `futures-io` crate.
+//!
+//! # Untrusted input
+//!
+//! Decoding never panics, whatever the input: lengths that don't fit in memory, out-of-range values and the like all produce a [`DecodeError`](errors::DecodeError).
+//! Beyond that, the resources a decoder will spend can be capped with [`DecodeLimits`](basic::streaming::DecodeLimits).
+//! The tree decoders recurse once per level of nesting, so they limit the nesting depth by default;
+//! the streaming decoders don't recurse, so they don't need to.
+
+// This has to come before the modules, so that it's in scope for them.
+macro_rules! config_accessors {
+	($field:ident, $type:ty, $getter:ident, $mut_getter:ident, $setter:ident) => {
+		pub fn $getter(&self) -> &$type {
+			&self.$field
+		}
+
+		pub fn $mut_getter(&mut self) -> &mut $type {
+			&mut self.$field
+		}
+
+		pub fn $setter(&mut self, value: $type) -> &mut Self {
+			self.$field = value;
+			self
+		}
+	};
+}
 
 pub mod basic;
 pub mod errors;
 pub mod extended;
+
+/// The type of half-precision floats, from the [`half`] crate.
+pub use half::f16;